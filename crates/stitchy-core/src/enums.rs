@@ -5,6 +5,8 @@ use clap::ValueEnum;
 #[cfg(feature = "parser")]
 use serde::{Serialize, Deserialize};
 
+use image::{imageops::FilterType, metadata::Orientation};
+
 /// Configure which end of the set of files to take from. The first file used will be the one
 /// at the specified end, and then the next file in from the end, and so on.
 /// The meaning of [TakeFrom::Start] or [TakeFrom::End] depends on the ordering specified with
@@ -34,3 +36,163 @@ pub enum OrderBy {
     Latest,
     Alphabetic
 }
+
+/// Configure the main axis of a grid layout. Images are placed along this axis until a line is
+/// full, and then placement continues on the next line along the other axis.
+///
+/// With [MajorAxis::Horizontal], images fill each row from left to right and rows are stacked
+/// from top to bottom. With [MajorAxis::Vertical], images fill each column from top to bottom and
+/// columns are placed from left to right.
+#[derive(PartialEq, Debug, Copy, Clone)]
+#[cfg_attr(feature = "parser", derive(ValueEnum, Serialize, Deserialize))]
+pub enum MajorAxis {
+    Horizontal,
+    Vertical
+}
+
+/// Configure where images sit within a line of a grid layout that is shorter than the longest
+/// line, such as a partly-filled last line. Lines run along the main axis, so with horizontal
+/// lines [LineAlignment::Start] is the left edge and [LineAlignment::End] is the right edge.
+///
+/// [LineAlignment::Spread] shares the empty space evenly between the images in the line, and
+/// centres a line holding a single image. [LineAlignment::Fill] scales the images in the last
+/// line up until it is as long as the longest line, making that line thicker than the others;
+/// other short lines are left at the start.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "parser", derive(ValueEnum, Serialize, Deserialize))]
+pub enum LineAlignment {
+    #[default]
+    Start,
+    #[cfg_attr(feature = "parser", value(alias = "center"))]
+    Centre,
+    End,
+    Spread,
+    Fill
+}
+
+/// Configure how pixel values are treated while images are resized.
+///
+/// [ResampleMode::Encoded] filters the stored sRGB values directly, which is fastest.
+/// [ResampleMode::Linear] converts to linear light and premultiplies alpha before filtering,
+/// which keeps thin lines and high-contrast edges from darkening and stops the colour of
+/// transparent pixels bleeding into visible ones, but takes longer.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "parser", derive(ValueEnum, Serialize, Deserialize))]
+pub enum ResampleMode {
+    #[default]
+    Encoded,
+    Linear
+}
+
+/// Configure the filter used when resizing images, for choosing a filter by name. Each converts
+/// into the [FilterType] of the same name, which is what the stitch itself uses.
+///
+/// In order from fastest to highest quality, roughly: [ResizeFilter::Nearest] copies the nearest
+/// source pixel without filtering, [ResizeFilter::Triangle] interpolates linearly,
+/// [ResizeFilter::CatmullRom] is a cubic filter, [ResizeFilter::Gaussian] is softer but free of
+/// ringing, and [ResizeFilter::Lanczos3] is the sharpest.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "parser", derive(ValueEnum, Serialize, Deserialize))]
+pub enum ResizeFilter {
+    Nearest,
+    Triangle,
+    CatmullRom,
+    Gaussian,
+    #[default]
+    Lanczos3
+}
+
+impl From<ResizeFilter> for FilterType {
+    fn from(value: ResizeFilter) -> Self {
+        match value {
+            ResizeFilter::Nearest => FilterType::Nearest,
+            ResizeFilter::Triangle => FilterType::Triangle,
+            ResizeFilter::CatmullRom => FilterType::CatmullRom,
+            ResizeFilter::Gaussian => FilterType::Gaussian,
+            ResizeFilter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+/// Configure the pixel type of the output image.
+///
+/// With [PixelFormat::Auto], the output follows the sources: it has 8-bit channels unless any
+/// source has more precision, in which case 16-bit or floating point channels are used as needed
+/// to keep it. Alpha is left out of 16-bit outputs where no source has it and nothing shows
+/// through from the background, and a greyscale output is used if all sources are greyscale.
+/// The other variants set the pixel type explicitly, converting the sources to it.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "parser", derive(ValueEnum, Serialize, Deserialize))]
+pub enum PixelFormat {
+    #[default]
+    Auto,
+    Rgba8,
+    Rgba16,
+    Rgb16,
+    Luma16,
+    #[cfg_attr(feature = "parser", value(name = "rgba32f"))]
+    Rgba32F
+}
+
+/// Configure the colour space that every source image is converted into before stitching, so
+/// that images from different devices match. Sources without an embedded colour profile are
+/// assumed to be sRGB. The output is in this colour space, and its ICC profile is available from
+/// [WorkingSpace::icc_profile] for embedding in the output file.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "parser", derive(ValueEnum, Serialize, Deserialize))]
+pub enum WorkingSpace {
+    #[default]
+    Srgb,
+    DisplayP3,
+    AdobeRgb,
+    #[cfg_attr(feature = "parser", value(name = "bt2020"))]
+    Bt2020,
+    ProPhotoRgb
+}
+
+/// Configure how each image is turned the right way up when loaded from a file.
+///
+/// By default the orientation recorded in the file's EXIF metadata is applied, for any format
+/// that records one. The metadata can instead be ignored, or replaced by a fixed rotation or flip
+/// applied to every image.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "parser", derive(ValueEnum, Serialize, Deserialize))]
+pub enum OrientationMode {
+    #[default]
+    Auto,
+    Ignore,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    FlipHorizontal,
+    FlipVertical
+}
+
+impl OrientationMode {
+    /// Get the orientation to apply, or [None] if it should be read from the file's metadata
+    pub(crate) fn fixed_orientation(&self) -> Option<Orientation> {
+        match self {
+            OrientationMode::Auto => None,
+            OrientationMode::Ignore => Some(Orientation::NoTransforms),
+            OrientationMode::Rotate90 => Some(Orientation::Rotate90),
+            OrientationMode::Rotate180 => Some(Orientation::Rotate180),
+            OrientationMode::Rotate270 => Some(Orientation::Rotate270),
+            OrientationMode::FlipHorizontal => Some(Orientation::FlipHorizontal),
+            OrientationMode::FlipVertical => Some(Orientation::FlipVertical),
+        }
+    }
+}
+
+/// Configure how animated sources that are shorter than the longest one are lined up with it
+/// when stitching an animation. The output runs for as long as the longest source.
+///
+/// With [AnimationSync::Loop], shorter animations start again from their first frame each time
+/// they finish. With [AnimationSync::Hold], they play once and then stay on their last frame.
+/// Still images are shown throughout either way.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+#[cfg_attr(feature = "parser", derive(ValueEnum, Serialize, Deserialize))]
+pub enum AnimationSync {
+    #[default]
+    Loop,
+    Hold
+}
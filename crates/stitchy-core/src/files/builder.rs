@@ -12,6 +12,7 @@ use std::path::PathBuf;
 /// See documentation for [`ImageFiles`] for more information.
 pub struct ImageFilesBuilder<P: FileProperties, L: FileLocation<P>> {
     file_list: Vec<L>,
+    accept_extensionless: bool,
     phantom: PhantomData<P>
 }
 
@@ -19,6 +20,7 @@ impl<P: FileProperties, L: FileLocation<P>> Default for ImageFilesBuilder<P, L>
     fn default() -> Self {
         Self {
             file_list: vec![],
+            accept_extensionless: false,
             phantom: PhantomData
         }
     }
@@ -40,11 +42,21 @@ impl<P, L> ImageFilesBuilder<P, L>
         Ok(ImageFiles::new(image_files))
     }
 
+    /// Accept files that have no extension if their contents are in a known image format. This
+    /// applies to files and directories added after it is set. Off by default.
+    pub fn accept_extensionless(self, accept_extensionless: bool) -> Self {
+        Self { accept_extensionless, ..self }
+    }
+
     /// Add a single file to the working set, given a PathBuf with its absolute path.
     pub fn add_file(mut self, location: L) -> Result<Self, String> {
         let accepted_extensions = ImageFiles::<P>::allowed_extensions();
         let file_extension = location.extension()?;
-        if !Self::extension_in_list(file_extension.as_str(), &accepted_extensions) {
+        let recognised = match file_extension.is_empty() && self.accept_extensionless {
+            true => location.content_format()?.is_some(),
+            false => Self::extension_in_list(file_extension.as_str(), &accepted_extensions)
+        };
+        if !recognised {
             return Err(format!("File not recognised as image file: {:?}", location));
         }
         if !location.is_file()? {
@@ -55,7 +67,7 @@ impl<P, L> ImageFilesBuilder<P, L>
     }
 
     /// Checks if a file has an extension matching any in a given set
-    fn extension_in_list(extension: &str, accepted_extensions: &[&str; 6]) -> bool {
+    fn extension_in_list(extension: &str, accepted_extensions: &[&str]) -> bool {
         accepted_extensions.contains(&extension)
     }
 }
@@ -85,7 +97,9 @@ impl ImageFilesBuilder<FilePathWithMetadata, FilePath> {
     }
 
     /// Add to the working set all files within the given directory that have known image file
-    /// extensions. The supplied PathBuf must be the absolute path to a directory.
+    /// extensions, and files without an extension that contain images if
+    /// [ImageFilesBuilder::accept_extensionless] was set. The supplied PathBuf must be the
+    /// absolute path to a directory.
     pub fn add_directory(mut self, source_path: PathBuf) -> Result<Self, String> {
 
         // Scan directory and add all image files found
@@ -116,19 +130,25 @@ impl ImageFilesBuilder<FilePathWithMetadata, FilePath> {
                     continue;
                 }
 
-                // Check the extension is a known image format
+                // Check the extension is a known image format, or for files without one, that
+                // the contents are if those are accepted
                 let extension = path.extension()
                     .unwrap_or(OsStr::new(""))
                     .to_ascii_lowercase();
                 let lower_str_extension = extension
                     .to_str()
                     .unwrap_or("");
-                if !Self::extension_in_list(lower_str_extension, &accepted_extensions) {
+                let location = FilePath::new(path);
+                let recognised = match lower_str_extension.is_empty() && self.accept_extensionless {
+                    true => matches!(location.content_format(), Ok(Some(_))),
+                    false => Self::extension_in_list(lower_str_extension, &accepted_extensions)
+                };
+                if !recognised {
                     continue;
                 }
 
                 // Add to list of usable paths
-                image_files.push(FilePath::new(path));
+                image_files.push(location);
             }
         } else {
             return Err(
@@ -0,0 +1,203 @@
+use crate::WorkingSpace;
+use image::{ColorType, DynamicImage, ImageBuffer, Pixel, Rgb, Rgba};
+use moxcms::{
+    CmsError, ColorProfile, DataColorSpace, Layout, ToneReprCurve, TransformExecutor,
+    TransformOptions, Xyzd,
+};
+
+impl WorkingSpace {
+    fn profile(&self) -> ColorProfile {
+        match self {
+            WorkingSpace::Srgb => ColorProfile::new_srgb(),
+            WorkingSpace::DisplayP3 => ColorProfile::new_display_p3(),
+            WorkingSpace::AdobeRgb => ColorProfile::new_adobe_rgb(),
+            WorkingSpace::Bt2020 => ColorProfile::new_bt2020(),
+            WorkingSpace::ProPhotoRgb => ColorProfile::new_pro_photo_rgb(),
+        }
+    }
+
+    /// Get the ICC profile describing this colour space, for embedding in output files
+    pub fn icc_profile(&self) -> Result<Vec<u8>, String> {
+        self.profile()
+            .encode()
+            .map_err(|e| format!("Cannot encode colour profile for {:?}: {}", self, e))
+    }
+}
+
+/// Find the colour profile to convert an image from, given the ICC profile embedded in its file,
+/// or [None] if the image is already in the working space. Images without a profile are taken to
+/// be sRGB. A profile that cannot be read or converted from is reported with a warning, and the
+/// image is taken to be sRGB instead.
+pub(crate) fn source_profile(
+    icc_profile: Option<&[u8]>,
+    working_space: WorkingSpace,
+    full_path_label: &str,
+) -> Option<ColorProfile> {
+    let target = working_space.profile();
+    let source = match icc_profile.map(ColorProfile::new_from_slice) {
+        Some(Ok(profile)) => match check_transform(&profile, &target) {
+            Ok(()) => profile,
+            Err(e) => {
+                warn_untagged(&format!("Cannot convert colour profile: {}", e), full_path_label);
+                ColorProfile::new_srgb()
+            }
+        },
+        Some(Err(e)) => {
+            warn_untagged(&format!("Cannot read colour profile: {}", e), full_path_label);
+            ColorProfile::new_srgb()
+        }
+        None => ColorProfile::new_srgb(),
+    };
+    match same_colour_space(&source, &target) {
+        true => None,
+        false => Some(source),
+    }
+}
+
+fn warn_untagged(message: &str, full_path_label: &str) {
+    eprintln!("Warning: {} in {}; treating it as sRGB", message, full_path_label);
+}
+
+/// Check that a transform can be made from a profile into the working space, for the kinds of
+/// profile that [convert_to_working_space] uses
+fn check_transform(source: &ColorProfile, target: &ColorProfile) -> Result<(), CmsError> {
+    let source_layout = match source.color_space {
+        DataColorSpace::Gray => Layout::Gray,
+        DataColorSpace::Rgb => Layout::Rgb,
+        _ => return Ok(()),
+    };
+    source
+        .create_transform_8bit(source_layout, target, Layout::Rgb, TransformOptions::default())
+        .map(|_| ())
+}
+
+/// Check whether two profiles describe the same RGB colour space, in which case converting
+/// between them would only lose precision. Allows for the rounding of values stored in files.
+fn same_colour_space(a: &ColorProfile, b: &ColorProfile) -> bool {
+    const TOLERANCE: f64 = 1e-3;
+    let has_lut = |profile: &ColorProfile| {
+        profile.lut_a_to_b_perceptual.is_some() || profile.lut_a_to_b_colorimetric.is_some()
+    };
+    if !a.is_matrix_shaper() || !b.is_matrix_shaper() || has_lut(a) || has_lut(b) {
+        return false;
+    }
+    let close = |x: Xyzd, y: Xyzd| {
+        (x.x - y.x).abs() < TOLERANCE && (x.y - y.y).abs() < TOLERANCE
+            && (x.z - y.z).abs() < TOLERANCE
+    };
+    let points_match = close(a.red_colorant, b.red_colorant)
+        && close(a.green_colorant, b.green_colorant)
+        && close(a.blue_colorant, b.blue_colorant)
+        && close(a.white_point, b.white_point);
+    let curves_match = |x: &Option<ToneReprCurve>, y: &Option<ToneReprCurve>| {
+        let (Some(x), Some(y)) = (x, y) else {
+            return false;
+        };
+        let (Ok(x), Ok(y)) = (x.make_linear_evaluator(), y.make_linear_evaluator()) else {
+            return false;
+        };
+        (0..=256).map(|step| step as f32 / 256.0).all(|value| {
+            (x.evaluate_value(value) - y.evaluate_value(value)).abs() < TOLERANCE as f32
+        })
+    };
+    points_match
+        && curves_match(&a.red_trc, &b.red_trc)
+        && curves_match(&a.green_trc, &b.green_trc)
+        && curves_match(&a.blue_trc, &b.blue_trc)
+}
+
+/// Convert an image from the colour space described by a profile, as found by [source_profile],
+/// into the working space. Profiles for colour spaces other than RGB or greyscale are ignored,
+/// as the decoder will have already converted such images to RGB.
+pub(crate) fn convert_to_working_space(
+    image: DynamicImage,
+    source: Option<&ColorProfile>,
+    working_space: WorkingSpace,
+) -> Result<DynamicImage, String> {
+    let Some(source) = source else {
+        return Ok(image);
+    };
+
+    // A greyscale profile only describes greyscale pixels; an RGB profile needs RGB pixels
+    let grey = match (source.color_space, image.color().has_color()) {
+        (DataColorSpace::Gray, false) => true,
+        (DataColorSpace::Rgb, _) => false,
+        _ => return Ok(image),
+    };
+    let target = working_space.profile();
+    let alpha = image.color().has_alpha();
+    let (source_layout, target_layout) = match (grey, alpha) {
+        (true, true) => (Layout::GrayAlpha, Layout::Rgba),
+        (true, false) => (Layout::Gray, Layout::Rgb),
+        (false, true) => (Layout::Rgba, Layout::Rgba),
+        (false, false) => (Layout::Rgb, Layout::Rgb),
+    };
+    let options = TransformOptions::default();
+    let error = |e: CmsError| format!("Cannot convert colour profile: {}", e);
+
+    let wide = image.color().bytes_per_pixel() > image.color().channel_count();
+    let converted = match image.color() {
+        ColorType::Rgb32F | ColorType::Rgba32F => {
+            let transform = source
+                .create_transform_f32(source_layout, &target, target_layout, options)
+                .map_err(error)?;
+            match alpha {
+                true => DynamicImage::ImageRgba32F(transform_buffer::<Rgba<f32>, _>(
+                    &image.into_rgba32f(), transform.as_ref())?),
+                false => DynamicImage::ImageRgb32F(transform_buffer::<Rgb<f32>, _>(
+                    &image.into_rgb32f(), transform.as_ref())?),
+            }
+        }
+        _ if wide => {
+            let transform = source
+                .create_transform_16bit(source_layout, &target, target_layout, options)
+                .map_err(error)?;
+            let transform = transform.as_ref();
+            match (grey, alpha) {
+                (true, true) => DynamicImage::ImageRgba16(
+                    transform_buffer::<Rgba<u16>, _>(&image.into_luma_alpha16(), transform)?),
+                (true, false) => DynamicImage::ImageRgb16(
+                    transform_buffer::<Rgb<u16>, _>(&image.into_luma16(), transform)?),
+                (false, true) => DynamicImage::ImageRgba16(
+                    transform_buffer::<Rgba<u16>, _>(&image.into_rgba16(), transform)?),
+                (false, false) => DynamicImage::ImageRgb16(
+                    transform_buffer::<Rgb<u16>, _>(&image.into_rgb16(), transform)?),
+            }
+        }
+        _ => {
+            let transform = source
+                .create_transform_8bit(source_layout, &target, target_layout, options)
+                .map_err(error)?;
+            let transform = transform.as_ref();
+            match (grey, alpha) {
+                (true, true) => DynamicImage::ImageRgba8(
+                    transform_buffer::<Rgba<u8>, _>(&image.into_luma_alpha8(), transform)?),
+                (true, false) => DynamicImage::ImageRgb8(
+                    transform_buffer::<Rgb<u8>, _>(&image.into_luma8(), transform)?),
+                (false, true) => DynamicImage::ImageRgba8(
+                    transform_buffer::<Rgba<u8>, _>(&image.into_rgba8(), transform)?),
+                (false, false) => DynamicImage::ImageRgb8(
+                    transform_buffer::<Rgb<u8>, _>(&image.into_rgb8(), transform)?),
+            }
+        }
+    };
+    Ok(converted)
+}
+
+/// Run a colour transform over every pixel of an image, producing an image of another pixel type
+fn transform_buffer<Q, P>(
+    source: &ImageBuffer<P, Vec<P::Subpixel>>,
+    transform: &(dyn TransformExecutor<P::Subpixel> + Send + Sync),
+) -> Result<ImageBuffer<Q, Vec<Q::Subpixel>>, String>
+where
+    P: Pixel,
+    Q: Pixel<Subpixel = P::Subpixel>,
+    P::Subpixel: Default,
+{
+    let (width, height) = source.dimensions();
+    let mut target = ImageBuffer::<Q, Vec<Q::Subpixel>>::new(width, height);
+    transform
+        .transform(source.as_raw(), &mut target)
+        .map_err(|e| format!("Cannot convert colour profile: {}", e))?;
+    Ok(target)
+}
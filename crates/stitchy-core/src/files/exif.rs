@@ -0,0 +1,76 @@
+use image::metadata::Orientation;
+use std::io::{Read, Seek, SeekFrom};
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+const ORIENTATION_TAG: u16 = 0x0112;
+const SHORT_TYPE: u16 = 3;
+
+/// Find the orientation in the eXIf chunk of a PNG file, which the PNG decoder does not read.
+/// Only the chunk headers are read until the eXIf chunk is found, skipping over image data.
+pub(crate) fn png_orientation<R: Read + Seek>(mut source: R) -> Result<Orientation, String> {
+    let mut signature = [0u8; 8];
+    source
+        .read_exact(&mut signature)
+        .map_err(|e| format!("Cannot read PNG signature: {}", e))?;
+    if signature != PNG_SIGNATURE {
+        return Err("Not a PNG file".to_owned());
+    }
+
+    let mut header = [0u8; 8];
+    while source.read_exact(&mut header).is_ok() {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        match &header[4..8] {
+            b"eXIf" => {
+                // The length comes from the file, so read no more than is there
+                let mut chunk = Vec::new();
+                let read = source
+                    .by_ref()
+                    .take(length as u64)
+                    .read_to_end(&mut chunk)
+                    .map_err(|e| format!("Cannot read PNG eXIf chunk: {}", e))?;
+                if read != length as usize {
+                    return Err("PNG eXIf chunk is truncated".to_owned());
+                }
+                return Ok(exif_orientation(&chunk).unwrap_or(Orientation::NoTransforms));
+            }
+            b"IEND" => break,
+            _ => {
+                // Skip the chunk data and its CRC
+                source
+                    .seek(SeekFrom::Current(length as i64 + 4))
+                    .map_err(|e| format!("Cannot read PNG chunks: {}", e))?;
+            }
+        }
+    }
+    Ok(Orientation::NoTransforms)
+}
+
+/// Read the orientation tag from the first image directory of EXIF data, which has the same
+/// structure as a TIFF file. Some writers include the "Exif" prefix used in JPEG files, so that
+/// is skipped if present.
+pub(crate) fn exif_orientation(data: &[u8]) -> Option<Orientation> {
+    let data = data.strip_prefix(b"Exif\0\0").unwrap_or(data);
+    let big_endian = match data.get(0..4)? {
+        [b'I', b'I', 42, 0] => false,
+        [b'M', b'M', 0, 42] => true,
+        _ => return None,
+    };
+    let read_u16 = |offset: usize| -> Option<u16> {
+        let bytes = [*data.get(offset)?, *data.get(offset + 1)?];
+        Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    };
+    let read_u32 = |offset: usize| -> Option<u32> {
+        let bytes = data.get(offset..offset + 4)?;
+        let bytes = [bytes[0], bytes[1], bytes[2], bytes[3]];
+        Some(if big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    };
+
+    let directory = read_u32(4)? as usize;
+    let entry_count = read_u16(directory)? as usize;
+    (0..entry_count)
+        .map(|index| directory + 2 + index * 12)
+        .find(|&entry| read_u16(entry) == Some(ORIENTATION_TAG))
+        .filter(|&entry| read_u16(entry + 2) == Some(SHORT_TYPE))
+        .and_then(|entry| read_u16(entry + 8))
+        .and_then(|value| Orientation::from_exif(value.min(255) as u8))
+}
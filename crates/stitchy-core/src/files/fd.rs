@@ -1,13 +1,17 @@
+use crate::files::util::{content_format, mime_type_format, SNIFF_LENGTH};
 use crate::{FileLocation, FileProperties};
-use image::{metadata::Orientation, DynamicImage, ImageFormat};
+use image::{metadata::Orientation, ColorType, DynamicImage, Frame, ImageFormat};
 use std::fs::File;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Seek};
 use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::fs::FileExt;
+use std::sync::OnceLock;
 use std::time::SystemTime;
 
 /// Wrapper for file metadata.
 /// Loading these properties for a file does not require actually reading, let alone parsing, the
-/// file's contents.
+/// file's contents. The first few bytes are read when the format is first needed, so that it is
+/// detected from the contents rather than trusting the MIME type.
 /// Like the [OwnedRawFdLocation], this owns the file descriptor now, and the file will be closed when
 /// this instance is dropped.
 #[derive(Debug)]
@@ -17,27 +21,42 @@ pub struct OwnedRawFdProperties {
     mime_type: String,
     modify_time: SystemTime,
     size_bytes: u64,
+    content_format: OnceLock<Option<ImageFormat>>,
 }
 
 impl OwnedRawFdProperties {
     pub fn borrow_file_mut(&mut self) -> &File {
         &mut self.file
     }
+
+    /// Get the format detected from the start of the file's contents, reading them the first
+    /// time this is needed
+    pub fn content_format(&self) -> Option<ImageFormat> {
+        *self.content_format.get_or_init(|| sniff_format(&self.file).unwrap_or(None))
+    }
+
+    /// Move back to the start of the file, since reading metadata moves the file position
+    fn rewind(&self) -> Result<(), String> {
+        (&self.file)
+            .rewind()
+            .map_err(|e| format!("Failed to seek file descriptor {}: {:?}", self.fd, e))
+    }
 }
 
 impl FileProperties for OwnedRawFdProperties {
     fn infer_format(&self) -> Option<ImageFormat> {
-        match self.mime_type.as_str() {
-            "image/jpeg" => Some(ImageFormat::Jpeg),
-            "image/png" => Some(ImageFormat::Png),
-            "image/gif" => Some(ImageFormat::Gif),
-            "image/bmp" => Some(ImageFormat::Bmp),
-            "image/webp" => Some(ImageFormat::WebP),
+        self.content_format().or_else(|| mime_type_format(self.mime_type.as_str()))
+    }
+
+    fn format_mismatch(&self) -> Option<(ImageFormat, ImageFormat)> {
+        match (mime_type_format(self.mime_type.as_str()), self.content_format()) {
+            (Some(named), Some(contents)) if named != contents => Some((named, contents)),
             _ => None,
         }
     }
 
     fn into_image_contents(mut self, print_info: bool) -> Result<DynamicImage, String> {
+        self.rewind()?;
         let mut image_buffer = Vec::new();
         self.file
             .read_to_end(&mut image_buffer)
@@ -74,11 +93,35 @@ impl FileProperties for OwnedRawFdProperties {
         None
     }
 
-    #[inline]
     fn orientation(&self) -> Result<Orientation, String> {
+        self.rewind()?;
         let reader = BufReader::new(&self.file);
         self.decode_orientation(reader)
     }
+
+    fn dimensions(&self) -> Result<(u32, u32), String> {
+        self.rewind()?;
+        let reader = BufReader::new(&self.file);
+        self.decode_dimensions(reader)
+    }
+
+    fn color_type(&self) -> Result<ColorType, String> {
+        self.rewind()?;
+        let reader = BufReader::new(&self.file);
+        self.decode_color_type(reader)
+    }
+
+    fn icc_profile(&self) -> Result<Option<Vec<u8>>, String> {
+        self.rewind()?;
+        let reader = BufReader::new(&self.file);
+        self.decode_icc_profile(reader)
+    }
+
+    fn animation_frames(&self) -> Result<Option<Vec<Frame>>, String> {
+        self.rewind()?;
+        let reader = BufReader::new(&self.file);
+        self.decode_animation_frames(reader)
+    }
 }
 
 /// Wrapper for a file's location by a raw file descriptor. This owns the file descriptor now, and the file will be
@@ -110,15 +153,13 @@ impl FileLocation<OwnedRawFdProperties> for OwnedRawFdLocation {
         Ok(metadata.is_file())
     }
 
+    fn content_format(&self) -> Result<Option<ImageFormat>, String> {
+        sniff_format(&self.file)
+            .map_err(|e| format!("Failed to read file descriptor {}: {:?}", self.fd, e))
+    }
+
     fn extension(&self) -> Result<String, String> {
-        let extension_index = match self.mime_type.find("/") {
-            Some(i) => i + 1,
-            None => return Err(format!("Could not parse MIME type: {}", self.mime_type)),
-        };
-        if extension_index >= self.mime_type.len() - 1 {
-            return Err(format!("Invalid MIME type: {}", self.mime_type));
-        }
-        Ok(self.mime_type[extension_index..].to_owned())
+        crate::files::util::mime_type_extension(&self.mime_type)
     }
 
     fn into_properties(self) -> Result<OwnedRawFdProperties, String> {
@@ -139,7 +180,16 @@ impl FileLocation<OwnedRawFdProperties> for OwnedRawFdLocation {
             mime_type: self.mime_type,
             modify_time,
             size_bytes,
+            content_format: OnceLock::new(),
         };
         Ok(properties)
     }
 }
+
+/// Read the first bytes of a file, and detect its image format from them. The file position is
+/// left where it was, so that this can happen while the file is being decoded.
+fn sniff_format(file: &File) -> Result<Option<ImageFormat>, std::io::Error> {
+    let mut header = vec![0; SNIFF_LENGTH as usize];
+    let length = file.read_at(&mut header, 0)?;
+    Ok(content_format(&header[..length]))
+}
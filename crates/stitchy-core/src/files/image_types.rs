@@ -1,6 +1,11 @@
 use std::cmp::Ordering;
 use crate::{OrderBy, TakeFrom, ImageFilesBuilder, FileProperties, image::DynamicImage, FileLocation};
-use image::ImageFormat;
+use crate::files::{
+    colour::{convert_to_working_space, source_profile},
+    oriented_dimensions, path_label,
+};
+use crate::{OrientationMode, WorkingSpace};
+use image::{ColorType, ImageFormat};
 
 /// A set of image files, storing some file properties internally.
 ///
@@ -12,7 +17,8 @@ use image::ImageFormat;
 ///
 /// See crate-level documentation for examples.
 pub struct ImageFiles<P: FileProperties> {
-    file_list: Vec<P>
+    file_list: Vec<P>,
+    orientation_mode: OrientationMode
 }
 
 impl<P: FileProperties> ImageFiles<P> {
@@ -23,16 +29,26 @@ impl<P: FileProperties> ImageFiles<P> {
     }
 
     pub(crate) fn new(file_list: Vec<P>) -> Self {
-        Self { file_list }
+        Self { file_list, orientation_mode: OrientationMode::default() }
     }
 
-    /// Return an array of the accepted file extensions.
+    /// Set how images are turned the right way up when loaded, which by default follows the
+    /// orientation in each file's metadata. This applies to the dimensions read from the files
+    /// as well as the image data.
+    pub fn with_orientation(self, orientation_mode: OrientationMode) -> Self {
+        Self { orientation_mode, ..self }
+    }
+
+    /// Return a list of the accepted file extensions.
     ///
-    /// These match image formats that can be processed by this crate. Adding individual files
-    /// with other extensions will fail, and files with other extensions will be ignored if adding
-    /// whole directories.
-    pub fn allowed_extensions() -> [&'static str; 6] {
-        ["jpg", "jpeg", "png", "gif", "bmp", "webp"]
+    /// These match image formats that can be processed by this crate, which depends on the
+    /// format features enabled. Adding individual files with other extensions will fail, and
+    /// files with other extensions will be ignored if adding whole directories.
+    pub fn allowed_extensions() -> Vec<&'static str> {
+        crate::files::util::extension_formats()
+            .into_iter()
+            .map(|(extension, _)| extension.trim_start_matches('.'))
+            .collect()
     }
 
     /// Get the "main" extension used by a format.
@@ -46,6 +62,20 @@ impl<P: FileProperties> ImageFiles<P> {
             ImageFormat::Gif => Some("gif"),
             ImageFormat::Bmp => Some("bmp"),
             ImageFormat::WebP => Some("webp"),
+            #[cfg(feature = "tiff")]
+            ImageFormat::Tiff => Some("tiff"),
+            #[cfg(feature = "qoi")]
+            ImageFormat::Qoi => Some("qoi"),
+            #[cfg(feature = "tga")]
+            ImageFormat::Tga => Some("tga"),
+            #[cfg(feature = "ico")]
+            ImageFormat::Ico => Some("ico"),
+            #[cfg(feature = "pnm")]
+            ImageFormat::Pnm => Some("pnm"),
+            #[cfg(feature = "hdr")]
+            ImageFormat::Hdr => Some("hdr"),
+            #[cfg(feature = "exr")]
+            ImageFormat::OpenExr => Some("exr"),
             _ => None
         }
     }
@@ -122,20 +152,147 @@ impl<P: FileProperties> ImageFiles<P> {
         Ok(self)
     }
 
+    /// Read the width and height of every file in the set, with orientation metadata applied.
+    /// Only file headers are read, so this is much faster than loading the image data.
+    pub fn dimensions(&self) -> Result<Vec<(u32, u32)>, String> {
+        self.file_list
+            .iter()
+            .map(|file| {
+                let dimensions = file.dimensions()?;
+                let Some(orientation) = self.orientation_mode.fixed_orientation() else {
+                    return Ok(dimensions);
+                };
+
+                // Undo the orientation from the metadata, then apply the fixed one instead
+                let (width, height) = oriented_dimensions(
+                    dimensions.0, dimensions.1, file.orientation()?);
+                Ok(oriented_dimensions(width, height, orientation))
+            })
+            .collect()
+    }
+
+    /// Get the colour type of each file in the set once decoded, reading only the file headers
+    pub fn color_types(&self) -> Result<Vec<ColorType>, String> {
+        self.file_list.iter().map(|file| file.color_type()).collect()
+    }
+
     /// Load the image data from the files in the set, and return a vector of [`DynamicImage`].
-    /// The result can then be stitched together.
+    /// The result can then be stitched together. Images are oriented as set with
+    /// [ImageFiles::with_orientation], and images with an embedded colour profile are converted
+    /// to sRGB.
     pub fn into_image_contents(self, print_info: bool) -> Result<Vec<DynamicImage>, String> {
         let mut images = Vec::with_capacity(self.file_list.len());
         for file in self.file_list {
-            let orientation = file.orientation()?;
-            let mut image = file.into_image_contents(print_info)?;
-            image.apply_orientation(orientation);
+            let image = Self::load(
+                file, print_info, self.orientation_mode, WorkingSpace::default())?;
             images.push(image);
         }
 
         Ok(images)
     }
 
+    /// Load the image data from the files in the set using a number of threads, where zero uses
+    /// one thread per CPU core. The images are returned in the same order as
+    /// [ImageFiles::into_image_contents] would return them, though any information printed may
+    /// be out of order.
+    pub fn into_image_contents_with_threads(
+        self,
+        print_info: bool,
+        threads: usize,
+    ) -> Result<Vec<DynamicImage>, String>
+    where
+        P: Send,
+    {
+        self.load_all(print_info, threads, WorkingSpace::default())
+    }
+
+    /// Load the image data from the files in the set using a number of threads, converting each
+    /// into a working colour space
+    pub(crate) fn load_all(
+        self,
+        print_info: bool,
+        threads: usize,
+        working_space: WorkingSpace,
+    ) -> Result<Vec<DynamicImage>, String>
+    where
+        P: Send,
+    {
+        let mut images = Vec::with_capacity(self.file_list.len());
+        crate::parallel::map_in_order(
+            self.file_list,
+            threads,
+            |file| Self::load(file, print_info, self.orientation_mode, working_space),
+            |_, image| {
+                images.push(image);
+                Ok(())
+            },
+        )?;
+
+        Ok(images)
+    }
+
+    /// Take the files out of the set, in their current order, along with how to orient them
+    pub(crate) fn into_files(self) -> (Vec<P>, OrientationMode) {
+        (self.file_list, self.orientation_mode)
+    }
+
+    /// Load the image data from a single file, turned the right way up and with its colours
+    /// converted from its embedded colour profile, if any, into the working space
+    pub(crate) fn load(
+        file: P,
+        print_info: bool,
+        orientation_mode: OrientationMode,
+        working_space: WorkingSpace,
+    ) -> Result<DynamicImage, String> {
+        let orientation = match orientation_mode.fixed_orientation() {
+            Some(orientation) => orientation,
+            None => file.orientation()?,
+        };
+        let full_path_label = path_label(&file).to_owned();
+        let icc_profile = file.icc_profile()?;
+        let source = source_profile(icc_profile.as_deref(), working_space, &full_path_label);
+        let mut image = file.into_image_contents(print_info)?;
+        image.apply_orientation(orientation);
+        convert_to_working_space(image, source.as_ref(), working_space)
+            .map_err(|e| format!("{} in {}", e, full_path_label))
+    }
+
+    /// Load every frame of an animated file, each prepared in the same way as [ImageFiles::load],
+    /// along with how long it is shown for in milliseconds. A still image gives a single frame
+    /// that is shown for no time at all.
+    pub(crate) fn load_frames(
+        file: P,
+        print_info: bool,
+        orientation_mode: OrientationMode,
+        working_space: WorkingSpace,
+    ) -> Result<Vec<(DynamicImage, u32)>, String> {
+        let Some(frames) = file.animation_frames()? else {
+            let image = Self::load(file, print_info, orientation_mode, working_space)?;
+            return Ok(vec![(image, 0)]);
+        };
+        let orientation = match orientation_mode.fixed_orientation() {
+            Some(orientation) => orientation,
+            None => file.orientation()?,
+        };
+        let full_path_label = path_label(&file);
+        let icc_profile = file.icc_profile()?;
+        let source = source_profile(icc_profile.as_deref(), working_space, full_path_label);
+        if print_info {
+            println!("Animation: {}, {} frames", full_path_label, frames.len());
+        }
+        frames
+            .into_iter()
+            .map(|frame| {
+                let (numerator, denominator) = frame.delay().numer_denom_ms();
+                let mut image = DynamicImage::ImageRgba8(frame.into_buffer());
+                image.apply_orientation(orientation);
+                let image = convert_to_working_space(image, source.as_ref(), working_space)
+                    .map_err(|e| format!("{} in {}", e, full_path_label))?;
+                Ok((image, numerator / denominator.max(1)))
+            })
+            .collect()
+    }
+
     /// Suggest an output format to use for saving the stitch result after loading and stitching
     /// the image files in this set.
     ///
@@ -154,4 +311,37 @@ impl<P: FileProperties> ImageFiles<P> {
             false => None
         }
     }
+
+    /// Check whether any file in the set is in a format that can hold an animation, being GIF or
+    /// WebP. Only the format is checked, so such files may still turn out to be still images.
+    pub fn may_be_animated(&self) -> bool {
+        self.file_list
+            .iter()
+            .any(|file| matches!(file.infer_format(), Some(ImageFormat::Gif | ImageFormat::WebP)))
+    }
+
+    /// Describe each file in the set whose contents are in a different format from the one its
+    /// extension or MIME type names. Such files are still loaded according to their contents.
+    pub fn format_warnings(&self) -> Vec<String> {
+        self.file_list
+            .iter()
+            .filter_map(|file| {
+                let (named, contents) = file.format_mismatch()?;
+                Some(format!(
+                    "{} is named as {} but contains {} data",
+                    path_label(file),
+                    Self::format_label(named),
+                    Self::format_label(contents)
+                ))
+            })
+            .collect()
+    }
+
+    /// Short name of a format for messages
+    fn format_label(format: ImageFormat) -> String {
+        match Self::get_main_extension(format) {
+            Some(extension) => extension.to_uppercase(),
+            None => format!("{:?}", format)
+        }
+    }
 }
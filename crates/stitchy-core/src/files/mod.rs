@@ -1,4 +1,6 @@
 pub mod builder;
+pub(crate) mod colour;
+pub(crate) mod exif;
 
 #[cfg(unix)]
 pub mod fd;
@@ -9,9 +11,10 @@ pub mod raw;
 pub mod util;
 
 use image::{
-    codecs::{jpeg::JpegDecoder, webp::WebPDecoder},
+    codecs::{gif::GifDecoder, webp::WebPDecoder},
     metadata::Orientation,
-    DynamicImage, ImageDecoder, ImageError, ImageFormat,
+    AnimationDecoder, ColorType, DynamicImage, Frame, ImageDecoder, ImageError, ImageFormat,
+    ImageReader,
 };
 use std::io::{BufRead, Seek};
 use std::time::SystemTime;
@@ -21,11 +24,22 @@ pub trait FileLocation<P: FileProperties> {
     fn is_file(&self) -> Result<bool, String>;
     fn extension(&self) -> Result<String, String>;
     fn into_properties(self) -> Result<P, String>;
+
+    /// Detect the image format from the start of the file's contents, for locations that can be
+    /// read ahead of loading. Used to accept files without an extension.
+    fn content_format(&self) -> Result<Option<ImageFormat>, String> {
+        Ok(None)
+    }
 }
 
 /// Functions to get useful properties of files.
 /// Implementers of this trait are representations of files. The files are not necessarily required to be open, or to
 /// have been opened or read.
+///
+/// Implementers should also provide [FileProperties::dimensions], as its default returns an error.
+/// It is needed by [crate::StitchBuilder::plan_files], [crate::StitchBuilder::stitch_files],
+/// [crate::StitchBuilder::stitch_animation] and [crate::StitchBuilder::stitch_slideshow], which
+/// plan the layout from file headers before loading any image data.
 pub trait FileProperties {
     fn infer_format(&self) -> Option<ImageFormat>;
     fn into_image_contents(self, print_info: bool) -> Result<DynamicImage, String>;
@@ -34,30 +48,109 @@ pub trait FileProperties {
     fn full_path(&self) -> Option<&String>;
     fn orientation(&self) -> Result<Orientation, String>;
 
-    fn decode_orientation<R: BufRead + Seek>(&self, source: R) -> Result<Orientation, String> {
-        let format = match self.infer_format() {
-            Some(format) => format,
-            None => {
-                return Ok(Orientation::NoTransforms);
-            }
-        };
+    /// If the file's contents are in a different format from the one its name or MIME type
+    /// suggests, get the suggested format and the format of the contents, in that order
+    fn format_mismatch(&self) -> Option<(ImageFormat, ImageFormat)> {
+        None
+    }
 
-        let full_path_label = match self.full_path() {
-            Some(string) => string.as_str(),
-            None => "(path unknown)",
-        };
+    /// Get the width and height of the image once its orientation is applied, reading only as
+    /// much of the file as is needed to find them. The default returns an error, so types that
+    /// do not implement this cannot be used to plan a layout from file headers, nor to stitch
+    /// files one at a time.
+    fn dimensions(&self) -> Result<(u32, u32), String> {
+        Err(format!("Reading dimensions is unsupported for {}", path_label(self)))
+    }
 
-        match format {
-            ImageFormat::Jpeg => {
-                Self::decode_orientation_from_codec(full_path_label, JpegDecoder::new(source))
-            }
-            ImageFormat::WebP => {
-                Self::decode_orientation_from_codec(full_path_label, WebPDecoder::new(source))
+    /// Get the colour type the image has once decoded, reading only the header of the file.
+    /// Types that do not implement this are treated as 8-bit RGBA.
+    fn color_type(&self) -> Result<ColorType, String> {
+        Ok(ColorType::Rgba8)
+    }
+
+    /// Get the ICC colour profile embedded in the file, if it has one. Types that do not
+    /// implement this are treated as having none.
+    fn icc_profile(&self) -> Result<Option<Vec<u8>>, String> {
+        Ok(None)
+    }
+
+    /// Decode every frame of an animated GIF or WebP file, or get [None] for a still image. Each
+    /// frame covers the whole image, with the frames before it already drawn underneath. Types
+    /// that do not implement this are treated as still images.
+    fn animation_frames(&self) -> Result<Option<Vec<Frame>>, String> {
+        Ok(None)
+    }
+
+    fn decode_dimensions<R: BufRead + Seek>(&self, mut source: R) -> Result<(u32, u32), String> {
+        let full_path_label = path_label(self);
+        if source_format(self, &mut source)? == Some(ImageFormat::Png) {
+            let (width, height) = header_decoder(self, &mut source)?.dimensions();
+            source
+                .rewind()
+                .map_err(|e| format!("Error reading {}: {:?}", full_path_label, e))?;
+            let orientation = self.decode_orientation(source)?;
+            return Ok(oriented_dimensions(width, height, orientation));
+        }
+
+        let mut decoder = header_decoder(self, source)?;
+        let (width, height) = decoder.dimensions();
+        let orientation = decoder
+            .orientation()
+            .map_err(|e| format!("Cannot decode metadata in {}: {:?}", full_path_label, e))?;
+        Ok(oriented_dimensions(width, height, orientation))
+    }
+
+    fn decode_color_type<R: BufRead + Seek>(&self, source: R) -> Result<ColorType, String> {
+        Ok(header_decoder(self, source)?.color_type())
+    }
+
+    fn decode_icc_profile<R: BufRead + Seek>(&self, source: R) -> Result<Option<Vec<u8>>, String> {
+        let full_path_label = path_label(self);
+        header_decoder(self, source)?
+            .icc_profile()
+            .map_err(|e| format!("Cannot read colour profile in {}: {:?}", full_path_label, e))
+    }
+
+    fn decode_animation_frames<R: BufRead + Seek>(
+        &self,
+        mut source: R,
+    ) -> Result<Option<Vec<Frame>>, String> {
+        let full_path_label = path_label(self);
+        let decode_error = |e: ImageError| format!("Error decoding {}: {:?}", full_path_label, e);
+        let frames = match source_format(self, &mut source)? {
+            Some(ImageFormat::Gif) => GifDecoder::new(source).map_err(decode_error)?.into_frames(),
+            Some(ImageFormat::WebP) => {
+                let decoder = WebPDecoder::new(source).map_err(decode_error)?;
+                if !decoder.has_animation() {
+                    return Ok(None);
+                }
+                decoder.into_frames()
             }
-            _ => Ok(Orientation::NoTransforms),
+            _ => return Ok(None),
+        };
+        let frames = frames.collect_frames().map_err(decode_error)?;
+        match frames.len() {
+            0 | 1 => Ok(None),
+            _ => Ok(Some(frames)),
         }
     }
 
+    /// Read the orientation from the metadata of any format that has it. The PNG decoder does
+    /// not read the eXIf chunk, so that is found separately.
+    fn decode_orientation<R: BufRead + Seek>(&self, mut source: R) -> Result<Orientation, String> {
+        let full_path_label = path_label(self);
+        if source_format(self, &mut source)? == Some(ImageFormat::Png) {
+            return exif::png_orientation(source)
+                .map_err(|e| format!("Cannot decode metadata in {}: {}", full_path_label, e));
+        }
+
+        let mut decoder = header_decoder(self, source)?;
+        decoder
+            .orientation()
+            .map_err(|e| format!("Cannot decode metadata in {}: {:?}", full_path_label, e))
+    }
+
+    #[deprecated(note = "use FileProperties::decode_orientation, which also reads PNG eXIf chunks")]
     fn decode_orientation_from_codec<T: ImageDecoder>(
         full_path_label: &str,
         decoder_result: Result<T, ImageError>,
@@ -70,3 +163,55 @@ pub trait FileProperties {
         Ok(orientation)
     }
 }
+
+/// Get a label for a file in error messages, being its path if known
+pub(crate) fn path_label<P: FileProperties + ?Sized>(file: &P) -> &str {
+    match file.full_path() {
+        Some(string) => string.as_str(),
+        None => "(path unknown)",
+    }
+}
+
+/// Get the format of a file, guessing from its contents if it cannot be inferred otherwise. The
+/// position of the source is left unchanged.
+fn source_format<P: FileProperties + ?Sized, R: BufRead + Seek>(
+    file: &P,
+    source: &mut R,
+) -> Result<Option<ImageFormat>, String> {
+    if let Some(format) = file.infer_format() {
+        return Ok(Some(format));
+    }
+    ImageReader::new(source)
+        .with_guessed_format()
+        .map(|reader| reader.format())
+        .map_err(|e| format!("Error reading {}: {:?}", path_label(file), e))
+}
+
+/// Create a decoder for a file, which reads the header but none of the image data yet
+fn header_decoder<'a, P: FileProperties + ?Sized, R: BufRead + Seek + 'a>(
+    file: &P,
+    source: R,
+) -> Result<impl ImageDecoder + 'a, String> {
+    let full_path_label = path_label(file);
+    let reader = match file.infer_format() {
+        Some(format) => ImageReader::with_format(source, format),
+        None => ImageReader::new(source)
+            .with_guessed_format()
+            .map_err(|e| format!("Error reading {}: {:?}", full_path_label, e))?,
+    };
+    reader
+        .into_decoder()
+        .map_err(|e| format!("Error decoding {}: {:?}", full_path_label, e))
+}
+
+/// Get the dimensions of an image after an orientation is applied, which swaps the width and
+/// height for rotations of a quarter turn
+pub(crate) fn oriented_dimensions(width: u32, height: u32, orientation: Orientation) -> (u32, u32) {
+    match orientation {
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH => (height, width),
+        _ => (width, height),
+    }
+}
@@ -1,20 +1,40 @@
+use crate::files::util::{extension_format, sniff_file_format};
 use crate::{FileLocation, FileProperties};
-use image::{metadata::Orientation, DynamicImage, ImageFormat};
+use image::{metadata::Orientation, ColorType, DynamicImage, Frame, ImageFormat, ImageReader};
 
 use std::ffi::OsStr;
 use std::fs::File;
 use std::io::BufReader;
 use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 use std::time::SystemTime;
 
 /// Wrapper for file metadata.
 /// Loading these properties for a file does not require actually reading, let alone parsing, the
-/// file's contents.
+/// file's contents. The first few bytes are read when the format is first needed, so that it is
+/// detected from the contents rather than trusting the file extension.
 #[derive(Debug)]
 pub struct FilePathWithMetadata {
     full_path: String,
     modify_time: SystemTime,
     size_bytes: u64,
+    content_format: OnceLock<Option<ImageFormat>>,
+}
+
+impl FilePathWithMetadata {
+    /// Get the format suggested by the file extension, ignoring case
+    pub fn extension_format(&self) -> Option<ImageFormat> {
+        let extension = Path::new(&self.full_path).extension()?.to_str()?;
+        extension_format(extension)
+    }
+
+    /// Get the format detected from the first bytes of the file, if it is recognised. The file is
+    /// read only the first time this is needed.
+    pub fn content_format(&self) -> Option<ImageFormat> {
+        *self.content_format.get_or_init(|| {
+            sniff_file_format(Path::new(&self.full_path)).unwrap_or(None)
+        })
+    }
 }
 
 impl Default for FilePathWithMetadata {
@@ -23,29 +43,32 @@ impl Default for FilePathWithMetadata {
             full_path: "".to_owned(),
             modify_time: SystemTime::now(),
             size_bytes: 0,
+            content_format: OnceLock::new(),
         }
     }
 }
 
 impl FileProperties for FilePathWithMetadata {
     fn infer_format(&self) -> Option<ImageFormat> {
-        match self.full_path.rfind('.') {
-            Some(pos) => {
-                let extension = &self.full_path[pos..self.full_path.len()];
-                for &(ext, fmt) in crate::files::util::extension_formats().iter() {
-                    if ext == extension {
-                        return Some(fmt);
-                    }
-                }
-                None
-            }
-            None => None,
+        self.content_format().or_else(|| self.extension_format())
+    }
+
+    fn format_mismatch(&self) -> Option<(ImageFormat, ImageFormat)> {
+        match (self.extension_format(), self.content_format()) {
+            (Some(named), Some(contents)) if named != contents => Some((named, contents)),
+            _ => None,
         }
     }
 
     fn into_image_contents(self, print_info: bool) -> Result<DynamicImage, String> {
         let path = Path::new(&self.full_path);
-        let image = image::open(path).map_err(|e| format!("Failed to open {:?}: {:?}", path, e))?;
+        let mut reader = ImageReader::open(path)
+            .map_err(|e| format!("Failed to open {:?}: {:?}", path, e))?;
+        if let Some(format) = self.infer_format() {
+            reader.set_format(format);
+        }
+        let image = reader.decode()
+            .map_err(|e| format!("Failed to open {:?}: {:?}", path, e))?;
 
         if print_info {
             if let Some(file_name) = path.file_name() {
@@ -85,6 +108,34 @@ impl FileProperties for FilePathWithMetadata {
         let reader = BufReader::new(file);
         self.decode_orientation(reader)
     }
+
+    fn dimensions(&self) -> Result<(u32, u32), String> {
+        let file = File::open(&self.full_path)
+            .map_err(|e| format!("Cannot open file {}: {:?}", &self.full_path, e))?;
+        let reader = BufReader::new(file);
+        self.decode_dimensions(reader)
+    }
+
+    fn color_type(&self) -> Result<ColorType, String> {
+        let file = File::open(&self.full_path)
+            .map_err(|e| format!("Cannot open file {}: {:?}", &self.full_path, e))?;
+        let reader = BufReader::new(file);
+        self.decode_color_type(reader)
+    }
+
+    fn icc_profile(&self) -> Result<Option<Vec<u8>>, String> {
+        let file = File::open(&self.full_path)
+            .map_err(|e| format!("Cannot open file {}: {:?}", &self.full_path, e))?;
+        let reader = BufReader::new(file);
+        self.decode_icc_profile(reader)
+    }
+
+    fn animation_frames(&self) -> Result<Option<Vec<Frame>>, String> {
+        let file = File::open(&self.full_path)
+            .map_err(|e| format!("Cannot open file {}: {:?}", &self.full_path, e))?;
+        let reader = BufReader::new(file);
+        self.decode_animation_frames(reader)
+    }
 }
 
 /// Wrapper for a file's location by its absolute filesystem path
@@ -105,6 +156,10 @@ impl FileLocation<FilePathWithMetadata> for FilePath {
         Ok(path_buf.is_file())
     }
 
+    fn content_format(&self) -> Result<Option<ImageFormat>, String> {
+        sniff_file_format(&self.path)
+    }
+
     fn extension(&self) -> Result<String, String> {
         let path_buf = PathBuf::from(&self.path);
         path_buf
@@ -135,6 +190,7 @@ impl FileLocation<FilePathWithMetadata> for FilePath {
             full_path: path_string,
             modify_time,
             size_bytes,
+            content_format: OnceLock::new(),
         };
         Ok(properties)
     }
@@ -1,5 +1,6 @@
+use crate::files::util::{content_format, mime_type_format};
 use crate::{FileLocation, FileProperties};
-use image::{metadata::Orientation, DynamicImage, ImageFormat};
+use image::{metadata::Orientation, ColorType, DynamicImage, Frame, ImageFormat};
 use std::io::Cursor;
 use std::time::SystemTime;
 
@@ -23,12 +24,12 @@ impl<'a> RawBufferProperties<'a> {
 
 impl<'a> FileProperties for RawBufferProperties<'a> {
     fn infer_format(&self) -> Option<ImageFormat> {
-        match self.mime_type.as_str() {
-            "image/jpeg" => Some(ImageFormat::Jpeg),
-            "image/png" => Some(ImageFormat::Png),
-            "image/gif" => Some(ImageFormat::Gif),
-            "image/bmp" => Some(ImageFormat::Bmp),
-            "image/webp" => Some(ImageFormat::WebP),
+        content_format(self.buffer).or_else(|| mime_type_format(self.mime_type.as_str()))
+    }
+
+    fn format_mismatch(&self) -> Option<(ImageFormat, ImageFormat)> {
+        match (mime_type_format(self.mime_type.as_str()), content_format(self.buffer)) {
+            (Some(named), Some(contents)) if named != contents => Some((named, contents)),
             _ => None,
         }
     }
@@ -71,6 +72,27 @@ impl<'a> FileProperties for RawBufferProperties<'a> {
         let reader = Cursor::new(self.buffer);
         self.decode_orientation(reader)
     }
+
+    #[inline]
+    fn dimensions(&self) -> Result<(u32, u32), String> {
+        let reader = Cursor::new(self.buffer);
+        self.decode_dimensions(reader)
+    }
+
+    fn color_type(&self) -> Result<ColorType, String> {
+        let reader = Cursor::new(self.buffer);
+        self.decode_color_type(reader)
+    }
+
+    fn icc_profile(&self) -> Result<Option<Vec<u8>>, String> {
+        let reader = Cursor::new(self.buffer);
+        self.decode_icc_profile(reader)
+    }
+
+    fn animation_frames(&self) -> Result<Option<Vec<Frame>>, String> {
+        let reader = Cursor::new(self.buffer);
+        self.decode_animation_frames(reader)
+    }
 }
 
 /// Wrapper for a file's raw data, and everything that cannot be obtained from that data.
@@ -100,15 +122,12 @@ impl<'a> FileLocation<RawBufferProperties<'a>> for RawBufferLocation<'a> {
         Ok(true)
     }
 
+    fn content_format(&self) -> Result<Option<ImageFormat>, String> {
+        Ok(content_format(self.buffer))
+    }
+
     fn extension(&self) -> Result<String, String> {
-        let extension_index = match self.mime_type.find("/") {
-            Some(i) => i + 1,
-            None => return Err(format!("Could not parse MIME type: {}", self.mime_type)),
-        };
-        if extension_index >= self.mime_type.len() - 1 {
-            return Err(format!("Invalid MIME type: {}", self.mime_type));
-        }
-        Ok(self.mime_type[extension_index..].to_owned())
+        crate::files::util::mime_type_extension(&self.mime_type)
     }
 
     fn into_properties(self) -> Result<RawBufferProperties<'a>, String> {
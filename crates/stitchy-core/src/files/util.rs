@@ -1,9 +1,16 @@
 
+use crate::{FilePathWithMetadata, ImageFiles};
 use image::ImageFormat;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
 
 const BYTES_KIB: u64 = 1024;
 const BYTES_MIB: u64 = 1024 * 1024;
 
+/// Number of bytes read from the start of a file to detect its format
+pub(crate) const SNIFF_LENGTH: u64 = 32;
+
 /// Return a string representing the size of the file, in bytes, KiB, or MiB
 ///
 /// Used in [crate::ImageFileSet::into_image_contents] if outputting information about the input
@@ -26,14 +33,115 @@ pub fn make_size_string(length_bytes: u64) -> String {
     }
 }
 
-/// Mappings of known file extensions to their image format
-pub fn extension_formats() -> [(&'static str, ImageFormat); 6] {
-    [
+/// Mappings of known file extensions to their image format. Formats other than JPEG, PNG, GIF,
+/// BMP, and WebP are only included if the cargo feature for that format is enabled.
+pub fn extension_formats() -> Vec<(&'static str, ImageFormat)> {
+    #[allow(unused_mut)]
+    let mut formats = vec![
         (".jpg", ImageFormat::Jpeg),
         (".jpeg", ImageFormat::Jpeg),
         (".png", ImageFormat::Png),
         (".gif", ImageFormat::Gif),
         (".bmp", ImageFormat::Bmp),
         (".webp", ImageFormat::WebP)
-    ]
+    ];
+    #[cfg(feature = "tiff")]
+    formats.extend([(".tif", ImageFormat::Tiff), (".tiff", ImageFormat::Tiff)]);
+    #[cfg(feature = "qoi")]
+    formats.push((".qoi", ImageFormat::Qoi));
+    #[cfg(feature = "tga")]
+    formats.push((".tga", ImageFormat::Tga));
+    #[cfg(feature = "ico")]
+    formats.push((".ico", ImageFormat::Ico));
+    #[cfg(feature = "pnm")]
+    formats.extend([
+        (".pbm", ImageFormat::Pnm),
+        (".pgm", ImageFormat::Pnm),
+        (".ppm", ImageFormat::Pnm),
+        (".pnm", ImageFormat::Pnm),
+        (".pam", ImageFormat::Pnm)
+    ]);
+    #[cfg(feature = "hdr")]
+    formats.push((".hdr", ImageFormat::Hdr));
+    #[cfg(feature = "exr")]
+    formats.push((".exr", ImageFormat::OpenExr));
+    formats
+}
+
+/// Get the image format for a MIME type, if it is one of the formats enabled in this build
+pub fn mime_type_format(mime_type: &str) -> Option<ImageFormat> {
+    match mime_type {
+        "image/jpeg" => Some(ImageFormat::Jpeg),
+        "image/png" => Some(ImageFormat::Png),
+        "image/gif" => Some(ImageFormat::Gif),
+        "image/bmp" => Some(ImageFormat::Bmp),
+        "image/webp" => Some(ImageFormat::WebP),
+        #[cfg(feature = "tiff")]
+        "image/tiff" => Some(ImageFormat::Tiff),
+        #[cfg(feature = "qoi")]
+        "image/qoi" => Some(ImageFormat::Qoi),
+        #[cfg(feature = "tga")]
+        "image/x-tga" | "image/x-targa" => Some(ImageFormat::Tga),
+        #[cfg(feature = "ico")]
+        "image/x-icon" | "image/vnd.microsoft.icon" => Some(ImageFormat::Ico),
+        #[cfg(feature = "pnm")]
+        "image/x-portable-bitmap"
+        | "image/x-portable-graymap"
+        | "image/x-portable-pixmap"
+        | "image/x-portable-anymap"
+        | "image/x-portable-arbitrarymap" => Some(ImageFormat::Pnm),
+        #[cfg(feature = "hdr")]
+        "image/vnd.radiance" => Some(ImageFormat::Hdr),
+        #[cfg(feature = "exr")]
+        "image/x-exr" | "image/aces" => Some(ImageFormat::OpenExr),
+        _ => None
+    }
+}
+
+/// Get the file extension matching a MIME type. Known image types give the main extension of
+/// their format, so that they are checked against the allowed extensions the same way as files
+/// with paths; other types give their subtype.
+pub(crate) fn mime_type_extension(mime_type: &str) -> Result<String, String> {
+    if let Some(extension) = mime_type_format(mime_type)
+        .and_then(ImageFiles::<FilePathWithMetadata>::get_main_extension)
+    {
+        return Ok(extension.to_owned());
+    }
+    let extension_index = match mime_type.find("/") {
+        Some(i) => i + 1,
+        None => return Err(format!("Could not parse MIME type: {}", mime_type)),
+    };
+    if extension_index >= mime_type.len() - 1 {
+        return Err(format!("Invalid MIME type: {}", mime_type));
+    }
+    Ok(mime_type[extension_index..].to_owned())
+}
+
+/// Get the image format for a file extension, ignoring case, if it is one of the formats enabled
+/// in this build. The extension is given without its leading dot.
+pub(crate) fn extension_format(extension: &str) -> Option<ImageFormat> {
+    extension_formats()
+        .into_iter()
+        .find(|(known_extension, _)| known_extension[1..].eq_ignore_ascii_case(extension))
+        .map(|(_, format)| format)
+}
+
+/// Detect an image format from the first bytes of a file's contents, if it is one of the formats
+/// enabled in this build
+pub(crate) fn content_format(header: &[u8]) -> Option<ImageFormat> {
+    let format = image::guess_format(header).ok()?;
+    extension_formats()
+        .iter()
+        .any(|&(_, known_format)| known_format == format)
+        .then_some(format)
+}
+
+/// Read the first bytes of a file, and detect its image format from them
+pub(crate) fn sniff_file_format(path: &Path) -> Result<Option<ImageFormat>, String> {
+    let file = File::open(path).map_err(|e| format!("Cannot open file {:?}: {:?}", path, e))?;
+    let mut header = Vec::with_capacity(SNIFF_LENGTH as usize);
+    file.take(SNIFF_LENGTH)
+        .read_to_end(&mut header)
+        .map_err(|e| format!("Cannot read file {:?}: {:?}", path, e))?;
+    Ok(content_format(&header))
 }
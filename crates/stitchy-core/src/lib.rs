@@ -10,7 +10,8 @@
 //! The second process is performed by the [`Stitch`] struct and its associated builder pattern.
 //! The configuration sets the output image size and layout of the output image. The output image
 //! is returned as an in-memory struct of type [`DynamicImage`], re-exported from the image crate
-//! (see the [image crate on crates.io](https://crates.io/crates/image)).
+//! (see the [image crate on crates.io](https://crates.io/crates/image)). The layout can also be
+//! worked out without rendering anything, using [`StitchBuilder::plan`].
 //!
 //! # Examples
 //!
@@ -35,13 +36,17 @@
 
 mod enums;
 mod files;
+mod parallel;
 mod stitch;
 
 #[cfg(test)]
 mod tests;
 
 /// Enums used for configuring the image stitching process
-pub use enums::{OrderBy, TakeFrom};
+pub use enums::{
+    AnimationSync, LineAlignment, MajorAxis, OrderBy, OrientationMode, PixelFormat, ResampleMode,
+    ResizeFilter, TakeFrom, WorkingSpace,
+};
 
 /// Types used for loading files and passing them into the image stitching process
 pub use files::{
@@ -57,7 +62,11 @@ pub use files::{
 pub use files::fd::{OwnedRawFdLocation, OwnedRawFdProperties};
 
 /// Type used for running the image stitching process
-pub use stitch::{builder::StitchBuilder, AlignmentMode, Stitch};
+pub use stitch::{
+    builder::StitchBuilder,
+    plan::{Placement, StitchPlan},
+    AlignmentMode, Crossfade, GridSpec, Stitch, UnsharpMask,
+};
 
 /// File utilities, used by the CLI crate
 pub mod util {
@@ -68,13 +77,19 @@ pub mod util {
 pub mod image {
     pub use image::{
         codecs::{
-            gif::GifEncoder,
+            gif::{GifEncoder, Repeat},
             jpeg::JpegEncoder,
-            png::{CompressionType as PngCompressionType, FilterType as PngFilterType, PngEncoder}
+            png::{CompressionType as PngCompressionType, FilterType as PngFilterType, PngEncoder},
+            webp::WebPEncoder,
         },
         imageops::FilterType,
         metadata::Orientation,
-        DynamicImage, Frame, GenericImage, ImageDecoder,
-        ImageError, ImageFormat,
+        ColorType, Delay, DynamicImage, Frame, GenericImage, ImageDecoder, ImageEncoder,
+        ImageError, ImageFormat, Rgba, RgbaImage,
     };
+
+    #[cfg(feature = "avif")]
+    pub use image::codecs::avif::AvifEncoder;
+    #[cfg(feature = "pnm")]
+    pub use image::codecs::pnm::{PnmEncoder, PnmSubtype, SampleEncoding as PnmSampleEncoding};
 }
@@ -0,0 +1,77 @@
+use std::collections::BTreeMap;
+use std::num::NonZeroUsize;
+use std::sync::{mpsc, Mutex};
+
+/// Get the number of threads to use for some number of items, where zero requests one thread per
+/// available CPU core. Never more threads than items are used, and always at least one.
+pub(crate) fn resolve_thread_count(threads: usize, item_count: usize) -> usize {
+    let threads = match threads {
+        0 => std::thread::available_parallelism()
+            .map(NonZeroUsize::get)
+            .unwrap_or(1),
+        n => n,
+    };
+    threads.min(item_count).max(1)
+}
+
+/// Run some work on every item using a number of threads, passing each result to the consumer
+/// on the calling thread in the same order as the items were given. Results that finish early
+/// are held until all earlier results have been consumed, so that the consumer sees exactly what
+/// it would if all work were done on a single thread.
+///
+/// Stops at the first error from either the work or the consumer, and returns that error.
+pub(crate) fn map_in_order<T, R, W, C>(
+    items: Vec<T>,
+    threads: usize,
+    work: W,
+    mut consume: C,
+) -> Result<(), String>
+where
+    T: Send,
+    R: Send,
+    W: Fn(T) -> Result<R, String> + Sync,
+    C: FnMut(usize, R) -> Result<(), String>,
+{
+    let threads = resolve_thread_count(threads, items.len());
+    if threads == 1 {
+        for (index, item) in items.into_iter().enumerate() {
+            consume(index, work(item)?)?;
+        }
+        return Ok(());
+    }
+
+    let queue = Mutex::new(items.into_iter().enumerate());
+    std::thread::scope(|scope| {
+        let (sender, receiver) = mpsc::sync_channel::<(usize, Result<R, String>)>(threads);
+        for _ in 0..threads {
+            let sender = sender.clone();
+            let queue = &queue;
+            let work = &work;
+            scope.spawn(move || loop {
+                let next = match queue.lock() {
+                    Ok(mut queue) => queue.next(),
+                    Err(_) => None,
+                };
+                let Some((index, item)) = next else { break };
+
+                // Sending fails once the receiver has stopped early, so stop working too
+                if sender.send((index, work(item))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(sender);
+
+        // Consume results in order, holding any that arrive before their turn
+        let mut next_index = 0;
+        let mut waiting: BTreeMap<usize, R> = BTreeMap::new();
+        for (index, result) in receiver.iter() {
+            waiting.insert(index, result?);
+            while let Some(result) = waiting.remove(&next_index) {
+                consume(next_index, result)?;
+                next_index += 1;
+            }
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,139 @@
+use crate::{
+    parallel,
+    image::DynamicImage,
+    stitch::{
+        plan::{Placement, StitchPlan},
+        RenderOptions,
+    },
+    AnimationSync, FileProperties, ImageFiles, PixelFormat, Stitch,
+};
+use image::{Delay, Frame};
+use std::collections::BTreeSet;
+
+/// How long a frame is shown when its file gives it no delay, which is what web browsers do
+const DEFAULT_FRAME_DELAY_MS: u32 = 100;
+
+/// The frames of one source, already resized to its place in the output
+struct SourceFrames {
+    frames: Vec<DynamicImage>,
+
+    /// The time each frame is first shown, in milliseconds from the start of the source
+    start_times: Vec<u32>,
+
+    /// The length of the source in milliseconds, which is zero for a still image
+    duration: u32,
+}
+
+impl SourceFrames {
+    fn new(frames: Vec<(DynamicImage, u32)>) -> Self {
+        let animated = frames.len() > 1;
+        let mut start_times = Vec::with_capacity(frames.len());
+        let mut duration: u32 = 0;
+        let frames = frames
+            .into_iter()
+            .map(|(image, delay)| {
+                start_times.push(duration);
+                if animated {
+                    let delay = if delay == 0 { DEFAULT_FRAME_DELAY_MS } else { delay };
+                    duration = duration.saturating_add(delay);
+                }
+                image
+            })
+            .collect();
+        Self { frames, start_times, duration }
+    }
+
+    /// Get the index of the frame showing at some time from the start of the output
+    fn frame_at(&self, time: u32, sync: AnimationSync) -> usize {
+        if self.duration == 0 {
+            return 0;
+        }
+        let time = match sync {
+            AnimationSync::Loop => time % self.duration,
+            AnimationSync::Hold if time >= self.duration => return self.frames.len() - 1,
+            AnimationSync::Hold => time,
+        };
+        self.start_times.partition_point(|&start| start <= time) - 1
+    }
+
+    /// Add each time before the end of the output at which this source moves to another frame
+    fn add_change_times(&self, times: &mut BTreeSet<u32>, output_duration: u32, sync: AnimationSync) {
+        if self.duration == 0 {
+            return;
+        }
+        let mut offset: u32 = 0;
+        while offset < output_duration {
+            times.extend(self.start_times
+                .iter()
+                .map(|&start| offset.saturating_add(start))
+                .filter(|&time| time < output_duration));
+            if sync == AnimationSync::Hold {
+                break;
+            }
+            offset = offset.saturating_add(self.duration);
+        }
+    }
+}
+
+impl Stitch {
+
+    /// Render a plan as an animation, loading every frame of each file and resizing it to the
+    /// file's place in the output. A new output frame starts whenever any source changes frame,
+    /// and the output lasts as long as the longest source. Output frames are always 8-bit RGBA,
+    /// and a single frame with no delay is returned if none of the files is animated.
+    pub(crate) fn stitch_animation<P: FileProperties + Send>(
+        plan: &StitchPlan,
+        files: ImageFiles<P>,
+        render: RenderOptions,
+        sync: AnimationSync,
+        print_info: bool,
+    ) -> Result<Vec<Frame>, String> {
+        let render = RenderOptions { pixel_format: PixelFormat::Rgba8, ..render };
+        let (files, orientation_mode) = files.into_files();
+        let work: Vec<(P, &Placement)> = files.into_iter()
+            .zip(plan.placements.iter())
+            .collect();
+        let mut sources = Vec::with_capacity(work.len());
+        let render = &render;
+        parallel::map_in_order(
+            work,
+            render.threads,
+            |(file, placement)| {
+                let frames = ImageFiles::load_frames(
+                    file, print_info, orientation_mode, render.working_space)?;
+                Ok(SourceFrames::new(frames
+                    .into_iter()
+                    .map(|(img, delay)| (Self::resize(&img, placement, render), delay))
+                    .collect()))
+            },
+            |_, source| {
+                sources.push(source);
+                Ok(())
+            },
+        )?;
+
+        // Find every time at which some part of the output changes
+        let output_duration = sources.iter().map(|source| source.duration).max().unwrap_or(0);
+        let mut change_times = BTreeSet::from([0]);
+        for source in sources.iter() {
+            source.add_change_times(&mut change_times, output_duration, sync);
+        }
+        let change_times: Vec<u32> = change_times.into_iter().collect();
+
+        // Draw the frame showing from each source at each of those times
+        let background = Self::new_canvas(plan, render, &[])?;
+        let mut frames = Vec::with_capacity(change_times.len());
+        for (index, &time) in change_times.iter().enumerate() {
+            let end_time = change_times.get(index + 1).copied().unwrap_or(output_duration);
+            let mut output_image = background.clone();
+            for (source, placement) in sources.iter().zip(plan.placements.iter()) {
+                let frame = &source.frames[source.frame_at(time, sync)];
+                Self::paint(&mut output_image, frame.clone(), placement, render)?;
+            }
+            let delay = Delay::from_numer_denom_ms(end_time - time, 1);
+            frames.push(Frame::from_parts(output_image.into_rgba8(), 0, 0, delay));
+        }
+
+        Ok(frames)
+    }
+}
@@ -1,6 +1,10 @@
 use crate::{
-    image::{DynamicImage, FilterType},
-    AlignmentMode, FileProperties, ImageFiles, Stitch,
+    image::{DynamicImage, FilterType, Frame, Rgba},
+    stitch::{
+        optimiser::GridCandidate, pen::ImageSize, plan::StitchPlan, LayoutOptions, RenderOptions,
+    },
+    AlignmentMode, AnimationSync, Crossfade, FileProperties, GridSpec, ImageFiles, LineAlignment,
+    PixelFormat, ResampleMode, Stitch, UnsharpMask, WorkingSpace,
 };
 use std::fmt::Debug;
 
@@ -14,9 +18,23 @@ use std::fmt::Debug;
 pub struct StitchBuilder {
     pub(crate) images: Vec<DynamicImage>,
     pub(crate) alignment: AlignmentMode,
+    pub(crate) grid: GridSpec,
     pub(crate) width_limit: u32,
     pub(crate) height_limit: u32,
-    pub(crate) resize_filter: FilterType
+    pub(crate) target_aspect_ratio: Option<f64>,
+    pub(crate) line_alignment: LineAlignment,
+    pub(crate) spacing: u32,
+    pub(crate) margin: u32,
+    pub(crate) background: Rgba<u8>,
+    pub(crate) resize_filter: FilterType,
+    pub(crate) resample_mode: ResampleMode,
+    pub(crate) unsharp_mask: Option<UnsharpMask>,
+    pub(crate) pixel_format: PixelFormat,
+    pub(crate) working_space: WorkingSpace,
+    pub(crate) threads: usize,
+    pub(crate) animation_sync: AnimationSync,
+    pub(crate) slide_delay: u32,
+    pub(crate) crossfade: Option<Crossfade>
 }
 
 impl Default for StitchBuilder {
@@ -24,9 +42,23 @@ impl Default for StitchBuilder {
         Self {
             images: vec![],
             alignment: AlignmentMode::default(),
+            grid: GridSpec::default(),
             width_limit: 0,
             height_limit: 0,
-            resize_filter: FilterType::Lanczos3
+            target_aspect_ratio: None,
+            line_alignment: LineAlignment::default(),
+            spacing: 0,
+            margin: 0,
+            background: Rgba([0, 0, 0, 0]),
+            resize_filter: FilterType::Lanczos3,
+            resample_mode: ResampleMode::default(),
+            unsharp_mask: None,
+            pixel_format: PixelFormat::default(),
+            working_space: WorkingSpace::default(),
+            threads: 1,
+            animation_sync: AnimationSync::default(),
+            slide_delay: 1000,
+            crossfade: None
         }
     }
 }
@@ -36,11 +68,18 @@ impl StitchBuilder {
         StitchBuilder { images, ..self }
     }
 
+    /// Load the image data from all of the files, to be stitched along with the rest of the
+    /// configuration. Every image is held in memory until the stitch is done; use
+    /// [StitchBuilder::stitch_files] instead to load images one at a time.
+    ///
+    /// Files are loaded using the number of threads set with [StitchBuilder::threads], and
+    /// converted into the colour space set with [StitchBuilder::working_space], so set those
+    /// first if needed.
     pub fn image_files<P>(self, files: ImageFiles<P>) -> Result<StitchBuilder, String>
     where
-        P: FileProperties + Debug,
+        P: FileProperties + Debug + Send,
     {
-        let images = files.into_image_contents(false)?;
+        let images = files.load_all(false, self.threads, self.working_space)?;
         Ok(StitchBuilder { images, ..self })
     }
 
@@ -48,6 +87,13 @@ impl StitchBuilder {
         StitchBuilder { alignment, ..self }
     }
 
+    /// Fix any of the rows, columns, or main axis used in the [AlignmentMode::Grid] layout.
+    /// The [AlignmentMode::Masonry] layout uses the number of columns only, and other alignment
+    /// modes ignore this entirely.
+    pub fn grid(self, grid: GridSpec) -> StitchBuilder {
+        StitchBuilder { grid, ..self }
+    }
+
     pub fn width_limit(self, width_limit: u32) -> StitchBuilder {
         StitchBuilder {
             width_limit,
@@ -62,6 +108,42 @@ impl StitchBuilder {
         }
     }
 
+    /// Set the preferred ratio of width to height for the output. This is used when choosing
+    /// an arrangement for the [AlignmentMode::Grid] layout, which would otherwise prefer a square
+    /// output. It is a preference only, weighed against leaving empty space and downscaling
+    /// images; the output will not be cropped or padded to match.
+    pub fn target_aspect_ratio(self, aspect_ratio: f64) -> StitchBuilder {
+        StitchBuilder {
+            target_aspect_ratio: Some(aspect_ratio),
+            ..self
+        }
+    }
+
+    /// Set where images sit within lines that are shorter than the longest line, such as the last
+    /// line of a grid that is not completely filled. Applies to the [AlignmentMode::Grid],
+    /// [AlignmentMode::Horizontal], and [AlignmentMode::Vertical] layouts.
+    pub fn line_alignment(self, line_alignment: LineAlignment) -> StitchBuilder {
+        StitchBuilder { line_alignment, ..self }
+    }
+
+    /// Set the gap, in pixels, left between neighbouring images. Gaps keep their size when the
+    /// output is scaled down to fit the width or height limit.
+    pub fn spacing(self, spacing: u32) -> StitchBuilder {
+        StitchBuilder { spacing, ..self }
+    }
+
+    /// Set the border, in pixels, left around the outside of the output. The width and height
+    /// limits apply to the output including its margin.
+    pub fn margin(self, margin: u32) -> StitchBuilder {
+        StitchBuilder { margin, ..self }
+    }
+
+    /// Set the colour painted behind the images, which shows in gaps, margins, and any space not
+    /// covered by an image. The default is fully transparent.
+    pub fn background(self, background: Rgba<u8>) -> StitchBuilder {
+        StitchBuilder { background, ..self }
+    }
+
     pub fn resize_filter(self, resize_filter: FilterType) -> StitchBuilder {
         StitchBuilder {
             resize_filter,
@@ -69,17 +151,265 @@ impl StitchBuilder {
         }
     }
 
+    /// Set how pixel values are treated while resizing; see [ResampleMode] for the trade-offs
+    pub fn resample_mode(self, resample_mode: ResampleMode) -> StitchBuilder {
+        StitchBuilder { resample_mode, ..self }
+    }
+
+    /// Sharpen each image after resizing it, using an unsharp mask. Off by default.
+    pub fn unsharp_mask(self, unsharp_mask: UnsharpMask) -> StitchBuilder {
+        StitchBuilder {
+            unsharp_mask: Some(unsharp_mask),
+            ..self
+        }
+    }
+
+    /// Set the pixel type of the output image. By default it follows the sources, keeping 16-bit
+    /// and floating point precision where any source has it; see [PixelFormat].
+    pub fn pixel_format(self, pixel_format: PixelFormat) -> StitchBuilder {
+        StitchBuilder {
+            pixel_format,
+            ..self
+        }
+    }
+
+    /// Set the colour space that images loaded from files are converted into, using the colour
+    /// profile embedded in each file. This is sRGB by default. Images given directly to this
+    /// builder are assumed to be in this colour space already.
+    pub fn working_space(self, working_space: WorkingSpace) -> StitchBuilder {
+        StitchBuilder {
+            working_space,
+            ..self
+        }
+    }
+
+    /// Set the number of threads used for loading and resizing images, where zero uses one thread
+    /// per CPU core. The default is a single thread. The output is the same whatever the number
+    /// of threads, though with more threads, more images may be held in memory at once.
+    pub fn threads(self, threads: usize) -> StitchBuilder {
+        StitchBuilder { threads, ..self }
+    }
+
+    /// Set how animations shorter than the longest one are lined up with it, when stitching with
+    /// [StitchBuilder::stitch_animation]
+    pub fn animation_sync(self, animation_sync: AnimationSync) -> StitchBuilder {
+        StitchBuilder { animation_sync, ..self }
+    }
+
+    /// Set how long each slide is shown for in a slideshow, in milliseconds. Defaults to one
+    /// second.
+    pub fn slide_delay(self, slide_delay: u32) -> StitchBuilder {
+        StitchBuilder { slide_delay, ..self }
+    }
+
+    /// Blend each slide of a slideshow into the next over a number of frames. Off by default.
+    pub fn crossfade(self, crossfade: Crossfade) -> StitchBuilder {
+        StitchBuilder {
+            crossfade: Some(crossfade),
+            ..self
+        }
+    }
+
+    /// Work out the layout without rendering anything, returning the output size and where each
+    /// image will be placed. The plan is the same one that [StitchBuilder::stitch] would render.
+    pub fn plan(&self) -> Result<StitchPlan, String> {
+        let layout = self.layout_options(self.images.len())?;
+        let plan = Stitch::plan_for_sizes(&Stitch::image_sizes(&self.images), &layout);
+        Self::check_plan(plan, &layout)
+    }
+
+    /// Work out the layout for a set of image files without loading their image data, reading
+    /// only the dimensions from each file's header. Any images already given to this builder are
+    /// not included.
+    pub fn plan_files<P>(&self, files: &ImageFiles<P>) -> Result<StitchPlan, String>
+    where
+        P: FileProperties,
+    {
+        let image_sizes: Vec<ImageSize> = files
+            .dimensions()?
+            .into_iter()
+            .map(|(w, h)| ImageSize::new(w, h))
+            .collect();
+        let layout = self.layout_options(image_sizes.len())?;
+        Self::check_plan(Stitch::plan_for_sizes(&image_sizes, &layout), &layout)
+    }
+
     pub fn stitch(self) -> Result<DynamicImage, String> {
-        if self.images.is_empty() {
+        let layout = self.layout_options(self.images.len())?;
+        let render = self.render_options()?;
+        let stitch = Stitch::new(self.images, layout, render);
+        Self::check_plan(stitch.plan(), &layout)?;
+        stitch.stitch()
+    }
+
+    /// Stitch a set of image files, loading them one at a time so that only one source image per
+    /// thread is held in memory alongside the output. The layout is planned from the file headers
+    /// first, as with [StitchBuilder::plan_files]. Any images already given to this builder are
+    /// not included.
+    pub fn stitch_files<P>(
+        self,
+        files: ImageFiles<P>,
+        print_info: bool,
+    ) -> Result<DynamicImage, String>
+    where
+        P: FileProperties + Send,
+    {
+        let plan = self.plan_files(&files)?;
+        Stitch::stitch_files(&plan, files, self.render_options()?, print_info)
+    }
+
+    /// Stitch a set of image files into an animation, decoding every frame of animated GIF and
+    /// WebP files and giving each output frame the same layout. Still images are shown
+    /// throughout, and shorter animations are lined up with the longest as set by
+    /// [StitchBuilder::animation_sync]. Each frame lasts until any source changes frame, and
+    /// frames without a delay in their file are shown for 100 milliseconds.
+    ///
+    /// Frames are 8-bit RGBA regardless of the pixel format set. If none of the files is
+    /// animated, a single frame with no delay is returned. Any images already given to this
+    /// builder are not included.
+    pub fn stitch_animation<P>(
+        self,
+        files: ImageFiles<P>,
+        print_info: bool,
+    ) -> Result<Vec<Frame>, String>
+    where
+        P: FileProperties + Send,
+    {
+        let plan = self.plan_files(&files)?;
+        Stitch::stitch_animation(
+            &plan, files, self.render_options()?, self.animation_sync, print_info)
+    }
+
+    /// Turn a set of image files into the frames of a slideshow, showing one file at a time in
+    /// their current order, such as set by [ImageFiles::sort_and_truncate_by]. Every slide has the
+    /// same size, being large enough for the widest and the tallest file within the width and
+    /// height limits, and each file is scaled to fit within it and centred, leaving the
+    /// background colour around it. Animated files show their first frame.
+    ///
+    /// Each slide is shown for the time set by [StitchBuilder::slide_delay], followed by the
+    /// frames of a [Crossfade] into the next slide if one is set. The layout options, spacing,
+    /// and margin are not used, and frames are 8-bit RGBA regardless of the pixel format set.
+    /// Any images already given to this builder are not included.
+    pub fn stitch_slideshow<P>(
+        self,
+        files: ImageFiles<P>,
+        print_info: bool,
+    ) -> Result<Vec<Frame>, String>
+    where
+        P: FileProperties + Send,
+    {
+        if files.file_count() == 0 {
             return Err("No images to stitch".to_owned());
         }
-        Stitch::new(
-            self.images,
-            self.alignment,
-            self.width_limit,
-            self.height_limit,
-            self.resize_filter,
-        )
-        .stitch()
+        if self.slide_delay == 0 {
+            return Err("Slides must be shown for at least 1ms".to_owned());
+        }
+        if let Some(crossfade) = self.crossfade {
+            crossfade.validate()?;
+        }
+        let plan = Stitch::plan_slideshow(
+            &files.dimensions()?, self.width_limit, self.height_limit);
+        Stitch::stitch_slideshow(
+            &plan, files, self.render_options()?, self.slide_delay, self.crossfade, print_info)
+    }
+
+    /// Check the rendering configuration is usable, and collect the options for it
+    fn render_options(&self) -> Result<RenderOptions, String> {
+        if let Some(unsharp_mask) = self.unsharp_mask {
+            unsharp_mask.validate()?;
+        }
+        Ok(RenderOptions {
+            resize_filter: self.resize_filter,
+            resample_mode: self.resample_mode,
+            unsharp_mask: self.unsharp_mask,
+            background: self.background,
+            pixel_format: self.pixel_format,
+            working_space: self.working_space,
+            threads: self.threads,
+        })
+    }
+
+    /// Check the configuration is usable, and collect the options that determine the layout
+    fn layout_options(&self, image_count: usize) -> Result<LayoutOptions, String> {
+        if image_count == 0 {
+            return Err("No images to stitch".to_owned());
+        }
+        match self.alignment {
+            AlignmentMode::Grid => self.grid.validate(image_count)?,
+            AlignmentMode::Masonry => GridSpec {
+                columns: self.grid.columns,
+                ..GridSpec::default()
+            }.validate(image_count)?,
+            _ => {}
+        }
+        if let Some(aspect_ratio) = self.target_aspect_ratio {
+            if !aspect_ratio.is_finite() || aspect_ratio <= 0.0 {
+                return Err(format!("Target aspect ratio must be positive, got {}", aspect_ratio));
+            }
+        }
+        let layout = LayoutOptions {
+            alignment: self.alignment,
+            grid: self.grid,
+            width_limit: self.width_limit,
+            height_limit: self.height_limit,
+            target_aspect_ratio: self.target_aspect_ratio,
+            line_alignment: self.line_alignment,
+            spacing: self.spacing,
+            margin: self.margin,
+        };
+
+        // Check the margin and the gaps between images leave room for the images within the
+        // limits, for the layout or at least one of the grid arrangements allowed. Justified rows
+        // and masonry columns without a fixed count are arranged later, so only the margin is
+        // known to be needed.
+        let last_gap = image_count - 1;
+        let gap_counts = match self.alignment {
+            AlignmentMode::Horizontal => vec![(last_gap, 0)],
+            AlignmentMode::Vertical => vec![(0, last_gap)],
+            AlignmentMode::Grid => GridCandidate::all_allowed(self.grid, image_count)
+                .iter()
+                .map(|candidate| candidate.gap_counts(image_count))
+                .collect(),
+            AlignmentMode::Masonry => match self.grid.columns {
+                Some(columns) => vec![(columns - 1, image_count.div_ceil(columns) - 1)],
+                None => vec![(0, 0)],
+            },
+            AlignmentMode::Justified => vec![(0, 0)],
+        };
+        let fits = gap_counts.iter().any(|&(width_gaps, height_gaps)| {
+            layout.limit_without_room(width_gaps, height_gaps).is_none()
+        });
+        if !fits {
+            let (width_gaps, height_gaps) = gap_counts.first().copied().unwrap_or((0, 0));
+            if let Some((name, limit, used)) = layout.limit_without_room(width_gaps, height_gaps) {
+                return Err(match used == 2 * self.margin as u64 {
+                    true => format!(
+                        "The {} limit of {}px leaves no room inside a margin of {}px",
+                        name, limit, self.margin
+                    ),
+                    false => format!(
+                        "The {} limit of {}px leaves no room for the images, as the margin and \
+                            the {}px gaps between them take up {}px",
+                        name, limit, self.spacing, used
+                    ),
+                });
+            }
+        }
+        Ok(layout)
+    }
+
+    /// Check that every image in a plan is given some space, which it may not be if the spacing
+    /// or margin leaves too little room within the limits for a layout arranged from the image
+    /// sizes. Without spacing or margin, images as small as the limits allow are left as they are.
+    fn check_plan(plan: StitchPlan, layout: &LayoutOptions) -> Result<StitchPlan, String> {
+        if layout.spacing == 0 && layout.margin == 0 {
+            return Ok(plan);
+        }
+        if plan.placements.iter().any(|placement| placement.width == 0 || placement.height == 0) {
+            return Err(
+                "The spacing and margin leave no room for the images within the size limits"
+                    .to_owned());
+        }
+        Ok(plan)
     }
 }
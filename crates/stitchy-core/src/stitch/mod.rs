@@ -1,50 +1,191 @@
+mod animation;
 pub mod builder;
+pub mod optimiser;
 pub mod pen;
+pub mod plan;
+mod resample;
+mod slideshow;
 
 #[cfg(test)]
 mod tests;
 
 use crate::{
-    image::{DynamicImage, GenericImage, FilterType},
-    stitch::pen::{HorizontalGridPen, ImageGridPen, ImageRect, VerticalGridPen},
-    StitchBuilder,
+    parallel,
+    image::{ColorType, DynamicImage, GenericImage, FilterType, Rgba},
+    stitch::{
+        optimiser::GridCandidate,
+        pen::{
+            HorizontalGridPen, ImageGridPen, ImageRect, ImageSize, JustifiedRowPen, MasonryPen,
+            VerticalGridPen,
+        },
+        plan::{Placement, StitchPlan},
+    },
+    FileProperties, ImageFiles, LineAlignment, MajorAxis, PixelFormat, ResampleMode,
+    StitchBuilder, WorkingSpace,
 };
+use image::{imageops, ImageBuffer, Pixel, Rgba32FImage, RgbaImage};
 
 /// Layout configuration for the stitched result.
 ///
 /// Options include:
-/// - Grid, where a guess is made at a sensible number of rows and columns
+/// - Grid, where a sensible number of rows and columns is chosen
 /// - Horizontal, where all images are placed in a single row
 /// - Vertical, where all images are placed in a single column
+/// - Justified, where images are placed in rows, and each row is scaled to the full width of the
+///   output so that row heights vary but no space is left empty
+/// - Masonry, where images are scaled to the same width and each is placed at the bottom of
+///   whichever column is shortest at the time
 ///
-/// The grid layout is based on a guess of which should be the main axis (either horizontal or
-/// vertical), then guessing the main axis and cross axis dimensions, and then stitching images
-/// along the main axis until full and moving along the cross axis. Currently, this cannot be
-/// controlled.
+/// The grid layout stitches images along a main axis (either horizontal or vertical) until a line
+/// is full, and then moves along the cross axis to start the next line. The main axis and the
+/// number of images per line are chosen by trying every arrangement and scoring each one on how
+/// much of the output it fills, how little the images are scaled down, and how close the output
+/// is to a target aspect ratio (see [StitchBuilder::target_aspect_ratio]). Any of these can be
+/// fixed instead by supplying a [GridSpec]. Lines shorter than the longest, such as a partly
+/// filled last line, are positioned according to the [LineAlignment].
 #[derive(PartialEq, Debug, Copy, Clone, Default)]
 pub enum AlignmentMode {
     #[default]
     Grid,
     Horizontal,
     Vertical,
+    Justified,
+    Masonry,
 }
 
-/// An approximate aspect ratio class
-enum AspectType {
-    Wide,
-    Portrait,
-    Squarish,
+/// Fixed dimensions for the [AlignmentMode::Grid] layout. Anything left as [None] is guessed.
+/// The [AlignmentMode::Masonry] layout also uses the number of columns, if given.
+///
+/// If only the number of columns is given, rows are filled first, so that the output has exactly
+/// that many columns (provided there are enough images). Likewise, if only the number of rows is
+/// given, columns are filled first. If both are given, their product must be at least the number
+/// of images; trailing lines are left out if there are fewer images than the grid can hold.
+///
+/// The major axis can be set to override the direction in which lines are filled. Note that when
+/// filling lines along one axis with the line count fixed along the other, the number of lines
+/// may end up smaller than requested, since every line except the last is filled completely.
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub struct GridSpec {
+    pub rows: Option<usize>,
+    pub columns: Option<usize>,
+    pub major_axis: Option<MajorAxis>,
 }
 
-impl AspectType {
-    pub fn get_aspect_from_dims(w: u32, h: u32) -> AspectType {
-        let aspect_ratio: f32 = (w as f32) / (h as f32);
-        if aspect_ratio > 1.25f32 {
-            AspectType::Wide
-        } else if aspect_ratio < 0.8f32 {
-            AspectType::Portrait
-        } else {
-            AspectType::Squarish
+impl GridSpec {
+    /// Check that the dimensions given are usable for the given number of images
+    pub(crate) fn validate(&self, image_count: usize) -> Result<(), String> {
+        if self.rows == Some(0) || self.columns == Some(0) {
+            return Err("Grid rows and columns must be at least 1".to_owned());
+        }
+        if let (Some(rows), Some(columns)) = (self.rows, self.columns) {
+            if rows * columns < image_count {
+                return Err(format!(
+                    "A grid of {} rows and {} columns cannot hold {} images",
+                    rows, columns, image_count
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Settings for sharpening each image after it is resized, which helps fine detail such as text
+/// stay readable when heavily downscaled. Each image is blurred with a Gaussian of the given
+/// standard deviation, in pixels, and the difference from the blurred image is added back where
+/// it exceeds the threshold, which is on the scale of 0 to 255 of 8-bit channels.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct UnsharpMask {
+    pub sigma: f32,
+    pub threshold: i32,
+}
+
+impl UnsharpMask {
+    /// Check that the settings are usable
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if !self.sigma.is_finite() || self.sigma <= 0.0 {
+            return Err(format!("Sharpening sigma must be positive, got {}", self.sigma));
+        }
+        if self.threshold < 0 {
+            return Err(format!("Sharpening threshold cannot be negative, got {}", self.threshold));
+        }
+        Ok(())
+    }
+}
+
+/// Settings for blending each slide of a slideshow into the next. The given number of frames are
+/// added between each pair of slides, each shown for the given delay in milliseconds, and
+/// stepping evenly from one slide to the other.
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub struct Crossfade {
+    pub frames: u32,
+    pub frame_delay: u32,
+}
+
+impl Crossfade {
+    /// Check that the settings are usable
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.frames == 0 {
+            return Err("A crossfade needs at least one frame".to_owned());
+        }
+        if self.frame_delay == 0 {
+            return Err("Crossfade frames must be shown for at least 1ms".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Options that control where each image is placed in the output
+#[derive(PartialEq, Debug, Copy, Clone, Default)]
+pub(crate) struct LayoutOptions {
+    pub alignment: AlignmentMode,
+    pub grid: GridSpec,
+    pub width_limit: u32,
+    pub height_limit: u32,
+    pub target_aspect_ratio: Option<f64>,
+    pub line_alignment: LineAlignment,
+    pub spacing: u32,
+    pub margin: u32,
+}
+
+impl LayoutOptions {
+
+    /// Find the first size limit that the outer margin and the given numbers of gaps across the
+    /// width and down the height take up entirely, leaving no room for the images
+    pub(crate) fn limit_without_room(
+        &self,
+        width_gaps: usize,
+        height_gaps: usize,
+    ) -> Option<(&'static str, u32, u64)> {
+        let margins = 2 * self.margin as u64;
+        [("width", self.width_limit, width_gaps), ("height", self.height_limit, height_gaps)]
+            .into_iter()
+            .map(|(name, limit, gaps)| (name, limit, margins + gaps as u64 * self.spacing as u64))
+            .find(|&(_, limit, used)| limit > 0 && limit as u64 <= used)
+    }
+}
+
+/// Options that control how images are painted onto the output
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub(crate) struct RenderOptions {
+    pub resize_filter: FilterType,
+    pub resample_mode: ResampleMode,
+    pub unsharp_mask: Option<UnsharpMask>,
+    pub background: Rgba<u8>,
+    pub pixel_format: PixelFormat,
+    pub working_space: WorkingSpace,
+    pub threads: usize,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            resize_filter: FilterType::Lanczos3,
+            resample_mode: ResampleMode::default(),
+            unsharp_mask: None,
+            background: Rgba([0, 0, 0, 0]),
+            pixel_format: PixelFormat::default(),
+            working_space: WorkingSpace::default(),
+            threads: 1,
         }
     }
 }
@@ -55,7 +196,8 @@ pub struct Stitch {
     images: Vec<DynamicImage>,
     axis_pen: Box<dyn ImageGridPen>,
     image_rects: Vec<ImageRect>,
-    resize_filter: FilterType
+    margin: u32,
+    render: RenderOptions,
 }
 
 impl Stitch {
@@ -65,23 +207,68 @@ impl Stitch {
 
     pub(crate) fn new(
         images: Vec<DynamicImage>,
-        alignment: AlignmentMode,
-        width_limit: u32,
-        height_limit: u32,
-        resize_filter: FilterType
+        layout: LayoutOptions,
+        render: RenderOptions,
     ) -> Stitch {
-        let mut axis_pen = Self::make_axis_pen(alignment, &images);
-        let unscaled_image_rects = axis_pen.generate_output_rects(&images);
-        let image_rects = match width_limit > 0 || height_limit > 0 {
-            true => axis_pen.scale_image_rects(unscaled_image_rects, width_limit, height_limit),
-            false => unscaled_image_rects,
-        };
+        let image_sizes = Self::image_sizes(&images);
+        let (axis_pen, image_rects) = Self::lay_out(&image_sizes, &layout);
 
         Stitch {
             images,
             axis_pen,
             image_rects,
-            resize_filter
+            margin: layout.margin,
+            render,
+        }
+    }
+
+    /// Get the plan for this stitch, which gives the output size and where each image is placed
+    pub fn plan(&self) -> StitchPlan {
+        StitchPlan::new(self.axis_pen.get_output_dimensions(), &self.image_rects, self.margin)
+    }
+
+    /// Work out the plan for images of the given sizes, without needing their contents
+    pub(crate) fn plan_for_sizes(image_sizes: &[ImageSize], layout: &LayoutOptions) -> StitchPlan {
+        let (axis_pen, image_rects) = Self::lay_out(image_sizes, layout);
+        StitchPlan::new(axis_pen.get_output_dimensions(), &image_rects, layout.margin)
+    }
+
+    pub(crate) fn image_sizes(images: &[DynamicImage]) -> Vec<ImageSize> {
+        images.iter()
+            .map(|image| ImageSize::new(image.width(), image.height()))
+            .collect()
+    }
+
+    /// Choose a pen for the layout and use it to place every image
+    fn lay_out(
+        image_sizes: &[ImageSize],
+        layout: &LayoutOptions,
+    ) -> (Box<dyn ImageGridPen>, Vec<ImageRect>) {
+        let mut axis_pen = Self::make_axis_pen(layout, image_sizes);
+        let image_rects = Self::generate_rects(axis_pen.as_mut(), layout, image_sizes);
+        (axis_pen, image_rects)
+    }
+
+    /// Use a pen to generate the rects for all images, scaling them down to the size limits.
+    /// Rects are relative to the inside of the outer margin, which the limits also leave room for.
+    fn generate_rects(
+        axis_pen: &mut dyn ImageGridPen,
+        layout: &LayoutOptions,
+        image_sizes: &[ImageSize],
+    ) -> Vec<ImageRect> {
+        let unscaled_image_rects = axis_pen.generate_output_rects(image_sizes);
+        let margins = layout.margin.saturating_mul(2);
+        let width_limit = match layout.width_limit {
+            0 => 0,
+            limit => limit.saturating_sub(margins).max(1),
+        };
+        let height_limit = match layout.height_limit {
+            0 => 0,
+            limit => limit.saturating_sub(margins).max(1),
+        };
+        match width_limit > 0 || height_limit > 0 {
+            true => axis_pen.scale_image_rects(unscaled_image_rects, width_limit, height_limit),
+            false => unscaled_image_rects,
         }
     }
 
@@ -89,101 +276,361 @@ impl Stitch {
     /// The pen draws in this direction until the images per line have been drawn, then moves to
     /// the next line.
     fn make_axis_pen(
-        alignment: AlignmentMode,
-        images: &Vec<DynamicImage>,
+        layout: &LayoutOptions,
+        image_sizes: &[ImageSize],
     ) -> Box<dyn ImageGridPen> {
-        let image_count = images.len();
+        let image_count = image_sizes.len();
 
         // Check for very particular alignment modes
-        if alignment == AlignmentMode::Horizontal {
-            let smallest_height = Self::find_smallest_image_height(images);
-            let pen = HorizontalGridPen::new(image_count, 1, 1, smallest_height);
-            return Box::new(pen);
-        }
-        if alignment == AlignmentMode::Vertical {
-            let smallest_width = Self::find_smallest_image_width(images);
-            let pen = VerticalGridPen::new(image_count, 1, 1, smallest_width);
-            return Box::new(pen);
-        }
-
-        // Find the smallest value "t" that satisfies t^2 >= count
-        let mut t: usize = 1;
-        while t * t < image_count {
-            t += 1;
-        }
-
-        // Can now determine grid dimensions
-        let grid_size_main_axis = t;
-        let grid_size_cross_axis = image_count.div_ceil(t);
-        let main_lines_with_full_size = image_count / t;
-
-        // Get some stats on the aspect ratios present in the images
-        let mut wide_count = 0;
-        let mut portrait_count = 0;
-        let mut squarish_count = 0;
-        for img in images {
-            let aspect_type = AspectType::get_aspect_from_dims(img.width(), img.height());
-            match aspect_type {
-                AspectType::Wide => wide_count += 1,
-                AspectType::Portrait => portrait_count += 1,
-                AspectType::Squarish => squarish_count += 1,
+        match layout.alignment {
+            AlignmentMode::Horizontal => {
+                let smallest_height = Self::find_smallest_image_height(image_sizes);
+                return Box::new(HorizontalGridPen::new(
+                    image_count, 1, 1, smallest_height, layout.spacing, layout.line_alignment));
+            }
+            AlignmentMode::Vertical => {
+                let smallest_width = Self::find_smallest_image_width(image_sizes);
+                return Box::new(VerticalGridPen::new(
+                    image_count, 1, 1, smallest_width, layout.spacing, layout.line_alignment));
             }
+            AlignmentMode::Justified => {
+                let smallest_height = Self::find_smallest_image_height(image_sizes);
+                return Box::new(JustifiedRowPen::new(smallest_height, layout.spacing));
+            }
+            AlignmentMode::Masonry => {
+                let smallest_width = Self::find_smallest_image_width(image_sizes);
+                return Box::new(MasonryPen::new(
+                    layout.grid.columns, smallest_width, layout.spacing));
+            }
+            AlignmentMode::Grid => {}
         }
 
-        // Choose a drawing direction based on aspect ratios
-        let draw_horizontal = portrait_count > wide_count || squarish_count >= wide_count;
-        if draw_horizontal {
-            let smallest_height = Self::find_smallest_image_height(images);
+        // Try every arrangement allowed by the grid options that leaves room for the images after
+        // the gaps between them, keeping the best scoring one
+        let mut best: Option<(f64, GridCandidate)> = None;
+        let candidates = GridCandidate::all_allowed(layout.grid, image_count)
+            .into_iter()
+            .filter(|candidate| {
+                let (width_gaps, height_gaps) = candidate.gap_counts(image_count);
+                layout.limit_without_room(width_gaps, height_gaps).is_none()
+            });
+        for candidate in candidates {
+            let mut pen = Self::make_grid_pen(candidate, layout, image_sizes);
+            let image_rects = Self::generate_rects(pen.as_mut(), layout, image_sizes);
+            let score = optimiser::score_layout(
+                image_sizes,
+                &image_rects,
+                pen.get_output_dimensions(),
+                layout.target_aspect_ratio,
+            );
+            let improves = match best {
+                Some((best_score, _)) => score > best_score + optimiser::SCORE_EPSILON,
+                None => true,
+            };
+            if improves {
+                best = Some((score, candidate));
+            }
+        }
+        let chosen = match best {
+            Some((_, candidate)) => candidate,
+            None => GridCandidate { draw_horizontal: true, line_length: 1 },
+        };
+        Self::make_grid_pen(chosen, layout, image_sizes)
+    }
+
+    /// Creates a pen for one grid arrangement
+    fn make_grid_pen(
+        candidate: GridCandidate,
+        layout: &LayoutOptions,
+        image_sizes: &[ImageSize],
+    ) -> Box<dyn ImageGridPen> {
+        let image_count = image_sizes.len();
+        let grid_size_main_axis = candidate.line_length;
+        let grid_size_cross_axis = image_count.div_ceil(grid_size_main_axis);
+        let main_lines_with_full_size = image_count / grid_size_main_axis;
+        if candidate.draw_horizontal {
+            let smallest_height = Self::find_smallest_image_height(image_sizes);
             let pen = HorizontalGridPen::new(
                 grid_size_main_axis,
                 grid_size_cross_axis,
                 main_lines_with_full_size,
                 smallest_height,
+                layout.spacing,
+                layout.line_alignment,
             );
             Box::new(pen)
         } else {
-            let smallest_width = Self::find_smallest_image_width(images);
+            let smallest_width = Self::find_smallest_image_width(image_sizes);
             let pen = VerticalGridPen::new(
                 grid_size_main_axis,
                 grid_size_cross_axis,
                 main_lines_with_full_size,
                 smallest_width,
+                layout.spacing,
+                layout.line_alignment,
             );
             Box::new(pen)
         }
     }
 
-    fn find_smallest_image_width(images: &Vec<DynamicImage>) -> u32 {
+    fn find_smallest_image_width(image_sizes: &[ImageSize]) -> u32 {
         let mut smallest_size: u32 = 1024 * 1024;
-        for image in images {
-            smallest_size = smallest_size.min(image.width());
+        for image in image_sizes {
+            smallest_size = smallest_size.min(image.w);
         }
         smallest_size
     }
 
-    fn find_smallest_image_height(images: &Vec<DynamicImage>) -> u32 {
+    fn find_smallest_image_height(image_sizes: &[ImageSize]) -> u32 {
         let mut smallest_size: u32 = 1024 * 1024;
-        for image in images {
-            smallest_size = smallest_size.min(image.height());
+        for image in image_sizes {
+            smallest_size = smallest_size.min(image.h);
         }
         smallest_size
     }
 
     pub fn stitch(self) -> Result<DynamicImage, String> {
-        // Determine output file dimensions
-        let out_dimensions = self.axis_pen.get_output_dimensions();
-
-        // Create the image and paint individual images
-        let mut output_image = DynamicImage::new_rgba8(out_dimensions.w, out_dimensions.h);
-        for i in 0..self.images.len() {
-            let img = &self.images[i];
-            let rect = &self.image_rects[i];
-            let scaled_image = img.resize_exact(rect.w, rect.h, self.resize_filter);
-            if let Err(err) = output_image.copy_from(&scaled_image, rect.x, rect.y) {
-                return Err(format!("{} error while copying file #{}", err, i));
-            }
+        let plan = self.plan();
+        let source_types: Vec<ColorType> = match self.render.pixel_format {
+            PixelFormat::Auto => self.images.iter().map(|img| img.color()).collect(),
+            _ => vec![],
+        };
+        let mut output_image = Self::new_canvas(&plan, &self.render, &source_types)?;
+        let work: Vec<(&DynamicImage, &Placement)> = plan.placements.iter()
+            .map(|placement| (&self.images[placement.source_index], placement))
+            .collect();
+        let render = &self.render;
+        parallel::map_in_order(
+            work,
+            render.threads,
+            |(img, placement)| Ok(Self::resize(img, placement, render)),
+            |i, scaled_image| Self::paint(&mut output_image, scaled_image, &plan.placements[i], render),
+        )?;
+
+        Ok(output_image)
+    }
+
+    /// Render a plan by loading each file in turn, so that only one source image per thread is
+    /// held in memory alongside the output at any time
+    pub(crate) fn stitch_files<P: FileProperties + Send>(
+        plan: &StitchPlan,
+        files: ImageFiles<P>,
+        render: RenderOptions,
+        print_info: bool,
+    ) -> Result<DynamicImage, String> {
+        let source_types = match render.pixel_format {
+            PixelFormat::Auto => files.color_types()?,
+            _ => vec![],
+        };
+        let mut output_image = Self::new_canvas(plan, &render, &source_types)?;
+        let (files, orientation_mode) = files.into_files();
+        let work: Vec<(P, &Placement)> = files.into_iter()
+            .zip(plan.placements.iter())
+            .collect();
+        let render = &render;
+        parallel::map_in_order(
+            work,
+            render.threads,
+            |(file, placement)| {
+                let img = ImageFiles::load(
+                    file, print_info, orientation_mode, render.working_space)?;
+                Ok(Self::resize(&img, placement, render))
+            },
+            |i, scaled_image| Self::paint(&mut output_image, scaled_image, &plan.placements[i], render),
+        )?;
+
+        Ok(output_image)
+    }
+
+    /// Choose the colour type of the output, either as configured or following the colour
+    /// types of the sources; see [PixelFormat] for how the sources are followed
+    pub(crate) fn canvas_color_type(
+        pixel_format: PixelFormat,
+        source_types: &[ColorType],
+        opaque_background: bool,
+    ) -> ColorType {
+        match pixel_format {
+            PixelFormat::Rgba8 => return ColorType::Rgba8,
+            PixelFormat::Rgba16 => return ColorType::Rgba16,
+            PixelFormat::Rgb16 => return ColorType::Rgb16,
+            PixelFormat::Luma16 => return ColorType::L16,
+            PixelFormat::Rgba32F => return ColorType::Rgba32F,
+            PixelFormat::Auto => {}
         }
+        let is_float = |color: &ColorType| matches!(color, ColorType::Rgb32F | ColorType::Rgba32F);
+        let is_wide = |color: &ColorType| color.bytes_per_pixel() > color.channel_count();
+        if source_types.iter().any(is_float) {
+            ColorType::Rgba32F
+        } else if !source_types.iter().any(is_wide) {
+            ColorType::Rgba8
+        } else if !opaque_background || source_types.iter().any(|color| color.has_alpha()) {
+            ColorType::Rgba16
+        } else if source_types.iter().all(|color| !color.has_color()) {
+            ColorType::L16
+        } else {
+            ColorType::Rgb16
+        }
+    }
 
+    /// Create the output image, filling it with the background colour if it is not transparent
+    fn new_canvas(
+        plan: &StitchPlan,
+        render: &RenderOptions,
+        source_types: &[ColorType],
+    ) -> Result<DynamicImage, String> {
+        let background = render.background;
+        let opaque_background = background.0[3] == u8::MAX || plan.covers_output();
+        let color_type = Self::canvas_color_type(
+            render.pixel_format, source_types, opaque_background);
+        let mut output_image = DynamicImage::new(plan.width, plan.height, color_type);
+        if background.0[3] > 0 {
+            let fill = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, background));
+            match &mut output_image {
+                DynamicImage::ImageRgba8(canvas) => Self::fill(canvas, fill.to_rgba8()),
+                DynamicImage::ImageRgba16(canvas) => Self::fill(canvas, fill.to_rgba16()),
+                DynamicImage::ImageRgb16(canvas) => Self::fill(canvas, fill.to_rgb16()),
+                DynamicImage::ImageLuma16(canvas) => Self::fill(canvas, fill.to_luma16()),
+                DynamicImage::ImageRgba32F(canvas) => Self::fill(canvas, fill.to_rgba32f()),
+                _ => return Err(format!("Unsupported output colour type {:?}", color_type)),
+            }
+        }
         Ok(output_image)
     }
+
+    /// Set every pixel of an image to the single pixel of another
+    fn fill<P: Pixel>(
+        canvas: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+        colour: ImageBuffer<P, Vec<P::Subpixel>>,
+    ) {
+        let colour = *colour.get_pixel(0, 0);
+        for pixel in canvas.pixels_mut() {
+            *pixel = colour;
+        }
+    }
+
+    /// Resize one image to the size it takes in the output
+    fn resize(img: &DynamicImage, placement: &Placement, render: &RenderOptions) -> DynamicImage {
+        let resized = if render.resample_mode == ResampleMode::Linear {
+            resample::resize_linear_premultiplied(
+                img, placement.width, placement.height, render.resize_filter)
+        } else {
+            let prescaled = match render.resize_filter {
+                FilterType::Nearest => None,
+                _ => Self::prescale(img, placement.width, placement.height),
+            };
+            let source = prescaled.as_ref().unwrap_or(img);
+            source.resize_exact(placement.width, placement.height, render.resize_filter)
+        };
+        match render.unsharp_mask {
+            Some(mask) => Self::sharpen(resized, mask),
+            None => resized,
+        }
+    }
+
+    /// Apply an unsharp mask, scaling the threshold from the 8-bit scale it is given in to the
+    /// range of the image's channels. Floating point images are sharpened separately, because the
+    /// image crate compares channels as integers when sharpening.
+    fn sharpen(img: DynamicImage, mask: UnsharpMask) -> DynamicImage {
+        let wide_threshold = mask.threshold.saturating_mul(257);
+        match img {
+            DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+                DynamicImage::ImageRgba32F(Self::sharpen_float(img.to_rgba32f(), mask))
+            }
+            DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageLumaA16(_)
+            | DynamicImage::ImageRgb16(_)
+            | DynamicImage::ImageRgba16(_) => img.unsharpen(mask.sigma, wide_threshold),
+            _ => img.unsharpen(mask.sigma, mask.threshold),
+        }
+    }
+
+    /// Apply an unsharp mask to the colour of a floating point image at full precision, keeping
+    /// values brighter than white. The image crate limits floating point channels to 1.0 when
+    /// blurring, so the blur is taken of a copy brought into that range.
+    fn sharpen_float(mut img: Rgba32FImage, mask: UnsharpMask) -> Rgba32FImage {
+        let threshold = mask.threshold as f32 / 255.0;
+        let peak = img
+            .pixels()
+            .flat_map(|pixel| [pixel.0[0], pixel.0[1], pixel.0[2]])
+            .fold(1.0, f32::max);
+        let mut in_range = img.clone();
+        for pixel in in_range.pixels_mut() {
+            pixel.0[0..3].iter_mut().for_each(|channel| *channel /= peak);
+        }
+        let blurred = imageops::blur(&in_range, mask.sigma);
+        for (pixel, blurred) in img.pixels_mut().zip(blurred.pixels()) {
+            for (channel, blurred) in pixel.0[0..3].iter_mut().zip(blurred.0) {
+                let difference = *channel - blurred * peak;
+                if difference.abs() > threshold {
+                    *channel = (*channel + difference).max(0.0);
+                }
+            }
+        }
+        img
+    }
+
+    /// Shrink an image cheaply by a power of two if it is far larger than the size it will be
+    /// drawn at, so that the final filter has far fewer pixels to read. The result is kept at
+    /// least twice the final size in each dimension, leaving the final filter to decide the
+    /// quality of the output. Returns [None] if the image is not large enough to benefit.
+    ///
+    /// Ideally this reduction would happen while decoding, but the JPEG decoder used by the image
+    /// crate cannot decode at a reduced size, so it happens straight after instead.
+    fn prescale(img: &DynamicImage, width: u32, height: u32) -> Option<DynamicImage> {
+        let mut factor: u32 = 1;
+        while img.width() / (factor * 2) >= width.saturating_mul(2)
+            && img.height() / (factor * 2) >= height.saturating_mul(2)
+        {
+            factor *= 2;
+        }
+        match factor {
+            1 => None,
+            _ => Some(img.thumbnail_exact(
+                img.width().div_ceil(factor), img.height().div_ceil(factor))),
+        }
+    }
+
+    /// Paint one resized image into the output, converting it to the output's pixel type and
+    /// blending over the background where the image is translucent
+    fn paint(
+        output_image: &mut DynamicImage,
+        scaled_image: DynamicImage,
+        placement: &Placement,
+        render: &RenderOptions,
+    ) -> Result<(), String> {
+        let blend = render.background.0[3] > 0;
+        match output_image {
+            DynamicImage::ImageRgba8(canvas) => {
+                Self::paint_buffer(canvas, &scaled_image.into_rgba8(), placement, blend)
+            }
+            DynamicImage::ImageRgba16(canvas) => {
+                Self::paint_buffer(canvas, &scaled_image.into_rgba16(), placement, blend)
+            }
+            DynamicImage::ImageRgb16(canvas) => {
+                Self::paint_buffer(canvas, &scaled_image.into_rgb16(), placement, blend)
+            }
+            DynamicImage::ImageLuma16(canvas) => {
+                Self::paint_buffer(canvas, &scaled_image.into_luma16(), placement, blend)
+            }
+            DynamicImage::ImageRgba32F(canvas) => {
+                Self::paint_buffer(canvas, &scaled_image.into_rgba32f(), placement, blend)
+            }
+            _ => Err(format!("Unsupported output colour type {:?}", output_image.color())),
+        }
+    }
+
+    fn paint_buffer<P: Pixel>(
+        canvas: &mut ImageBuffer<P, Vec<P::Subpixel>>,
+        scaled_image: &ImageBuffer<P, Vec<P::Subpixel>>,
+        placement: &Placement,
+        blend: bool,
+    ) -> Result<(), String> {
+        if blend {
+            image::imageops::overlay(canvas, scaled_image, placement.x as i64, placement.y as i64);
+        } else if let Err(err) = canvas.copy_from(scaled_image, placement.x, placement.y) {
+            return Err(format!(
+                "{} error while copying file #{}", err, placement.source_index));
+        }
+        Ok(())
+    }
 }
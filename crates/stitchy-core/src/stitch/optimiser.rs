@@ -0,0 +1,109 @@
+use crate::{
+    stitch::pen::{ImageRect, ImageSize},
+    GridSpec, MajorAxis,
+};
+
+/// Margin by which a score must beat the best so far to replace it. Candidates are tried in order
+/// of preference, so that near-identical scores are settled in favour of the earlier candidate.
+pub(crate) const SCORE_EPSILON: f64 = 1e-9;
+
+/// Aspect ratio preferred for the output when no target is given
+const DEFAULT_TARGET_ASPECT_RATIO: f64 = 1.0;
+
+/// One possible arrangement for the grid layout
+#[derive(PartialEq, Debug, Copy, Clone)]
+pub(crate) struct GridCandidate {
+    pub draw_horizontal: bool,
+    pub line_length: usize,
+}
+
+impl GridCandidate {
+    /// List every arrangement that satisfies the grid options, in order of preference. Horizontal
+    /// lines are preferred to vertical ones, and longer lines are preferred to shorter ones.
+    ///
+    /// If only columns are fixed, lines are drawn horizontally so that the number of columns is
+    /// exact. Likewise, if only rows are fixed, lines are drawn vertically.
+    pub fn all_allowed(grid: GridSpec, image_count: usize) -> Vec<GridCandidate> {
+        let directions: &[bool] = match (grid.major_axis, grid.rows, grid.columns) {
+            (Some(MajorAxis::Horizontal), _, _) => &[true],
+            (Some(MajorAxis::Vertical), _, _) => &[false],
+            (None, None, Some(_)) => &[true],
+            (None, Some(_), None) => &[false],
+            _ => &[true, false],
+        };
+
+        let mut candidates = vec![];
+        for &draw_horizontal in directions {
+            let (main_axis_request, cross_axis_request) = match draw_horizontal {
+                true => (grid.columns, grid.rows),
+                false => (grid.rows, grid.columns),
+            };
+            match (main_axis_request, cross_axis_request) {
+                (Some(main), _) => {
+                    candidates.push(GridCandidate { draw_horizontal, line_length: main });
+                }
+                (None, Some(cross)) => {
+                    let line_length = image_count.div_ceil(cross).max(1);
+                    candidates.push(GridCandidate { draw_horizontal, line_length });
+                }
+                (None, None) => {
+                    for line_length in (1..=image_count).rev() {
+                        candidates.push(GridCandidate { draw_horizontal, line_length });
+                    }
+                }
+            }
+        }
+        candidates
+    }
+
+    /// Count the gaps across the width and down the height of this arrangement
+    pub fn gap_counts(&self, image_count: usize) -> (usize, usize) {
+        let line_length = self.line_length.clamp(1, image_count.max(1));
+        let along_lines = line_length - 1;
+        let across_lines = image_count.div_ceil(line_length).saturating_sub(1);
+        match self.draw_horizontal {
+            true => (along_lines, across_lines),
+            false => (across_lines, along_lines),
+        }
+    }
+}
+
+/// Score a layout, where higher is better. The score is the sum of three terms:
+/// - The log of the fraction of the output covered by images, which is zero when there is no
+///   empty space and negative otherwise
+/// - The mean log of the linear scale applied to each image, which is negative when images are
+///   downscaled, and positive when they are upscaled to fill lines
+/// - The negated absolute log of the ratio between the output aspect ratio and the target, which
+///   is zero at the target and negative for outputs that are too wide or too tall (square is the
+///   target if none is given)
+///
+/// The terms are weighed equally, so the aspect ratio is only a preference; a layout well away
+/// from the target can still win if it leaves less empty space or keeps images at a larger size.
+pub(crate) fn score_layout(
+    image_sizes: &[ImageSize],
+    image_rects: &[ImageRect],
+    output_size: ImageSize,
+    target_aspect_ratio: Option<f64>,
+) -> f64 {
+    if image_sizes.is_empty() || output_size.w == 0 || output_size.h == 0 {
+        return f64::NEG_INFINITY;
+    }
+
+    let mut covered_area: f64 = 0.0;
+    let mut log_scale_sum: f64 = 0.0;
+    for (image, rect) in image_sizes.iter().zip(image_rects.iter()) {
+        let rect_area = (rect.w as f64) * (rect.h as f64);
+        let source_area = (image.w as f64) * (image.h as f64);
+        covered_area += rect_area;
+        log_scale_sum += 0.5 * (rect_area / source_area).ln();
+    }
+
+    let output_area = (output_size.w as f64) * (output_size.h as f64);
+    let fill_term = (covered_area / output_area).ln();
+    let scale_term = log_scale_sum / (image_sizes.len() as f64);
+    let target = target_aspect_ratio.unwrap_or(DEFAULT_TARGET_ASPECT_RATIO);
+    let aspect_ratio = (output_size.w as f64) / (output_size.h as f64);
+    let aspect_term = -(aspect_ratio / target).ln().abs();
+
+    fill_term + scale_term + aspect_term
+}
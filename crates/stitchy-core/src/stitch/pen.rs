@@ -1,7 +1,8 @@
-use image::DynamicImage;
+use crate::LineAlignment;
 use std::cmp::min;
 
 /// Size and position of an area within an image
+#[derive(PartialEq, Debug, Copy, Clone)]
 pub(crate) struct ImageRect {
     pub x: u32,
     pub y: u32,
@@ -10,6 +11,7 @@ pub(crate) struct ImageRect {
 }
 
 /// Size of an image
+#[derive(PartialEq, Debug, Copy, Clone)]
 pub(crate) struct ImageSize {
     pub w: u32,
     pub h: u32,
@@ -33,7 +35,7 @@ pub(crate) trait ImageGridPen {
     fn get_lines_at_full_size(&self) -> usize;
 
     fn get_output_dimensions(&self) -> ImageSize;
-    fn generate_output_rects(&mut self, images: &[DynamicImage]) -> Vec<ImageRect>;
+    fn generate_output_rects(&mut self, image_sizes: &[ImageSize]) -> Vec<ImageRect>;
     fn scale_image_rects(
         &mut self,
         image_rects: Vec<ImageRect>,
@@ -49,6 +51,9 @@ pub(crate) struct HorizontalGridPen {
     lines_at_full_size: usize,
     longest_line_length_pixels: u32,
     line_size_pixels: u32,
+    total_lines_size_pixels: u32,
+    spacing_pixels: u32,
+    line_alignment: LineAlignment,
 }
 
 impl HorizontalGridPen {
@@ -57,6 +62,8 @@ impl HorizontalGridPen {
         line_count: usize,
         lines_at_full_size: usize,
         line_size_pixels: u32,
+        spacing_pixels: u32,
+        line_alignment: LineAlignment,
     ) -> Self {
         Self {
             line_length,
@@ -64,6 +71,9 @@ impl HorizontalGridPen {
             lines_at_full_size,
             longest_line_length_pixels: 0,
             line_size_pixels,
+            total_lines_size_pixels: total_line_size(line_size_pixels, line_count, spacing_pixels),
+            spacing_pixels,
+            line_alignment,
         }
     }
 }
@@ -85,23 +95,25 @@ impl ImageGridPen for HorizontalGridPen {
     }
 
     fn get_output_dimensions(&self) -> ImageSize {
-        ImageSize::new(
-            self.longest_line_length_pixels,
-            self.line_size_pixels * self.line_count as u32,
-        )
+        ImageSize::new(self.longest_line_length_pixels, self.total_lines_size_pixels)
     }
 
-    fn generate_output_rects(&mut self, images: &[DynamicImage]) -> Vec<ImageRect> {
+    fn generate_output_rects(&mut self, image_sizes: &[ImageSize]) -> Vec<ImageRect> {
         let mut image_rects: Vec<ImageRect> = vec![];
         let mut pen_x: u32 = 0;
         let mut pen_y: u32 = 0;
         let mut grid_x: usize = 0;
         let mut grid_y: usize = 0;
 
-        for image in images {
+        for image in image_sizes {
+            // Leave a gap before each image except the first in a line
+            if grid_x > 0 {
+                pen_x += self.spacing_pixels;
+            }
+
             // Get sizing for this image
-            let scaling_factor = (self.line_size_pixels as f64) / (image.height() as f64);
-            let scaled_width = ((image.width() as f64) * scaling_factor) as u32;
+            let scaling_factor = (self.line_size_pixels as f64) / (image.h as f64);
+            let scaled_width = ((image.w as f64) * scaling_factor) as u32;
             image_rects.push(ImageRect {
                 x: pen_x,
                 y: pen_y,
@@ -111,23 +123,20 @@ impl ImageGridPen for HorizontalGridPen {
 
             // Advance pen and grid positions
             pen_x += scaled_width;
+            if self.longest_line_length_pixels < pen_x {
+                self.longest_line_length_pixels = pen_x;
+            }
             grid_x += 1;
             if (grid_y <= self.lines_at_full_size) && (grid_x >= self.line_length) {
-                if self.longest_line_length_pixels < pen_x {
-                    self.longest_line_length_pixels = pen_x;
-                }
                 pen_x = 0;
-                pen_y += self.line_size_pixels;
+                pen_y += self.line_size_pixels + self.spacing_pixels;
                 grid_x = 0;
                 grid_y += 1;
             }
         }
 
-        // Check if the pen overshot the known boundary before filling a grid row
-        if self.longest_line_length_pixels < pen_x {
-            self.longest_line_length_pixels = pen_x;
-        }
-
+        self.total_lines_size_pixels = align_lines(
+            &mut image_rects, self.line_length, self.spacing_pixels, self.line_alignment, true);
         image_rects
     }
 
@@ -145,7 +154,7 @@ impl ImageGridPen for HorizontalGridPen {
         };
 
         // Height is cross axis
-        let total_height = (self.line_count as u32) * self.line_size_pixels;
+        let total_height = self.get_output_dimensions().h;
         let allowed_height = if height_limit == 0 {
             total_height
         } else {
@@ -157,23 +166,52 @@ impl ImageGridPen for HorizontalGridPen {
             return image_rects;
         }
 
+        // Scale according to the greatest necessary reduction, where the gaps between images keep
+        // their size and only the images are scaled
+        let width_scale = max_line_scale(
+            &image_rects, self.line_length, allowed_width, self.spacing_pixels, |rect| rect.w);
+        let height_scale = available_scale(
+            allowed_height,
+            image_rects.chunks(self.line_length.max(1)).map(|line| line[0].h).sum(),
+            self.line_count,
+            self.spacing_pixels,
+        );
+        let gaps = ImageSize::new(
+            self.spacing_pixels * (self.line_length.min(image_rects.len()).max(1) as u32 - 1),
+            self.spacing_pixels * (self.line_count.max(1) as u32 - 1),
+        );
+        let allowed = ImageSize::new(allowed_width, allowed_height);
+        let mut using_scale: f64 = f64::min(width_scale, height_scale);
+
+        // Aligning can make a filled last line thicker than allowed for, so check the output
+        // against the limits again, scaling down further until it fits
+        loop {
+            let scaled_rects = self.place_scaled_rects(&image_rects, using_scale);
+            match reduced_scale(using_scale, self.get_output_dimensions(), allowed, gaps) {
+                Some(scale) => using_scale = scale,
+                None => return scaled_rects,
+            }
+        }
+    }
+}
+
+impl HorizontalGridPen {
+
+    /// Place the images at the given scale, keeping the gaps between them, then align the lines
+    fn place_scaled_rects(&mut self, image_rects: &[ImageRect], using_scale: f64) -> Vec<ImageRect> {
         // Reset the longest known line sizes; they will be updated
         self.longest_line_length_pixels = 0;
         self.line_size_pixels = 0;
 
-        // Scale according to the greatest necessary reduction
-        let width_scale = allowed_width as f64 / total_width as f64;
-        let height_scale = allowed_height as f64 / total_height as f64;
-        let using_scale: f64 = f64::min(width_scale, height_scale);
-
         // For each image, downscale its position and size
+        let spacing = self.spacing_pixels as f64;
         let mut pen_x: f64 = 0.0;
         let mut pen_y: f64 = 0.0;
         let mut next_pen_x: f64;
         let mut next_pen_y: f64;
         let mut image_on_line: usize = 0;
         let mut scaled_rects = vec![];
-        for rect in image_rects.into_iter() {
+        for rect in image_rects.iter() {
             let w = rect.w as f64 * using_scale;
             let h = rect.h as f64 * using_scale;
             next_pen_x = pen_x + w;
@@ -197,12 +235,14 @@ impl ImageGridPen for HorizontalGridPen {
             if image_on_line >= self.line_length {
                 image_on_line = 0;
                 pen_x = 0.0;
-                pen_y = next_pen_y;
+                pen_y = next_pen_y + spacing;
             } else {
-                pen_x = next_pen_x;
+                pen_x = next_pen_x + spacing;
             }
         }
 
+        self.total_lines_size_pixels = align_lines(
+            &mut scaled_rects, self.line_length, self.spacing_pixels, self.line_alignment, true);
         scaled_rects
     }
 }
@@ -214,6 +254,9 @@ pub(crate) struct VerticalGridPen {
     lines_at_full_size: usize,
     longest_line_length_pixels: u32,
     line_size_pixels: u32,
+    total_lines_size_pixels: u32,
+    spacing_pixels: u32,
+    line_alignment: LineAlignment,
 }
 
 impl VerticalGridPen {
@@ -222,6 +265,8 @@ impl VerticalGridPen {
         line_count: usize,
         lines_at_full_size: usize,
         line_size_pixels: u32,
+        spacing_pixels: u32,
+        line_alignment: LineAlignment,
     ) -> Self {
         Self {
             line_length,
@@ -229,6 +274,9 @@ impl VerticalGridPen {
             lines_at_full_size,
             longest_line_length_pixels: 0,
             line_size_pixels,
+            total_lines_size_pixels: total_line_size(line_size_pixels, line_count, spacing_pixels),
+            spacing_pixels,
+            line_alignment,
         }
     }
 }
@@ -250,23 +298,25 @@ impl ImageGridPen for VerticalGridPen {
     }
 
     fn get_output_dimensions(&self) -> ImageSize {
-        ImageSize::new(
-            self.line_size_pixels * self.line_count as u32,
-            self.longest_line_length_pixels,
-        )
+        ImageSize::new(self.total_lines_size_pixels, self.longest_line_length_pixels)
     }
 
-    fn generate_output_rects(&mut self, images: &[DynamicImage]) -> Vec<ImageRect> {
+    fn generate_output_rects(&mut self, image_sizes: &[ImageSize]) -> Vec<ImageRect> {
         let mut image_rects: Vec<ImageRect> = vec![];
         let mut pen_x: u32 = 0;
         let mut pen_y: u32 = 0;
         let mut grid_x: usize = 0;
         let mut grid_y: usize = 0;
 
-        for image in images {
+        for image in image_sizes {
+            // Leave a gap before each image except the first in a line
+            if grid_y > 0 {
+                pen_y += self.spacing_pixels;
+            }
+
             // Get sizing for this image
-            let scaling_factor = (self.line_size_pixels as f64) / (image.width() as f64);
-            let scaled_height = ((image.height() as f64) * scaling_factor) as u32;
+            let scaling_factor = (self.line_size_pixels as f64) / (image.w as f64);
+            let scaled_height = ((image.h as f64) * scaling_factor) as u32;
             image_rects.push(ImageRect {
                 x: pen_x,
                 y: pen_y,
@@ -276,23 +326,20 @@ impl ImageGridPen for VerticalGridPen {
 
             // Advance pen and grid positions
             pen_y += scaled_height;
+            if self.longest_line_length_pixels < pen_y {
+                self.longest_line_length_pixels = pen_y;
+            }
             grid_y += 1;
             if (grid_x <= self.lines_at_full_size) && (grid_y >= self.line_length) {
-                if self.longest_line_length_pixels < pen_y {
-                    self.longest_line_length_pixels = pen_y;
-                }
                 pen_y = 0;
-                pen_x += self.line_size_pixels;
+                pen_x += self.line_size_pixels + self.spacing_pixels;
                 grid_y = 0;
                 grid_x += 1;
             }
         }
 
-        // Check if the pen overshot the known boundary before filling a grid column
-        if self.longest_line_length_pixels < pen_y {
-            self.longest_line_length_pixels = pen_y;
-        }
-
+        self.total_lines_size_pixels = align_lines(
+            &mut image_rects, self.line_length, self.spacing_pixels, self.line_alignment, false);
         image_rects
     }
 
@@ -310,7 +357,7 @@ impl ImageGridPen for VerticalGridPen {
         };
 
         // Width is cross axis
-        let total_width = (self.line_count as u32) * self.line_size_pixels;
+        let total_width = self.get_output_dimensions().w;
         let allowed_width = if width_limit == 0 {
             total_width
         } else {
@@ -322,23 +369,52 @@ impl ImageGridPen for VerticalGridPen {
             return image_rects;
         }
 
+        // Scale according to the greatest necessary reduction, where the gaps between images keep
+        // their size and only the images are scaled
+        let height_scale = max_line_scale(
+            &image_rects, self.line_length, allowed_height, self.spacing_pixels, |rect| rect.h);
+        let width_scale = available_scale(
+            allowed_width,
+            image_rects.chunks(self.line_length.max(1)).map(|line| line[0].w).sum(),
+            self.line_count,
+            self.spacing_pixels,
+        );
+        let gaps = ImageSize::new(
+            self.spacing_pixels * (self.line_count.max(1) as u32 - 1),
+            self.spacing_pixels * (self.line_length.min(image_rects.len()).max(1) as u32 - 1),
+        );
+        let allowed = ImageSize::new(allowed_width, allowed_height);
+        let mut using_scale: f64 = f64::min(width_scale, height_scale);
+
+        // Aligning can make a filled last line thicker than allowed for, so check the output
+        // against the limits again, scaling down further until it fits
+        loop {
+            let scaled_rects = self.place_scaled_rects(&image_rects, using_scale);
+            match reduced_scale(using_scale, self.get_output_dimensions(), allowed, gaps) {
+                Some(scale) => using_scale = scale,
+                None => return scaled_rects,
+            }
+        }
+    }
+}
+
+impl VerticalGridPen {
+
+    /// Place the images at the given scale, keeping the gaps between them, then align the lines
+    fn place_scaled_rects(&mut self, image_rects: &[ImageRect], using_scale: f64) -> Vec<ImageRect> {
         // Reset the longest known line sizes; they will be updated
         self.longest_line_length_pixels = 0;
         self.line_size_pixels = 0;
 
-        // Scale according to the greatest necessary reduction
-        let width_scale = allowed_width as f64 / total_width as f64;
-        let height_scale = allowed_height as f64 / total_height as f64;
-        let using_scale: f64 = f64::min(width_scale, height_scale);
-
         // For each image, downscale its position and size
+        let spacing = self.spacing_pixels as f64;
         let mut pen_y: f64 = 0.0;
         let mut pen_x: f64 = 0.0;
         let mut next_pen_y: f64;
         let mut next_pen_x: f64;
         let mut image_on_line: usize = 0;
         let mut scaled_rects = vec![];
-        for rect in image_rects.into_iter() {
+        for rect in image_rects.iter() {
             let h = rect.h as f64 * using_scale;
             let w = rect.w as f64 * using_scale;
             next_pen_y = pen_y + h;
@@ -361,13 +437,527 @@ impl ImageGridPen for VerticalGridPen {
             image_on_line += 1;
             if image_on_line >= self.line_length {
                 image_on_line = 0;
-                pen_x = next_pen_x;
+                pen_x = next_pen_x + spacing;
                 pen_y = 0.0;
             } else {
-                pen_y = next_pen_y;
+                pen_y = next_pen_y + spacing;
+            }
+        }
+
+        self.total_lines_size_pixels = align_lines(
+            &mut scaled_rects, self.line_length, self.spacing_pixels, self.line_alignment, false);
+        scaled_rects
+    }
+}
+
+/// Total size of a number of lines of the same size, including the gaps between them
+fn total_line_size(line_size_pixels: u32, line_count: usize, spacing_pixels: u32) -> u32 {
+    let gap_count = line_count.saturating_sub(1) as u32;
+    line_size_pixels * line_count as u32 + spacing_pixels * gap_count
+}
+
+/// Find the scale that brings some content down to the allowed size, given that the gaps between
+/// each of a number of items are not scaled
+fn available_scale(allowed: u32, content: u32, item_count: usize, spacing_pixels: u32) -> f64 {
+    let gaps = (spacing_pixels as f64) * (item_count.saturating_sub(1) as f64);
+    if content == 0 {
+        return 1.0;
+    }
+    f64::max(0.0, (allowed as f64 - gaps) / content as f64)
+}
+
+/// Check an aligned output against the allowed size, and if it is too large, find a smaller scale
+/// for the images that brings it within the allowed size, given that the gaps keep their size.
+/// Filling a short line makes it thicker than it was when the scale was chosen, so the first
+/// scale does not always fit.
+fn reduced_scale(scale: f64, output: ImageSize, allowed: ImageSize, gaps: ImageSize) -> Option<f64> {
+    let axis_ratio = |output: u32, allowed: u32, gaps: u32| match output > allowed {
+        true => (allowed as f64 - gaps as f64 - 0.5) / (output.saturating_sub(gaps).max(1) as f64),
+        false => 1.0,
+    };
+    let ratio = f64::min(
+        axis_ratio(output.w, allowed.w, gaps.w),
+        axis_ratio(output.h, allowed.h, gaps.h),
+    );
+    match ratio < 1.0 && scale > 0.0 {
+        true => Some(f64::max(0.0, scale * ratio)),
+        false => None,
+    }
+}
+
+/// Find the scale that brings every line of images down to the allowed length, with the lines
+/// being consecutive runs of the given length, and the size of each image along the line given
+/// by the supplied function
+fn max_line_scale(
+    image_rects: &[ImageRect],
+    line_length: usize,
+    allowed: u32,
+    spacing_pixels: u32,
+    size_along_line: fn(&ImageRect) -> u32,
+) -> f64 {
+    let mut scale: f64 = 1.0;
+    for line in image_rects.chunks(line_length.max(1)) {
+        let content: u32 = line.iter().map(size_along_line).sum();
+        scale = f64::min(scale, available_scale(allowed, content, line.len(), spacing_pixels));
+    }
+    scale
+}
+
+/// Position the images within each line of a grid, where lines are consecutive runs of the given
+/// length that start at zero along the main axis. Lines shorter than the longest are moved or
+/// scaled according to the alignment. Returns the total size of all lines across the main axis,
+/// including the gaps between them.
+fn align_lines(
+    image_rects: &mut [ImageRect],
+    line_length: usize,
+    spacing_pixels: u32,
+    line_alignment: LineAlignment,
+    horizontal: bool,
+) -> u32 {
+    if image_rects.is_empty() || line_length == 0 {
+        return 0;
+    }
+
+    // Work as though lines are horizontal, swapping axes for vertical lines
+    if !horizontal {
+        image_rects.iter_mut().for_each(transpose_rect);
+    }
+
+    let line_end = |line: &[ImageRect]| line.last().map_or(0, |rect| rect.x + rect.w);
+    let longest = image_rects.chunks(line_length).map(line_end).max().unwrap_or(0);
+    let line_count = image_rects.len().div_ceil(line_length);
+    for (index, line) in image_rects.chunks_mut(line_length).enumerate() {
+        let empty_space = longest - line_end(line);
+        if empty_space == 0 {
+            continue;
+        }
+        match line_alignment {
+            LineAlignment::Start => {}
+            LineAlignment::Centre => line.iter_mut().for_each(|rect| rect.x += empty_space / 2),
+            LineAlignment::End => line.iter_mut().for_each(|rect| rect.x += empty_space),
+            LineAlignment::Spread if line.len() == 1 => line[0].x += empty_space / 2,
+            LineAlignment::Spread => {
+                let gap_count = (line.len() - 1) as u32;
+                for (i, rect) in line.iter_mut().enumerate() {
+                    rect.x += empty_space * (i as u32) / gap_count;
+                }
+            }
+            LineAlignment::Fill if index + 1 == line_count => {
+                fill_line(line, longest, spacing_pixels);
+            }
+            LineAlignment::Fill => {}
+        }
+    }
+
+    let total_size = image_rects.iter().map(|rect| rect.y + rect.h).max().unwrap_or(0);
+    if !horizontal {
+        image_rects.iter_mut().for_each(transpose_rect);
+    }
+    total_size
+}
+
+/// Scale up a horizontal line of images, keeping the gaps between them, so that it has the given
+/// length. The line keeps its position across the main axis but becomes thicker.
+fn fill_line(line: &mut [ImageRect], length: u32, spacing_pixels: u32) {
+    let gaps = spacing_pixels * (line.len() as u32 - 1);
+    let content: u32 = line.iter().map(|rect| rect.w).sum();
+    if content == 0 || length <= gaps {
+        return;
+    }
+    let scale = ((length - gaps) as f64) / (content as f64);
+    let spacing = spacing_pixels as f64;
+    let mut pen_x: f64 = 0.0;
+    for rect in line.iter_mut() {
+        let next_pen_x = pen_x + rect.w as f64 * scale;
+        rect.x = pen_x.round() as u32;
+        rect.w = (next_pen_x.round() as u32) - rect.x;
+        rect.h = (rect.h as f64 * scale).round() as u32;
+        pen_x = next_pen_x + spacing;
+    }
+}
+
+/// Swap the axes of a rect
+fn transpose_rect(rect: &mut ImageRect) {
+    std::mem::swap(&mut rect.x, &mut rect.y);
+    std::mem::swap(&mut rect.w, &mut rect.h);
+}
+
+/// Type that knows how to place images in rows which are each scaled to exactly the same width.
+/// Row heights will vary, so that every image keeps its aspect ratio and no space is left over.
+pub(crate) struct JustifiedRowPen {
+    target_line_size_pixels: u32,
+    spacing_pixels: u32,
+    aspect_ratios: Vec<f64>,
+    line_breaks: Vec<usize>,
+    output_width: f64,
+    output_height: f64,
+}
+
+impl JustifiedRowPen {
+    pub fn new(target_line_size_pixels: u32, spacing_pixels: u32) -> Self {
+        Self {
+            target_line_size_pixels,
+            spacing_pixels,
+            aspect_ratios: vec![],
+            line_breaks: vec![],
+            output_width: 0.0,
+            output_height: 0.0,
+        }
+    }
+
+    /// Choose where each row begins, so that row heights are as close as possible to the target
+    /// when each row is scaled to the given width. Returns the start index of each row.
+    fn find_line_breaks(&self, width: f64, target_height: f64) -> Vec<usize> {
+        let count = self.aspect_ratios.len();
+        let mut aspect_sums = vec![0.0; count + 1];
+        for (i, aspect) in self.aspect_ratios.iter().enumerate() {
+            aspect_sums[i + 1] = aspect_sums[i] + aspect;
+        }
+
+        // Minimise total squared deviation from the target height, over all ways to split images
+        // into rows, where best_costs[j] is the cost of laying out the first j images
+        let mut best_costs = vec![f64::MAX; count + 1];
+        let mut best_starts = vec![0; count + 1];
+        best_costs[0] = 0.0;
+        for end in 1..=count {
+            for start in 0..end {
+                let gaps = (self.spacing_pixels as f64) * ((end - start - 1) as f64);
+                let line_height = (width - gaps) / (aspect_sums[end] - aspect_sums[start]);
+                if line_height <= 0.0 {
+                    continue;
+                }
+                let cost = best_costs[start] + (line_height - target_height).powi(2);
+                if cost < best_costs[end] {
+                    best_costs[end] = cost;
+                    best_starts[end] = start;
+                }
+            }
+        }
+
+        // Walk back through the best splits to recover where each row starts
+        let mut line_breaks = vec![];
+        let mut end = count;
+        while end > 0 {
+            end = best_starts[end];
+            line_breaks.push(end);
+        }
+        line_breaks.reverse();
+        line_breaks
+    }
+
+    /// Get the aspect ratios of the images in each row, using the current line breaks
+    fn lines(&self) -> Vec<&[f64]> {
+        let mut lines = Vec::with_capacity(self.line_breaks.len());
+        for (line, &start) in self.line_breaks.iter().enumerate() {
+            let end = match self.line_breaks.get(line + 1) {
+                Some(&next_start) => next_start,
+                None => self.aspect_ratios.len(),
+            };
+            lines.push(&self.aspect_ratios[start..end]);
+        }
+        lines
+    }
+
+    /// Height of a row of images, with the given aspect ratios, when scaled to the given width
+    fn line_height(&self, line: &[f64], width: f64) -> f64 {
+        let gaps = (self.spacing_pixels as f64) * (line.len().saturating_sub(1) as f64);
+        let aspect_sum: f64 = line.iter().sum();
+        f64::max(0.0, (width - gaps) / aspect_sum)
+    }
+
+    /// Generate rects for every image, using the current line breaks and the given output width.
+    /// Positions are rounded cumulatively so that rows and columns meet without gaps.
+    fn layout_rects(&self, width: f64) -> Vec<ImageRect> {
+        let spacing = self.spacing_pixels as f64;
+        let mut image_rects = Vec::with_capacity(self.aspect_ratios.len());
+        let mut pen_y: f64 = 0.0;
+        for line in self.lines() {
+            let line_height = self.line_height(line, width);
+            let next_pen_y = pen_y + line_height;
+
+            let mut pen_x: f64 = 0.0;
+            for aspect in line.iter() {
+                let next_pen_x = pen_x + line_height * aspect;
+                image_rects.push(ImageRect {
+                    x: pen_x.round() as u32,
+                    y: pen_y.round() as u32,
+                    w: (next_pen_x.round() as u32) - (pen_x.round() as u32),
+                    h: (next_pen_y.round() as u32) - (pen_y.round() as u32),
+                });
+                pen_x = next_pen_x + spacing;
             }
+            pen_y = next_pen_y + spacing;
+        }
+        image_rects
+    }
+
+    /// Total height of all rows when scaled to the given width, including the gaps between them
+    fn total_height(&self, width: f64) -> f64 {
+        let lines = self.lines();
+        let gaps = (self.spacing_pixels as f64) * (lines.len().saturating_sub(1) as f64);
+        let line_heights: f64 = lines.into_iter()
+            .map(|line| self.line_height(line, width))
+            .sum();
+        line_heights + gaps
+    }
+}
+
+impl ImageGridPen for JustifiedRowPen {
+    #[cfg(test)]
+    fn get_images_per_line(&self) -> usize {
+        self.lines().into_iter().map(|line| line.len()).max().unwrap_or(0)
+    }
+
+    #[cfg(test)]
+    fn get_line_count(&self) -> usize {
+        self.line_breaks.len()
+    }
+
+    #[cfg(test)]
+    fn get_lines_at_full_size(&self) -> usize {
+        self.line_breaks.len()
+    }
+
+    fn get_output_dimensions(&self) -> ImageSize {
+        ImageSize::new(
+            self.output_width.round() as u32,
+            self.output_height.round() as u32,
+        )
+    }
+
+    fn generate_output_rects(&mut self, image_sizes: &[ImageSize]) -> Vec<ImageRect> {
+        if image_sizes.is_empty() {
+            return vec![];
+        }
+        self.aspect_ratios = image_sizes
+            .iter()
+            .map(|image| (image.w as f64) / (image.h as f64))
+            .collect();
+
+        // Aim for roughly as many rows as there are target-height squares across each row, so
+        // that the output is not far from square, and widen rows to fit the gaps between images
+        let target_height = self.target_line_size_pixels as f64;
+        let total_length: f64 = self.aspect_ratios.iter().sum::<f64>() * target_height;
+        let line_count = (total_length / target_height).sqrt().round().max(1.0);
+        let gaps_per_line = f64::max(0.0, image_sizes.len() as f64 / line_count - 1.0);
+        let width = (total_length / line_count + self.spacing_pixels as f64 * gaps_per_line)
+            .round()
+            .max(1.0);
+
+        self.line_breaks = self.find_line_breaks(width, target_height);
+        self.output_width = width;
+        self.output_height = self.total_height(width);
+        self.layout_rects(width)
+    }
+
+    fn scale_image_rects(
+        &mut self,
+        image_rects: Vec<ImageRect>,
+        width_limit: u32,
+        height_limit: u32,
+    ) -> Vec<ImageRect> {
+        let mut width = self.output_width;
+        if width_limit > 0 {
+            width = f64::min(width, width_limit as f64);
+        }
+
+        // Row heights are proportional to their widths less the gaps, so the total height changes
+        // by a fixed amount per unit of width
+        let height = self.total_height(width);
+        if height_limit > 0 && height > height_limit as f64 {
+            let height_per_width: f64 = self.lines()
+                .into_iter()
+                .map(|line| 1.0 / line.iter().sum::<f64>())
+                .sum();
+            width -= (height - height_limit as f64) / height_per_width;
+        }
+
+        // No scaling needed if limits exceed current size
+        if width >= self.output_width {
+            return image_rects;
+        }
+
+        // Rows keep their images, so lay out again at the reduced width
+        self.output_width = width;
+        self.output_height = self.total_height(width);
+        self.layout_rects(width)
+    }
+}
+
+/// Type that knows how to pack images into columns of equal width, placing each image at the
+/// bottom of whichever column is shortest at the time
+pub(crate) struct MasonryPen {
+    column_count: Option<usize>,
+    column_width_pixels: u32,
+    spacing_pixels: u32,
+    image_columns: Vec<usize>,
+    output_size: ImageSize,
+}
+
+impl MasonryPen {
+    pub fn new(column_count: Option<usize>, column_width_pixels: u32, spacing_pixels: u32) -> Self {
+        Self {
+            column_count,
+            column_width_pixels,
+            spacing_pixels,
+            image_columns: vec![],
+            output_size: ImageSize::new(0, 0),
+        }
+    }
+
+    /// Guess a number of columns that gives a roughly square output
+    fn guess_column_count(scaled_heights: &[u32], column_width_pixels: u32) -> usize {
+        let total_height: f64 = scaled_heights.iter().map(|&h| h as f64).sum();
+        let columns = (total_height / column_width_pixels as f64).sqrt().round() as usize;
+        columns.clamp(1, scaled_heights.len().max(1))
+    }
+
+    /// Find the shortest column, preferring the leftmost if there is a tie
+    fn shortest_column<T: PartialOrd + Copy>(column_heights: &[T]) -> usize {
+        let mut shortest = 0;
+        for (column, &height) in column_heights.iter().enumerate() {
+            if height < column_heights[shortest] {
+                shortest = column;
+            }
+        }
+        shortest
+    }
+}
+
+impl ImageGridPen for MasonryPen {
+    #[cfg(test)]
+    fn get_images_per_line(&self) -> usize {
+        let column_count = self.column_count.unwrap_or(0);
+        (0..column_count)
+            .map(|column| self.image_columns.iter().filter(|&&c| c == column).count())
+            .max()
+            .unwrap_or(0)
+    }
+
+    #[cfg(test)]
+    fn get_line_count(&self) -> usize {
+        self.column_count.unwrap_or(0)
+    }
+
+    #[cfg(test)]
+    fn get_lines_at_full_size(&self) -> usize {
+        self.column_count.unwrap_or(0)
+    }
+
+    fn get_output_dimensions(&self) -> ImageSize {
+        ImageSize::new(self.output_size.w, self.output_size.h)
+    }
+
+    fn generate_output_rects(&mut self, image_sizes: &[ImageSize]) -> Vec<ImageRect> {
+        // Every image keeps at least one row, so a column holding a very short image is never
+        // taken to be empty and the next image placed over it
+        let scaled_heights: Vec<u32> = image_sizes
+            .iter()
+            .map(|image| {
+                let scaling_factor = (self.column_width_pixels as f64) / (image.w as f64);
+                (((image.h as f64) * scaling_factor) as u32).max(1)
+            })
+            .collect();
+        let column_count = match self.column_count {
+            Some(count) => count,
+            None => Self::guess_column_count(&scaled_heights, self.column_width_pixels),
+        };
+        self.column_count = Some(column_count);
+
+        let mut image_rects: Vec<ImageRect> = vec![];
+        let mut column_heights: Vec<u32> = vec![0; column_count];
+        self.image_columns.clear();
+        for scaled_height in scaled_heights.into_iter() {
+            let column = Self::shortest_column(&column_heights);
+            let y = match column_heights[column] {
+                0 => 0,
+                height => height + self.spacing_pixels,
+            };
+            image_rects.push(ImageRect {
+                x: column as u32 * (self.column_width_pixels + self.spacing_pixels),
+                y,
+                w: self.column_width_pixels,
+                h: scaled_height,
+            });
+            column_heights[column] = y + scaled_height;
+            self.image_columns.push(column);
+        }
+
+        self.output_size = ImageSize::new(
+            total_line_size(self.column_width_pixels, column_count, self.spacing_pixels),
+            column_heights.into_iter().max().unwrap_or(0),
+        );
+        image_rects
+    }
+
+    fn scale_image_rects(
+        &mut self,
+        image_rects: Vec<ImageRect>,
+        width_limit: u32,
+        height_limit: u32,
+    ) -> Vec<ImageRect> {
+        let total_width = self.output_size.w;
+        let allowed_width = if width_limit == 0 {
+            total_width
+        } else {
+            min(total_width, width_limit)
+        };
+        let total_height = self.output_size.h;
+        let allowed_height = if height_limit == 0 {
+            total_height
+        } else {
+            min(total_height, height_limit)
+        };
+
+        // No scaling needed if limits exceed current size
+        if total_width <= allowed_width && total_height <= allowed_height {
+            return image_rects;
+        }
+
+        // Scale according to the greatest necessary reduction, where the gaps between images keep
+        // their size and only the images are scaled
+        let column_count = self.column_count.unwrap_or(0);
+        let mut using_scale = available_scale(
+            allowed_width,
+            column_count as u32 * self.column_width_pixels,
+            column_count,
+            self.spacing_pixels,
+        );
+        for column in 0..column_count {
+            let column_rects = image_rects.iter()
+                .zip(self.image_columns.iter())
+                .filter(|(_, &c)| c == column);
+            let content: u32 = column_rects.clone().map(|(rect, _)| rect.h).sum();
+            let scale = available_scale(
+                allowed_height, content, column_rects.count(), self.spacing_pixels);
+            using_scale = f64::min(using_scale, scale);
+        }
+
+        // Keep every image in the same column, downscaling the column widths and the positions
+        // down each column
+        let spacing = self.spacing_pixels as f64;
+        let column_width = self.column_width_pixels as f64 * using_scale;
+        let mut pen_y: Vec<f64> = vec![0.0; column_count];
+        let mut scaled_rects = vec![];
+        for (rect, &column) in image_rects.into_iter().zip(self.image_columns.iter()) {
+            let pen_x = column as f64 * (column_width + spacing);
+            let next_pen_x = pen_x + column_width;
+            let next_pen_y = pen_y[column] + rect.h as f64 * using_scale;
+            scaled_rects.push(ImageRect {
+                x: pen_x.round() as u32,
+                y: pen_y[column].round() as u32,
+                w: (next_pen_x.round() as u32) - (pen_x.round() as u32),
+                h: (next_pen_y.round() as u32) - (pen_y[column].round() as u32),
+            });
+            pen_y[column] = next_pen_y + spacing;
         }
 
+        let gaps = spacing * (column_count.saturating_sub(1) as f64);
+        self.column_width_pixels = column_width.round() as u32;
+        self.output_size = ImageSize::new(
+            (column_count as f64 * column_width + gaps).round() as u32,
+            pen_y.into_iter().fold(0.0, f64::max).round().max(spacing) as u32 - spacing as u32,
+        );
         scaled_rects
     }
 }
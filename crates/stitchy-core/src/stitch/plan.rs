@@ -0,0 +1,69 @@
+use crate::stitch::pen::{ImageRect, ImageSize};
+
+/// Where one source image is placed in the output, in pixels from the top-left corner
+#[derive(PartialEq, Eq, Debug, Copy, Clone)]
+pub struct Placement {
+    pub source_index: usize,
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Placement {
+    /// Check whether a point in the output falls within this placement
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        x >= self.x && x - self.x < self.width && y >= self.y && y - self.y < self.height
+    }
+}
+
+/// The layout of a stitch, giving the size of the output and where each source image is placed.
+/// Placements are listed in the same order as the source images, and include any outer margin.
+///
+/// Get a plan from [StitchBuilder::plan](crate::StitchBuilder::plan) to preview a layout without
+/// rendering it.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub struct StitchPlan {
+    pub width: u32,
+    pub height: u32,
+    pub placements: Vec<Placement>,
+}
+
+impl StitchPlan {
+    pub(crate) fn new(inner_size: ImageSize, image_rects: &[ImageRect], margin: u32) -> Self {
+        let margins = margin.saturating_mul(2);
+        let placements = image_rects
+            .iter()
+            .enumerate()
+            .map(|(source_index, rect)| Placement {
+                source_index,
+                x: rect.x + margin,
+                y: rect.y + margin,
+                width: rect.w,
+                height: rect.h,
+            })
+            .collect();
+        Self {
+            width: inner_size.w.saturating_add(margins),
+            height: inner_size.h.saturating_add(margins),
+            placements,
+        }
+    }
+
+    /// Check whether the placements fill the whole output, leaving no background showing
+    pub fn covers_output(&self) -> bool {
+        let covered: u64 = self.placements
+            .iter()
+            .map(|placement| placement.width as u64 * placement.height as u64)
+            .sum();
+        covered >= self.width as u64 * self.height as u64
+    }
+
+    /// Find the index of the source image shown at a point in the output, if any
+    pub fn source_at(&self, x: u32, y: u32) -> Option<usize> {
+        self.placements
+            .iter()
+            .find(|placement| placement.contains(x, y))
+            .map(|placement| placement.source_index)
+    }
+}
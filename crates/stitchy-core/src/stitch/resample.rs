@@ -0,0 +1,98 @@
+use crate::Stitch;
+use image::{imageops::FilterType, DynamicImage, Rgba32FImage};
+
+/// Resize an image in linear light with premultiplied alpha, returning a floating point image
+/// with straight alpha, so that no precision is lost before it is painted. Integer images come
+/// back sRGB-encoded, while floating point images already hold linear light, so they are neither
+/// converted nor limited to the range 0 to 1, keeping any values brighter than white.
+///
+/// Filtering values that are still gamma-encoded darkens fine detail and high-contrast edges,
+/// and filtering colours without weighting them by alpha lets the colour of fully transparent
+/// pixels bleed into the edges of visible ones. This avoids both, at the cost of working at a
+/// higher precision. Any reduction before the final filter is done in linear light too.
+pub(crate) fn resize_linear_premultiplied(
+    img: &DynamicImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+) -> DynamicImage {
+    let srgb_encoded = !matches!(img, DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_));
+    let mut linear = img.to_rgba32f();
+    for pixel in linear.pixels_mut() {
+        let alpha = pixel.0[3];
+        for channel in pixel.0[0..3].iter_mut() {
+            if srgb_encoded {
+                *channel = srgb_to_linear(*channel);
+            }
+            *channel *= alpha;
+        }
+    }
+
+    // The image crate limits floating point channels to 1.0 when resizing, so bring brighter
+    // values into that range first, and restore them afterwards
+    let peak = linear
+        .pixels()
+        .flat_map(|pixel| [pixel.0[0], pixel.0[1], pixel.0[2]])
+        .fold(1.0, f32::max);
+    if peak > 1.0 {
+        scale_colour(&mut linear, 1.0 / peak);
+    }
+
+    let linear = DynamicImage::ImageRgba32F(linear);
+    let prescaled = match filter {
+        FilterType::Nearest => None,
+        _ => Stitch::prescale(&linear, width, height),
+    };
+    let source = prescaled.as_ref().unwrap_or(&linear);
+    let mut resized = match source.resize_exact(width, height, filter) {
+        DynamicImage::ImageRgba32F(resized) => resized,
+        other => other.to_rgba32f(),
+    };
+
+    if peak > 1.0 {
+        scale_colour(&mut resized, peak);
+    }
+    unpremultiply(&mut resized, srgb_encoded);
+    DynamicImage::ImageRgba32F(resized)
+}
+
+/// Multiply the colour channels of every pixel, leaving alpha unchanged
+fn scale_colour(image: &mut Rgba32FImage, factor: f32) {
+    for pixel in image.pixels_mut() {
+        pixel.0[0..3].iter_mut().for_each(|channel| *channel *= factor);
+    }
+}
+
+/// Convert back from premultiplied linear light to straight alpha, and to sRGB if the source was
+/// encoded that way
+fn unpremultiply(image: &mut Rgba32FImage, srgb_encoded: bool) {
+    for pixel in image.pixels_mut() {
+        let alpha = pixel.0[3].clamp(0.0, 1.0);
+        for channel in pixel.0[0..3].iter_mut() {
+            *channel = match (alpha > 0.0, srgb_encoded) {
+                (true, true) => linear_to_srgb((*channel / alpha).clamp(0.0, 1.0)),
+                (true, false) => *channel / alpha,
+                (false, _) => 0.0,
+            };
+        }
+        pixel.0[3] = alpha;
+    }
+}
+
+/// Convert an sRGB-encoded value in the range 0 to 1 into linear light
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Convert a linear light value in the range 0 to 1 into its sRGB encoding
+fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
@@ -0,0 +1,125 @@
+use crate::{
+    parallel,
+    stitch::{
+        plan::{Placement, StitchPlan},
+        RenderOptions,
+    },
+    Crossfade, FileProperties, ImageFiles, PixelFormat, Stitch,
+};
+use image::{Delay, Frame, RgbaImage};
+
+impl Stitch {
+
+    /// Work out a slideshow's canvas, which is large enough for the widest and the tallest image
+    /// but shrunk to fit within any limits, and place each image as large as it fits on the
+    /// canvas without changing its aspect ratio, centred with any space left at the sides
+    pub(crate) fn plan_slideshow(
+        image_sizes: &[(u32, u32)],
+        width_limit: u32,
+        height_limit: u32,
+    ) -> StitchPlan {
+        let width = image_sizes.iter().map(|&(width, _)| width).max().unwrap_or(1).max(1);
+        let height = image_sizes.iter().map(|&(_, height)| height).max().unwrap_or(1).max(1);
+        let mut scale: f64 = 1.0;
+        if width_limit > 0 {
+            scale = scale.min(width_limit as f64 / width as f64);
+        }
+        if height_limit > 0 {
+            scale = scale.min(height_limit as f64 / height as f64);
+        }
+        let width = ((width as f64 * scale).round() as u32).max(1);
+        let height = ((height as f64 * scale).round() as u32).max(1);
+
+        let placements = image_sizes
+            .iter()
+            .enumerate()
+            .map(|(source_index, &(image_width, image_height))| {
+                let fit = f64::min(
+                    width as f64 / image_width.max(1) as f64,
+                    height as f64 / image_height.max(1) as f64,
+                );
+                let fitted = |size: u32, limit: u32| {
+                    ((size as f64 * fit).round() as u32).clamp(1, limit)
+                };
+                let placement_width = fitted(image_width, width);
+                let placement_height = fitted(image_height, height);
+                Placement {
+                    source_index,
+                    x: (width - placement_width) / 2,
+                    y: (height - placement_height) / 2,
+                    width: placement_width,
+                    height: placement_height,
+                }
+            })
+            .collect();
+        StitchPlan { width, height, placements }
+    }
+
+    /// Render each file as a slide on a canvas of its own, in the order given, and return them as
+    /// frames shown for the slide delay each. Crossfade frames are added between neighbouring
+    /// slides if requested. Frames are always 8-bit RGBA.
+    pub(crate) fn stitch_slideshow<P: FileProperties + Send>(
+        plan: &StitchPlan,
+        files: ImageFiles<P>,
+        render: RenderOptions,
+        slide_delay: u32,
+        crossfade: Option<Crossfade>,
+        print_info: bool,
+    ) -> Result<Vec<Frame>, String> {
+        let render = RenderOptions { pixel_format: PixelFormat::Rgba8, ..render };
+        let background = Self::new_canvas(plan, &render, &[])?;
+        let (files, orientation_mode) = files.into_files();
+        let work: Vec<(P, &Placement)> = files.into_iter()
+            .zip(plan.placements.iter())
+            .collect();
+        let mut slides: Vec<RgbaImage> = Vec::with_capacity(work.len());
+        let render = &render;
+        parallel::map_in_order(
+            work,
+            render.threads,
+            |(file, placement)| {
+                let img = ImageFiles::load(
+                    file, print_info, orientation_mode, render.working_space)?;
+                Ok(Self::resize(&img, placement, render))
+            },
+            |i, scaled_image| {
+                let mut slide = background.clone();
+                Self::paint(&mut slide, scaled_image, &plan.placements[i], render)?;
+                slides.push(slide.into_rgba8());
+                Ok(())
+            },
+        )?;
+
+        let mut frames = Vec::with_capacity(slides.len());
+        let mut slides = slides.into_iter().peekable();
+        while let Some(slide) = slides.next() {
+            let fades = match (crossfade, slides.peek()) {
+                (Some(crossfade), Some(next_slide)) => (1..=crossfade.frames)
+                    .map(|step| {
+                        let amount = step as f32 / (crossfade.frames + 1) as f32;
+                        let delay = Delay::from_numer_denom_ms(crossfade.frame_delay, 1);
+                        Frame::from_parts(Self::blend(&slide, next_slide, amount), 0, 0, delay)
+                    })
+                    .collect(),
+                _ => vec![],
+            };
+            let delay = Delay::from_numer_denom_ms(slide_delay, 1);
+            frames.push(Frame::from_parts(slide, 0, 0, delay));
+            frames.extend(fades);
+        }
+
+        Ok(frames)
+    }
+
+    /// Mix two images of the same size, taking the given amount of the second
+    fn blend(from: &RgbaImage, to: &RgbaImage, amount: f32) -> RgbaImage {
+        let mut blended = from.clone();
+        for (pixel, to_pixel) in blended.pixels_mut().zip(to.pixels()) {
+            for (channel, to_channel) in pixel.0.iter_mut().zip(to_pixel.0) {
+                let mixed = *channel as f32 + (to_channel as f32 - *channel as f32) * amount;
+                *channel = mixed.round() as u8;
+            }
+        }
+        blended
+    }
+}
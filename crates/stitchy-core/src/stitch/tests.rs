@@ -1,15 +1,58 @@
 use crate::enums::{OrderBy, TakeFrom};
 use crate::files::image_types::ImageFiles;
-use crate::image::FilterType;
-use crate::stitch::Stitch;
-use crate::AlignmentMode;
-use crate::FilePathWithMetadata;
+use crate::image::{FilterType, Rgba};
+use image::GenericImageView;
+use crate::stitch::{LayoutOptions, RenderOptions, Stitch};
+use crate::{AlignmentMode, GridSpec, LineAlignment, MajorAxis, Placement, ResampleMode};
+use crate::{ImageFilesBuilder, OrientationMode, PixelFormat, ResizeFilter, UnsharpMask};
+use crate::{AnimationSync, Crossfade, WorkingSpace};
+use image::ColorType;
+use crate::{FileLocation, FilePathWithMetadata, FileProperties, RawBufferLocation};
+#[cfg(unix)]
+use crate::OwnedRawFdLocation;
+use std::time::SystemTime;
 
 fn create_stitch(image_count: usize, alignment: AlignmentMode) -> Stitch {
+    create_grid_stitch(image_count, alignment, GridSpec::default())
+}
+
+fn create_grid_stitch(image_count: usize, alignment: AlignmentMode, grid: GridSpec) -> Stitch {
     let images: Vec<image::DynamicImage> = (0..image_count)
         .map(|_| image::DynamicImage::new_rgba8(1, 1))
         .collect();
-    Stitch::new(images, alignment, 0, 0, FilterType::Lanczos3)
+    let layout = LayoutOptions { alignment, grid, ..LayoutOptions::default() };
+    Stitch::new(images, layout, RenderOptions::default())
+}
+
+fn create_sized_stitch(
+    sizes: &[(u32, u32)],
+    alignment: AlignmentMode,
+    grid: GridSpec,
+    width_limit: u32,
+    height_limit: u32,
+) -> Stitch {
+    let layout = LayoutOptions {
+        alignment,
+        grid,
+        width_limit,
+        height_limit,
+        ..LayoutOptions::default()
+    };
+    create_layout_stitch(sizes, layout)
+}
+
+fn create_layout_stitch(sizes: &[(u32, u32)], layout: LayoutOptions) -> Stitch {
+    let images: Vec<image::DynamicImage> = sizes.iter()
+        .map(|&(w, h)| image::DynamicImage::new_rgba8(w, h))
+        .collect();
+    Stitch::new(images, layout, nearest_render())
+}
+
+fn nearest_render() -> RenderOptions {
+    RenderOptions {
+        resize_filter: FilterType::Nearest,
+        ..RenderOptions::default()
+    }
 }
 
 fn clear_output() -> Result<(), String> {
@@ -85,6 +128,697 @@ fn check_grid_resizing() {
     }
 }
 
+#[test]
+fn check_fixed_grid_resizing() {
+    let columns_only = GridSpec { columns: Some(4), ..GridSpec::default() };
+    let rows_only = GridSpec { rows: Some(2), ..GridSpec::default() };
+    let rows_horizontal = GridSpec {
+        rows: Some(2),
+        major_axis: Some(MajorAxis::Horizontal),
+        ..GridSpec::default()
+    };
+    let both = GridSpec { rows: Some(3), columns: Some(3), ..GridSpec::default() };
+    let cases: [(usize, GridSpec, (usize, usize, usize)); 7] = [
+        (1, columns_only, (4, 1, 0)),
+        (10, columns_only, (4, 3, 2)),
+        (16, columns_only, (4, 4, 4)),
+        (5, rows_only, (2, 3, 2)),
+        (9, rows_only, (2, 5, 4)),
+        (5, rows_horizontal, (3, 2, 1)),
+        (7, both, (3, 3, 2)),
+    ];
+    for (count, grid, expected_dimensions) in cases.into_iter() {
+        let stitch = create_grid_stitch(count, AlignmentMode::Grid, grid);
+        let pen = &stitch.axis_pen;
+        assert_eq!(
+            expected_dimensions,
+            (
+                pen.get_images_per_line(),
+                pen.get_line_count(),
+                pen.get_lines_at_full_size()
+            ),
+            "{} images with {:?}",
+            count,
+            grid
+        );
+    }
+}
+
+#[test]
+fn check_unusable_grid_rejected() {
+    let images = || -> Vec<image::DynamicImage> {
+        (0..7).map(|_| image::DynamicImage::new_rgba8(1, 1)).collect()
+    };
+    let too_small = Stitch::builder()
+        .images(images())
+        .grid(GridSpec { rows: Some(2), columns: Some(3), ..GridSpec::default() })
+        .stitch();
+    assert!(too_small.is_err());
+    let zero_columns = Stitch::builder()
+        .images(images())
+        .grid(GridSpec { columns: Some(0), ..GridSpec::default() })
+        .stitch();
+    assert!(zero_columns.is_err());
+}
+
+#[test]
+pub fn test_fixed_grid_output_dimensions() {
+    // Four 1x1 images into three columns gives two rows, the second one partially filled
+    let images: Vec<image::DynamicImage> = (0..4)
+        .map(|_| image::DynamicImage::new_rgba8(1, 1))
+        .collect();
+    let output = Stitch::builder()
+        .images(images)
+        .grid(GridSpec { columns: Some(3), ..GridSpec::default() })
+        .stitch()
+        .unwrap();
+    assert_eq!(output.width(), 3);
+    assert_eq!(output.height(), 2);
+}
+
+fn assert_rects_fill_output(stitch: &Stitch) {
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    let mut covered_area = 0;
+    for rect in stitch.image_rects.iter() {
+        assert!(rect.x + rect.w <= output_size.w);
+        assert!(rect.y + rect.h <= output_size.h);
+        let line_end = stitch.image_rects.iter()
+            .filter(|other| other.y == rect.y)
+            .map(|other| other.x + other.w)
+            .max()
+            .unwrap();
+        assert_eq!(line_end, output_size.w, "Row at y = {} does not fill the width", rect.y);
+        covered_area += rect.w * rect.h;
+    }
+    assert_eq!(covered_area, output_size.w * output_size.h);
+}
+
+#[test]
+fn check_justified_rows_fill_output() {
+    let sizes = [(300, 200), (100, 200), (400, 300), (200, 400), (500, 250), (640, 480)];
+    let justified = AlignmentMode::Justified;
+
+    let stitch = create_sized_stitch(&sizes, justified, GridSpec::default(), 0, 0);
+    assert!(stitch.axis_pen.get_line_count() > 1);
+    assert_rects_fill_output(&stitch);
+
+    let stitch = create_sized_stitch(&sizes, justified, GridSpec::default(), 250, 0);
+    assert_eq!(stitch.axis_pen.get_output_dimensions().w, 250);
+    assert_rects_fill_output(&stitch);
+
+    let stitch = create_sized_stitch(&sizes, justified, GridSpec::default(), 0, 100);
+    assert!(stitch.axis_pen.get_output_dimensions().h <= 100);
+    assert_rects_fill_output(&stitch);
+}
+
+#[test]
+fn check_masonry_fills_shortest_column() {
+    // Column width will be 100; heights once scaled are 300, 100, 100, 100, 200, 50
+    let sizes = [(100, 300), (200, 200), (100, 100), (300, 300), (100, 200), (200, 100)];
+    let masonry = AlignmentMode::Masonry;
+    let two_columns = GridSpec { columns: Some(2), ..GridSpec::default() };
+
+    let stitch = create_sized_stitch(&sizes, masonry, two_columns, 0, 0);
+    let positions: Vec<(u32, u32)> = stitch.image_rects.iter()
+        .map(|rect| (rect.x, rect.y))
+        .collect();
+    assert_eq!(
+        positions,
+        vec![(0, 0), (100, 0), (100, 100), (100, 200), (0, 300), (100, 300)]
+    );
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    assert_eq!((output_size.w, output_size.h), (200, 500));
+
+    let stitch = create_sized_stitch(&sizes, masonry, two_columns, 0, 250);
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    assert_eq!((output_size.w, output_size.h), (100, 250));
+    for rect in stitch.image_rects.iter() {
+        assert!(rect.x + rect.w <= output_size.w);
+        assert!(rect.y + rect.h <= output_size.h);
+    }
+
+    let stitch = create_sized_stitch(&sizes, masonry, GridSpec::default(), 0, 0);
+    assert_eq!(stitch.axis_pen.get_line_count(), 3);
+
+    // A very short image still takes up a row, so the next image goes below it
+    let sizes = [(100, 300), (1000, 1), (100, 100)];
+    let stitch = create_sized_stitch(&sizes, masonry, two_columns, 0, 0);
+    let positions: Vec<(u32, u32, u32)> = stitch.image_rects.iter()
+        .map(|rect| (rect.x, rect.y, rect.h))
+        .collect();
+    assert_eq!(positions, vec![(0, 0, 300), (100, 0, 1), (100, 1, 100)]);
+}
+
+fn covered_fraction(stitch: &Stitch) -> f64 {
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    let covered_area: u32 = stitch.image_rects.iter().map(|rect| rect.w * rect.h).sum();
+    covered_area as f64 / (output_size.w * output_size.h) as f64
+}
+
+#[test]
+fn check_grid_optimiser_reduces_empty_space() {
+    // Sizes of the tree images in the demo directory, two portrait and two landscape
+    let sizes = [(456, 684), (547, 363), (480, 270), (378, 504)];
+    let stitch = create_sized_stitch(&sizes, AlignmentMode::Grid, GridSpec::default(), 0, 0);
+    assert!(covered_fraction(&stitch) > 0.9);
+
+    // Two same-size portrait images and one smaller square image were previously placed in a 2x2
+    // grid with the portrait images downscaled, but fit better side-by-side at full size
+    let sizes = [(1080, 2280), (1080, 2280), (1080, 1080)];
+    let stitch = create_sized_stitch(&sizes, AlignmentMode::Grid, GridSpec::default(), 0, 0);
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    assert_eq!((output_size.w, output_size.h), (3240, 2280));
+    assert!(covered_fraction(&stitch) > 0.8);
+}
+
+#[test]
+fn check_grid_optimiser_uses_target_aspect_ratio() {
+    let images: Vec<image::DynamicImage> = (0..8)
+        .map(|_| image::DynamicImage::new_rgba8(1, 1))
+        .collect();
+    let layout = LayoutOptions {
+        target_aspect_ratio: Some(2.0),
+        ..LayoutOptions::default()
+    };
+    let stitch = Stitch::new(images, layout, nearest_render());
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    assert_eq!((output_size.w, output_size.h), (4, 2));
+
+    let images: Vec<image::DynamicImage> = (0..8)
+        .map(|_| image::DynamicImage::new_rgba8(1, 1))
+        .collect();
+    let layout = LayoutOptions {
+        target_aspect_ratio: Some(0.5),
+        ..LayoutOptions::default()
+    };
+    let stitch = Stitch::new(images, layout, nearest_render());
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    assert_eq!((output_size.w, output_size.h), (2, 4));
+
+    // The default square target gives way to less empty space, but a tall target is followed
+    let sizes = [(1080, 2280), (1080, 2280), (1080, 2280), (1080, 1080)];
+    let stitch = create_layout_stitch(&sizes, LayoutOptions::default());
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    assert_eq!((output_size.w, output_size.h), (4320, 2280));
+
+    let layout = LayoutOptions {
+        target_aspect_ratio: Some(0.5),
+        ..LayoutOptions::default()
+    };
+    let stitch = create_layout_stitch(&sizes, layout);
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    assert_eq!((output_size.w, output_size.h), (2160, 4560));
+}
+
+#[test]
+fn check_spacing_adds_gaps_between_images() {
+    let squares = [(100, 100), (100, 100), (100, 100)];
+    let layout = LayoutOptions {
+        alignment: AlignmentMode::Horizontal,
+        spacing: 10,
+        ..LayoutOptions::default()
+    };
+    let stitch = create_layout_stitch(&squares, layout);
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    assert_eq!((output_size.w, output_size.h), (320, 100));
+    let positions: Vec<u32> = stitch.image_rects.iter().map(|rect| rect.x).collect();
+    assert_eq!(positions, vec![0, 110, 220]);
+
+    // Gaps keep their size while images are scaled down to fit
+    let layout = LayoutOptions { width_limit: 170, ..layout };
+    let stitch = create_layout_stitch(&squares, layout);
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    assert_eq!((output_size.w, output_size.h), (170, 50));
+    let rects: Vec<(u32, u32)> = stitch.image_rects.iter().map(|rect| (rect.x, rect.w)).collect();
+    assert_eq!(rects, vec![(0, 50), (60, 50), (120, 50)]);
+
+    let layout = LayoutOptions {
+        alignment: AlignmentMode::Grid,
+        spacing: 10,
+        ..LayoutOptions::default()
+    };
+    let stitch = create_layout_stitch(&[(100, 100); 4], layout);
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    assert_eq!((output_size.w, output_size.h), (210, 210));
+
+    // Masonry gaps appear between columns and between images within a column
+    let sizes = [(100, 300), (200, 200), (100, 100), (300, 300), (100, 200), (200, 100)];
+    let layout = LayoutOptions {
+        alignment: AlignmentMode::Masonry,
+        grid: GridSpec { columns: Some(2), ..GridSpec::default() },
+        spacing: 10,
+        ..LayoutOptions::default()
+    };
+    let stitch = create_layout_stitch(&sizes, layout);
+    let positions: Vec<(u32, u32)> = stitch.image_rects.iter()
+        .map(|rect| (rect.x, rect.y))
+        .collect();
+    assert_eq!(
+        positions,
+        vec![(0, 0), (110, 0), (110, 110), (110, 220), (0, 310), (110, 330)]
+    );
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    assert_eq!((output_size.w, output_size.h), (210, 510));
+}
+
+#[test]
+fn check_justified_rows_with_spacing() {
+    let sizes = [(300, 200), (100, 200), (400, 300), (200, 400), (500, 250), (640, 480)];
+    let layout = LayoutOptions {
+        alignment: AlignmentMode::Justified,
+        spacing: 8,
+        width_limit: 400,
+        ..LayoutOptions::default()
+    };
+    let stitch = create_layout_stitch(&sizes, layout);
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    assert_eq!(output_size.w, 400);
+    for rect in stitch.image_rects.iter() {
+        let row: Vec<_> = stitch.image_rects.iter().filter(|other| other.y == rect.y).collect();
+        let line_end = row.iter().map(|other| other.x + other.w).max().unwrap();
+        assert_eq!(line_end, output_size.w, "Row at y = {} does not fill the width", rect.y);
+        assert!(rect.y + rect.h <= output_size.h);
+        let covered: u32 = row.iter().map(|other| other.w).sum();
+        assert_eq!(covered + 8 * (row.len() as u32 - 1), output_size.w);
+    }
+}
+
+#[test]
+fn check_short_lines_are_aligned() {
+    let aligned_layout = |count: usize, columns: usize, line_alignment: LineAlignment| {
+        let layout = LayoutOptions {
+            grid: GridSpec { columns: Some(columns), ..GridSpec::default() },
+            line_alignment,
+            ..LayoutOptions::default()
+        };
+        let stitch = create_layout_stitch(&vec![(100, 100); count], layout);
+        let output_size = stitch.axis_pen.get_output_dimensions();
+        let rects: Vec<(u32, u32, u32, u32)> = stitch.image_rects.iter()
+            .map(|rect| (rect.x, rect.y, rect.w, rect.h))
+            .collect();
+        ((output_size.w, output_size.h), rects)
+    };
+
+    let (size, rects) = aligned_layout(3, 2, LineAlignment::Start);
+    assert_eq!(size, (200, 200));
+    assert_eq!(rects[2], (0, 100, 100, 100));
+    let (size, rects) = aligned_layout(3, 2, LineAlignment::Centre);
+    assert_eq!(size, (200, 200));
+    assert_eq!(rects[2], (50, 100, 100, 100));
+    let (_, rects) = aligned_layout(3, 2, LineAlignment::End);
+    assert_eq!(rects[2], (100, 100, 100, 100));
+    let (_, rects) = aligned_layout(3, 2, LineAlignment::Spread);
+    assert_eq!(rects[2], (50, 100, 100, 100));
+    let (_, rects) = aligned_layout(5, 3, LineAlignment::Spread);
+    assert_eq!((rects[3].0, rects[4].0), (0, 200));
+
+    // Filling makes the last line thicker, and the output grows to fit it
+    let (size, rects) = aligned_layout(3, 2, LineAlignment::Fill);
+    assert_eq!(size, (200, 300));
+    assert_eq!(rects[0], (0, 0, 100, 100));
+    assert_eq!(rects[2], (0, 100, 200, 200));
+}
+
+#[test]
+fn check_short_lines_are_aligned_vertically_and_when_scaled() {
+    let layout = LayoutOptions {
+        grid: GridSpec {
+            rows: Some(2),
+            major_axis: Some(MajorAxis::Vertical),
+            ..GridSpec::default()
+        },
+        line_alignment: LineAlignment::Centre,
+        spacing: 10,
+        ..LayoutOptions::default()
+    };
+    let stitch = create_layout_stitch(&[(100, 100); 3], layout);
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    assert_eq!((output_size.w, output_size.h), (210, 210));
+    assert_eq!((stitch.image_rects[2].x, stitch.image_rects[2].y), (110, 55));
+
+    // The filled line counts towards the limit
+    let layout = LayoutOptions {
+        grid: GridSpec { columns: Some(2), ..GridSpec::default() },
+        line_alignment: LineAlignment::Fill,
+        height_limit: 150,
+        ..LayoutOptions::default()
+    };
+    let stitch = create_layout_stitch(&[(100, 100); 3], layout);
+    let output_size = stitch.axis_pen.get_output_dimensions();
+    assert_eq!((output_size.w, output_size.h), (100, 150));
+    let last = &stitch.image_rects[2];
+    assert_eq!((last.x, last.y, last.w, last.h), (0, 50, 100, 100));
+}
+
+#[test]
+fn check_filled_lines_stay_within_limits_with_spacing() {
+    let size_sets: [&[(u32, u32)]; 3] = [
+        &[(100, 100); 7],
+        &[(300, 200), (100, 200), (400, 300), (200, 400), (500, 250), (640, 480), (90, 160)],
+        &[(1920, 1080), (1080, 1920), (800, 600), (600, 800), (1024, 1024), (400, 300), (64, 48)],
+    ];
+    let grids = [
+        GridSpec { columns: Some(3), ..GridSpec::default() },
+        GridSpec { rows: Some(3), major_axis: Some(MajorAxis::Vertical), ..GridSpec::default() },
+    ];
+    for sizes in size_sets {
+        for grid in grids {
+            for (width_limit, height_limit) in [(237, 123), (123, 237), (400, 90)] {
+                let layout = LayoutOptions {
+                    grid,
+                    line_alignment: LineAlignment::Fill,
+                    spacing: 3,
+                    width_limit,
+                    height_limit,
+                    ..LayoutOptions::default()
+                };
+                let stitch = create_layout_stitch(sizes, layout);
+                let output_size = stitch.axis_pen.get_output_dimensions();
+                assert!(
+                    output_size.w <= width_limit && output_size.h <= height_limit,
+                    "{:?} in {:?} gave {}x{} for limits {}x{}",
+                    sizes, grid, output_size.w, output_size.h, width_limit, height_limit
+                );
+                for rect in stitch.image_rects.iter() {
+                    assert!(rect.x + rect.w <= output_size.w && rect.y + rect.h <= output_size.h);
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn check_plan_matches_stitch() {
+    let images: Vec<image::DynamicImage> = [(100, 200), (300, 100), (200, 200)].iter()
+        .map(|&(w, h)| image::DynamicImage::new_rgba8(w, h))
+        .collect();
+    let builder = Stitch::builder()
+        .images(images)
+        .alignment(AlignmentMode::Horizontal)
+        .spacing(4)
+        .margin(10)
+        .height_limit(120);
+    let plan = builder.plan().unwrap();
+    assert_eq!(plan.height, 120);
+    assert_eq!(plan.placements.len(), 3);
+    assert_eq!(
+        plan.placements[0],
+        Placement { source_index: 0, x: 10, y: 10, width: 50, height: 100 }
+    );
+    assert_eq!(plan.placements[1].x, 64);
+    assert_eq!(plan.width, 10 + 50 + 4 + 300 + 4 + 100 + 10);
+
+    // Hit-testing finds images, but not the gaps or margins
+    assert_eq!(plan.source_at(10, 10), Some(0));
+    assert_eq!(plan.source_at(100, 50), Some(1));
+    assert_eq!(plan.source_at(61, 50), None);
+    assert_eq!(plan.source_at(5, 50), None);
+
+    let output = builder.stitch().unwrap();
+    assert_eq!((output.width(), output.height()), (plan.width, plan.height));
+}
+
+#[test]
+fn check_large_images_prescaled_before_filtering() {
+    let mut source = image::RgbImage::new(1601, 1200);
+    for (x, y, pixel) in source.enumerate_pixels_mut() {
+        *pixel = image::Rgb([(x % 256) as u8, (y % 256) as u8, ((x * y) % 7 * 36) as u8]);
+    }
+    let source = image::DynamicImage::ImageRgb8(source);
+
+    // Only images at least four times the final size are reduced, and never below twice its size
+    assert!(Stitch::prescale(&source, 500, 400).is_none());
+    let prescaled = Stitch::prescale(&source, 100, 75).unwrap();
+    assert_eq!((prescaled.width(), prescaled.height()), (201, 150));
+    for (width, height) in [(100, 75), (40, 30)] {
+        let prescaled = Stitch::prescale(&source, width, height).unwrap();
+        assert!(prescaled.width() >= width * 2 && prescaled.height() >= height * 2);
+        assert!(prescaled.width() < width * 4 || prescaled.height() < height * 4);
+    }
+
+    // The final result is close to resizing the full image directly
+    let placement = Placement { source_index: 0, x: 0, y: 0, width: 80, height: 60 };
+    let direct = source.resize_exact(80, 60, FilterType::Lanczos3).to_rgb8();
+    let reduced = Stitch::resize(&source, &placement, &RenderOptions::default()).to_rgb8();
+    let total_difference: u64 = direct.as_raw().iter()
+        .zip(reduced.as_raw().iter())
+        .map(|(&a, &b)| (a as i32 - b as i32).unsigned_abs() as u64)
+        .sum();
+    let mean_difference = total_difference as f64 / direct.as_raw().len() as f64;
+    assert!(mean_difference < 4.0, "Mean difference was {}", mean_difference);
+}
+
+#[test]
+fn check_linear_resampling() {
+    let render = |resample_mode: ResampleMode| RenderOptions {
+        resize_filter: FilterType::Triangle,
+        resample_mode,
+        ..RenderOptions::default()
+    };
+    let placement = Placement { source_index: 0, x: 0, y: 0, width: 1, height: 1 };
+
+    // Averaging black and white gives mid-grey in linear light, which is lighter once encoded
+    let mut source = image::RgbaImage::new(2, 1);
+    source.put_pixel(0, 0, Rgba([0, 0, 0, 255]));
+    source.put_pixel(1, 0, Rgba([255, 255, 255, 255]));
+    let source = image::DynamicImage::ImageRgba8(source);
+    let encoded = Stitch::resize(&source, &placement, &render(ResampleMode::Encoded));
+    let linear = Stitch::resize(&source, &placement, &render(ResampleMode::Linear));
+    assert!((127..=128).contains(&encoded.get_pixel(0, 0).0[0]));
+    assert_eq!(linear.get_pixel(0, 0).0, [188, 188, 188, 255]);
+
+    // The colour of a fully transparent pixel does not bleed into a visible one
+    let mut source = image::RgbaImage::new(2, 1);
+    source.put_pixel(0, 0, Rgba([255, 0, 0, 255]));
+    source.put_pixel(1, 0, Rgba([0, 255, 0, 0]));
+    let source = image::DynamicImage::ImageRgba8(source);
+    let encoded = Stitch::resize(&source, &placement, &render(ResampleMode::Encoded));
+    let linear = Stitch::resize(&source, &placement, &render(ResampleMode::Linear));
+    assert!(encoded.get_pixel(0, 0).0[1] > 100);
+    assert_eq!(linear.get_pixel(0, 0).0, [255, 0, 0, 128]);
+
+    // Floating point values are already linear, and keep their range above 1.0
+    let mut source = image::Rgb32FImage::new(2, 1);
+    source.put_pixel(0, 0, image::Rgb([0.5, 2.0, 0.0]));
+    source.put_pixel(1, 0, image::Rgb([0.5, 4.0, 1.0]));
+    let source = image::DynamicImage::ImageRgb32F(source);
+    let linear = Stitch::resize(&source, &placement, &render(ResampleMode::Linear));
+    let [r, g, b, a] = linear.as_rgba32f().unwrap().get_pixel(0, 0).0;
+    assert!((r - 0.5).abs() < 1e-5, "{}", r);
+    assert!((g - 3.0).abs() < 1e-5, "{}", g);
+    assert!((b - 0.5).abs() < 1e-5, "{}", b);
+    assert_eq!(a, 1.0);
+    let output = Stitch::builder()
+        .images(vec![source])
+        .resize_filter(FilterType::Triangle)
+        .resample_mode(ResampleMode::Linear)
+        .width_limit(1)
+        .stitch()
+        .unwrap();
+    let [r, g, b, _] = output.as_rgba32f().unwrap().get_pixel(0, 0).0;
+    assert!((r - 0.5).abs() < 1e-5 && (g - 3.0).abs() < 1e-5 && (b - 0.5).abs() < 1e-5);
+}
+
+#[test]
+fn check_resize_filters_and_unsharp_mask() {
+    assert_eq!(FilterType::from(ResizeFilter::default()), FilterType::Lanczos3);
+    assert_eq!(FilterType::from(ResizeFilter::CatmullRom), FilterType::CatmullRom);
+
+    // A soft edge from grey to white, kept at the same size so only sharpening changes it
+    let mut source = image::RgbaImage::new(8, 1);
+    for (x, pixel) in source.pixels_mut().enumerate() {
+        let value = if x < 4 { 100 } else { 200 };
+        *pixel = Rgba([value, value, value, 255]);
+    }
+    let source = image::DynamicImage::ImageRgba8(source);
+    let placement = Placement { source_index: 0, x: 0, y: 0, width: 8, height: 1 };
+    let render = |unsharp_mask: Option<UnsharpMask>| RenderOptions {
+        resize_filter: FilterType::Triangle,
+        unsharp_mask,
+        ..RenderOptions::default()
+    };
+    let plain = Stitch::resize(&source, &placement, &render(None));
+    let mask = UnsharpMask { sigma: 1.0, threshold: 0 };
+    let sharpened = Stitch::resize(&source, &placement, &render(Some(mask)));
+    assert_eq!(plain.get_pixel(3, 0).0[0], 100);
+    assert!(sharpened.get_pixel(3, 0).0[0] < 100);
+    assert!(sharpened.get_pixel(4, 0).0[0] > 200);
+
+    // Floating point images are sharpened without losing values brighter than white or precision
+    let mut source = image::Rgba32FImage::new(8, 1);
+    for (x, pixel) in source.pixels_mut().enumerate() {
+        let value = if x < 4 { 2.0 } else { 4.0 };
+        *pixel = Rgba([value, value + 0.0001, value, 1.0]);
+    }
+    let source = image::DynamicImage::ImageRgba32F(source);
+    let sharpened = Stitch::resize(&source, &placement, &render(Some(mask)));
+    let sharpened = sharpened.as_rgba32f().unwrap();
+    assert!(sharpened.get_pixel(3, 0).0[0] < 2.0);
+    assert!(sharpened.get_pixel(4, 0).0[0] > 4.0);
+    let [r, g, _, a] = sharpened.get_pixel(0, 0).0;
+    assert!((r - 2.0).abs() < 1e-4 && (g - r - 0.0001).abs() < 1e-6, "{} {}", r, g);
+    assert_eq!(a, 1.0);
+
+    // Sharpening settings are checked before stitching
+    let result = Stitch::builder()
+        .images(vec![source])
+        .unsharp_mask(UnsharpMask { sigma: 0.0, threshold: 0 })
+        .stitch();
+    assert!(result.is_err());
+}
+
+#[test]
+fn check_canvas_follows_source_precision() {
+    let choose = |sources: &[ColorType], opaque: bool| {
+        Stitch::canvas_color_type(PixelFormat::Auto, sources, opaque)
+    };
+    assert_eq!(choose(&[ColorType::Rgb8, ColorType::L8], true), ColorType::Rgba8);
+    assert_eq!(choose(&[ColorType::L16, ColorType::L8], true), ColorType::L16);
+    assert_eq!(choose(&[ColorType::L16, ColorType::Rgb8], true), ColorType::Rgb16);
+    assert_eq!(choose(&[ColorType::L16, ColorType::Rgb8], false), ColorType::Rgba16);
+    assert_eq!(choose(&[ColorType::Rgb16, ColorType::La8], true), ColorType::Rgba16);
+    assert_eq!(choose(&[ColorType::Rgb32F, ColorType::Rgb16], true), ColorType::Rgba32F);
+    assert_eq!(
+        Stitch::canvas_color_type(PixelFormat::Luma16, &[ColorType::Rgba8], false),
+        ColorType::L16);
+
+    // Values that 8 bits cannot hold are kept through the stitch
+    let grey = |value: u16| {
+        image::DynamicImage::ImageLuma16(image::ImageBuffer::from_pixel(2, 2, image::Luma([value])))
+    };
+    let sources = vec![grey(1000), grey(1001)];
+    let stitch = |pixel_format: PixelFormat| Stitch::builder()
+        .images(sources.clone())
+        .alignment(AlignmentMode::Horizontal)
+        .pixel_format(pixel_format)
+        .stitch()
+        .unwrap();
+    let output = stitch(PixelFormat::Auto);
+    let output = output.as_luma16().unwrap();
+    assert_eq!(output.dimensions(), (4, 2));
+    assert_eq!(output.get_pixel(0, 0).0, [1000]);
+    assert_eq!(output.get_pixel(3, 1).0, [1001]);
+    let output = stitch(PixelFormat::Rgba32F);
+    assert_eq!(output.color(), ColorType::Rgba32F);
+    assert_eq!(stitch(PixelFormat::Rgba8).color(), ColorType::Rgba8);
+
+    // Gaps around the images need alpha unless the background is opaque
+    let with_margin = Stitch::builder().images(sources.clone()).margin(1);
+    assert_eq!(with_margin.stitch().unwrap().color(), ColorType::Rgba16);
+    let with_background = Stitch::builder()
+        .images(sources)
+        .margin(1)
+        .background(Rgba([255, 255, 255, 255]))
+        .stitch()
+        .unwrap();
+    assert_eq!(with_background.color(), ColorType::L16);
+    assert_eq!(with_background.as_luma16().unwrap().get_pixel(0, 0).0, [u16::MAX]);
+}
+
+#[test]
+fn check_margin_and_background() {
+    let red = Rgba([255, 0, 0, 255]);
+    let blue = Rgba([0, 0, 255, 255]);
+    let mut source = image::RgbaImage::new(2, 2);
+    for pixel in source.pixels_mut() {
+        *pixel = red;
+    }
+    let images = vec![
+        image::DynamicImage::ImageRgba8(source.clone()),
+        image::DynamicImage::ImageRgba8(source),
+    ];
+    let output = Stitch::builder()
+        .images(images.clone())
+        .alignment(AlignmentMode::Horizontal)
+        .spacing(1)
+        .margin(1)
+        .background(blue)
+        .stitch()
+        .unwrap();
+    assert_eq!((output.width(), output.height()), (7, 4));
+    assert_eq!(output.get_pixel(0, 0), blue);
+    assert_eq!(output.get_pixel(1, 1), red);
+    assert_eq!(output.get_pixel(3, 1), blue);
+    assert_eq!(output.get_pixel(4, 2), red);
+    assert_eq!(output.get_pixel(6, 3), blue);
+
+    let result = Stitch::builder()
+        .images(images)
+        .margin(10)
+        .width_limit(20)
+        .stitch();
+    assert!(result.is_err());
+}
+
+#[test]
+fn check_spacing_must_leave_room_within_limits() {
+    let images = |count: usize| vec![image::DynamicImage::new_rgba8(10, 10); count];
+
+    // Gaps alone larger than the limit give an error rather than images with no size
+    let result = Stitch::builder()
+        .images(images(8))
+        .alignment(AlignmentMode::Vertical)
+        .spacing(14)
+        .height_limit(19)
+        .stitch();
+    assert!(result.is_err());
+    let result = Stitch::builder()
+        .images(images(3))
+        .alignment(AlignmentMode::Horizontal)
+        .spacing(5)
+        .margin(2)
+        .width_limit(14)
+        .plan();
+    assert!(result.is_err());
+    let result = Stitch::builder()
+        .images(images(4))
+        .alignment(AlignmentMode::Masonry)
+        .grid(GridSpec { columns: Some(3), ..GridSpec::default() })
+        .spacing(10)
+        .width_limit(20)
+        .plan();
+    assert!(result.is_err());
+
+    // A grid uses an arrangement whose gaps fit, and fails only if there is none
+    let plan = Stitch::builder()
+        .images(images(4))
+        .alignment(AlignmentMode::Grid)
+        .spacing(10)
+        .width_limit(15)
+        .plan()
+        .unwrap();
+    assert!(plan.width <= 15, "{}x{}", plan.width, plan.height);
+    assert!(plan.placements.iter().all(|placement| placement.width > 0));
+    let result = Stitch::builder()
+        .images(images(3))
+        .alignment(AlignmentMode::Grid)
+        .spacing(16)
+        .width_limit(15)
+        .height_limit(15)
+        .plan();
+    assert!(result.is_err());
+
+    // Justified rows are arranged from the image sizes, so the plan itself is checked
+    let result = Stitch::builder()
+        .images(images(6))
+        .alignment(AlignmentMode::Justified)
+        .spacing(40)
+        .width_limit(60)
+        .height_limit(60)
+        .plan();
+    assert!(result.is_err());
+
+    // Without spacing or margin, a limit too small for every image is not an error
+    let plan = Stitch::builder()
+        .images(images(8))
+        .alignment(AlignmentMode::Vertical)
+        .height_limit(5)
+        .plan()
+        .unwrap();
+    assert_eq!(plan.height, 5);
+    assert!(plan.placements.iter().any(|placement| placement.height == 0));
+}
+
 #[test]
 pub fn test_types() {
     // Clear existing file
@@ -222,10 +956,9 @@ pub fn test_output_dimensions() {
     assert_eq!(process_result.height(), 1080);
 
     // Stitch all 4 files in a grid
-    // Main axis will default to horizontal, so images go across first row then across a second,
-    // hence scaled-down images give overall width of 511 + 1080 (from bottom row) and height of
-    // 2 x 1080
-    // NOTE these images could be stitched together in a smarter way!
+    // Placing each image in its own column keeps every image at full size while leaving only the
+    // space below the square image empty, hence overall width of 4 x 1080 and height of 2280. This
+    // is far from square, but a squarer grid would leave more empty space or shrink the images.
     let image_files = ImageFiles::builder()
         .add_current_directory(vec![
             "..",
@@ -244,8 +977,8 @@ pub fn test_output_dimensions() {
     let process_result = Stitch::builder().images(image_files).stitch().unwrap();
 
     // Assert dimensions
-    assert_eq!(process_result.width(), 1591);
-    assert_eq!(process_result.height(), 2160);
+    assert_eq!(process_result.width(), 4320);
+    assert_eq!(process_result.height(), 2280);
 }
 
 #[test]
@@ -280,6 +1013,193 @@ pub fn test_rotated_files() {
     assert_eq!(process_result.height(), 240);
 }
 
+#[test]
+pub fn test_orientation_ignored_or_overridden() {
+    let image_files = || ImageFiles::<FilePathWithMetadata>::builder()
+        .add_current_directory(vec!["..", "..", "images", "testing", "test_rotation"])
+        .unwrap()
+        .build()
+        .unwrap()
+        .sort_and_truncate_by(4, OrderBy::Alphabetic, TakeFrom::Start, false)
+        .unwrap();
+    let sizes = |images: Vec<image::DynamicImage>| -> Vec<(u32, u32)> {
+        images.iter().map(|image| image.dimensions()).collect()
+    };
+
+    // Metadata is applied by default, so all images come out the same way up
+    let files = image_files();
+    assert_eq!(files.dimensions().unwrap(), vec![(320, 240); 4]);
+    assert_eq!(sizes(files.into_image_contents(false).unwrap()), vec![(320, 240); 4]);
+
+    // Ignoring the metadata gives the images as stored, in order 180, 270, 90, and none
+    let stored = vec![(320, 240), (240, 320), (240, 320), (320, 240)];
+    let files = image_files().with_orientation(OrientationMode::Ignore);
+    assert_eq!(files.dimensions().unwrap(), stored);
+    assert_eq!(sizes(files.into_image_contents(false).unwrap()), stored);
+
+    // A fixed rotation replaces the metadata
+    let rotated = vec![(240, 320), (320, 240), (320, 240), (240, 320)];
+    let files = image_files().with_orientation(OrientationMode::Rotate90);
+    assert_eq!(files.dimensions().unwrap(), rotated);
+    let output = Stitch::builder()
+        .alignment(AlignmentMode::Horizontal)
+        .stitch_files(files, false)
+        .unwrap();
+    assert_eq!(output.dimensions(), (180 + 320 + 320 + 180, 240));
+}
+
+#[test]
+pub fn test_png_exif_orientation() {
+    // Insert an eXIf chunk, holding a big-endian orientation tag for a quarter turn clockwise,
+    // just after the header chunk of an encoded PNG
+    let mut png = std::io::Cursor::new(vec![]);
+    image::DynamicImage::new_rgb8(3, 2).write_to(&mut png, image::ImageFormat::Png).unwrap();
+    let png = png.into_inner();
+    let exif: Vec<u8> = [
+        &b"MM\0\x2a"[..], &[0, 0, 0, 8], &[0, 1],
+        &[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0], &[0, 0, 0, 0],
+    ].concat();
+    let mut chunk = (exif.len() as u32).to_be_bytes().to_vec();
+    chunk.extend_from_slice(b"eXIf");
+    chunk.extend_from_slice(&exif);
+    chunk.extend_from_slice(&png_crc(&chunk[4..]).to_be_bytes());
+    let header_end = 8 + 25;
+    let buffer = [&png[..header_end], &chunk, &png[header_end..]].concat();
+
+    let location = || RawBufferLocation::new(&buffer, "image/png".to_owned(), SystemTime::now());
+    let properties = location().into_properties().unwrap();
+    assert_eq!(properties.orientation().unwrap(), image::metadata::Orientation::Rotate90);
+    assert_eq!(properties.dimensions().unwrap(), (2, 3));
+
+    let image_files = |mode: OrientationMode| ImageFilesBuilder::default()
+        .add_file(location())
+        .unwrap()
+        .build()
+        .unwrap()
+        .with_orientation(mode);
+    let image = image_files(OrientationMode::Auto).into_image_contents(false).unwrap();
+    assert_eq!(image[0].dimensions(), (2, 3));
+    let image = image_files(OrientationMode::Ignore).into_image_contents(false).unwrap();
+    assert_eq!(image[0].dimensions(), (3, 2));
+
+    // A chunk length larger than the rest of the file is an error, not a huge allocation
+    let mut truncated = png[..header_end].to_vec();
+    truncated.extend_from_slice(&u32::MAX.to_be_bytes());
+    truncated.extend_from_slice(b"eXIf");
+    truncated.extend_from_slice(&exif);
+    let location = RawBufferLocation::new(&truncated, "image/png".to_owned(), SystemTime::now());
+    let properties = location.into_properties().unwrap();
+    assert!(properties.orientation().is_err());
+}
+
+/// Calculate the CRC used in PNG chunks
+fn png_crc(bytes: &[u8]) -> u32 {
+    let mut crc = u32::MAX;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = match crc & 1 {
+                1 => (crc >> 1) ^ 0xedb8_8320,
+                _ => crc >> 1,
+            };
+        }
+    }
+    !crc
+}
+
+#[test]
+pub fn test_probed_dimensions_match_contents() {
+    // Dimensions read from headers account for orientation metadata, as decoding does
+    let image_files = ImageFiles::builder()
+        .add_current_directory(vec!["..", "..", "images", "testing", "test_rotation"])
+        .unwrap()
+        .build()
+        .unwrap()
+        .sort_and_truncate_by(4, OrderBy::Alphabetic, TakeFrom::Start, false)
+        .unwrap();
+    let dimensions = image_files.dimensions().unwrap();
+    assert_eq!(dimensions, vec![(320, 240); 4]);
+    let plan = Stitch::builder()
+        .alignment(AlignmentMode::Horizontal)
+        .plan_files(&image_files)
+        .unwrap();
+    assert_eq!((plan.width, plan.height), (1280, 240));
+
+    let images = image_files.into_image_contents(false).unwrap();
+    for (image, &(w, h)) in images.iter().zip(dimensions.iter()) {
+        assert_eq!((image.width(), image.height()), (w, h));
+    }
+}
+
+#[test]
+pub fn test_probed_dimensions_from_other_sources() {
+    let mut path = std::env::current_dir().unwrap();
+    for component in ["..", "..", "images", "testing", "test_rotation", "img-90cw.jpg"] {
+        path.push(component);
+    }
+
+    let buffer = std::fs::read(&path).unwrap();
+    let properties = RawBufferLocation::new(&buffer, "image/jpeg".to_owned(), SystemTime::now())
+        .into_properties()
+        .unwrap();
+    assert_eq!(properties.dimensions().unwrap(), (320, 240));
+
+    #[cfg(unix)]
+    {
+        use std::os::fd::IntoRawFd;
+        let fd = std::fs::File::open(&path).unwrap().into_raw_fd();
+        let properties = OwnedRawFdLocation::new(fd, "image/jpeg".to_owned())
+            .into_properties()
+            .unwrap();
+        assert_eq!(properties.dimensions().unwrap(), (320, 240));
+
+        // Probing moves the file position, which must not break loading the contents afterwards
+        let image = properties.into_image_contents(false).unwrap();
+        assert_eq!(image.width() * image.height(), 320 * 240);
+    }
+}
+
+#[test]
+pub fn test_streamed_and_parallel_stitches_match_loaded_stitch() {
+    let image_files = || ImageFiles::<FilePathWithMetadata>::builder()
+        .add_current_directory(vec!["..", "..", "images", "testing", "test_types"])
+        .unwrap()
+        .build()
+        .unwrap()
+        .sort_and_truncate_by(13, OrderBy::Alphabetic, TakeFrom::Start, false)
+        .unwrap();
+    let builder = || Stitch::builder()
+        .resize_filter(FilterType::Nearest)
+        .spacing(2)
+        .background(Rgba([0, 128, 255, 255]))
+        .width_limit(800);
+
+    let loaded = builder()
+        .image_files(image_files())
+        .unwrap()
+        .stitch()
+        .unwrap();
+    let streamed = builder()
+        .stitch_files(image_files(), false)
+        .unwrap();
+    assert_eq!((loaded.width(), loaded.height()), (streamed.width(), streamed.height()));
+    assert!(loaded.as_bytes() == streamed.as_bytes());
+
+    // Loading and resizing on several threads gives exactly the same output
+    let loaded_in_parallel = builder()
+        .threads(4)
+        .image_files(image_files())
+        .unwrap()
+        .stitch()
+        .unwrap();
+    assert!(loaded.as_bytes() == loaded_in_parallel.as_bytes());
+    let streamed_in_parallel = builder()
+        .threads(0)
+        .stitch_files(image_files(), false)
+        .unwrap();
+    assert!(loaded.as_bytes() == streamed_in_parallel.as_bytes());
+}
+
 #[test]
 pub fn test_file_counts() {
     // Attempt increasing number of files, from 2 to 10
@@ -321,3 +1241,365 @@ pub fn test_file_counts() {
         );
     }
 }
+
+#[test]
+pub fn test_sixteen_bit_files_keep_precision() {
+    let mut source = image::ImageBuffer::<image::Rgb<u16>, Vec<u16>>::new(3, 2);
+    for (x, _, pixel) in source.enumerate_pixels_mut() {
+        *pixel = image::Rgb([x as u16 * 1001, 7, 65534]);
+    }
+    let mut buffer = std::io::Cursor::new(vec![]);
+    image::DynamicImage::ImageRgb16(source.clone())
+        .write_to(&mut buffer, image::ImageFormat::Png)
+        .unwrap();
+    let buffer = buffer.into_inner();
+
+    let location = RawBufferLocation::new(&buffer, "image/png".to_owned(), SystemTime::now());
+    let image_files = ImageFilesBuilder::default()
+        .add_file(location)
+        .unwrap()
+        .build()
+        .unwrap();
+    assert_eq!(image_files.color_types().unwrap(), vec![ColorType::Rgb16]);
+    let output = Stitch::builder()
+        .resize_filter(FilterType::Nearest)
+        .stitch_files(image_files, false)
+        .unwrap();
+    assert_eq!(output.as_rgb16(), Some(&source));
+}
+
+/// File properties implementing only the methods that had no default before header probing
+struct MinimalProperties;
+
+impl FileProperties for MinimalProperties {
+    fn infer_format(&self) -> Option<image::ImageFormat> {
+        Some(image::ImageFormat::Png)
+    }
+
+    fn into_image_contents(self, _print_info: bool) -> Result<image::DynamicImage, String> {
+        Ok(image::DynamicImage::new_rgba8(1, 1))
+    }
+
+    fn file_size(&self) -> u64 {
+        0
+    }
+
+    fn modify_time(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH
+    }
+
+    fn full_path(&self) -> Option<&String> {
+        None
+    }
+
+    fn orientation(&self) -> Result<image::metadata::Orientation, String> {
+        Ok(image::metadata::Orientation::NoTransforms)
+    }
+}
+
+#[test]
+fn check_file_properties_defaults() {
+    let properties = MinimalProperties;
+    assert!(properties.dimensions().is_err());
+    assert_eq!(properties.color_type(), Ok(ColorType::Rgba8));
+    assert_eq!(properties.icc_profile(), Ok(None));
+    assert!(properties.animation_frames().unwrap().is_none());
+    assert_eq!(properties.format_mismatch(), None);
+}
+
+#[test]
+pub fn test_embedded_colour_profiles_converted() {
+    use image::{ImageEncoder, codecs::png::PngEncoder};
+
+    let source = image::ImageBuffer::<image::Rgba<u16>, Vec<u16>>::from_pixel(
+        2, 2, image::Rgba([50000, 25000, 12000, 65535]));
+    let mut buffer = vec![];
+    let mut encoder = PngEncoder::new(&mut buffer);
+    encoder.set_icc_profile(WorkingSpace::DisplayP3.icc_profile().unwrap()).unwrap();
+    image::DynamicImage::ImageRgba16(source.clone()).write_with_encoder(encoder).unwrap();
+
+    let location = || RawBufferLocation::new(&buffer, "image/png".to_owned(), SystemTime::now());
+    let properties = location().into_properties().unwrap();
+    assert!(properties.icc_profile().unwrap().is_some());
+
+    let image_files = || ImageFilesBuilder::default()
+        .add_file(location())
+        .unwrap()
+        .build()
+        .unwrap();
+    let stitch = |working_space: WorkingSpace| Stitch::builder()
+        .resize_filter(FilterType::Nearest)
+        .working_space(working_space)
+        .stitch_files(image_files(), false)
+        .unwrap();
+
+    // Converting to sRGB keeps 16-bit precision and changes the colour numbers
+    let srgb = stitch(WorkingSpace::Srgb);
+    let pixel = srgb.as_rgba16().unwrap().get_pixel(0, 0).0;
+    assert!(pixel[0] > 50000 && pixel[2] < 12000, "Got {:?}", pixel);
+    assert_eq!(pixel[3], 65535);
+
+    // Stitching in the same space as the profile leaves the colour as it was
+    let p3 = stitch(WorkingSpace::DisplayP3);
+    let pixel = p3.as_rgba16().unwrap().get_pixel(1, 1).0;
+    for (channel, expected) in pixel.iter().zip(source.get_pixel(1, 1).0) {
+        assert!(channel.abs_diff(expected) <= 16, "Got {:?}", pixel);
+    }
+}
+
+#[test]
+pub fn test_feature_formats_from_buffers() {
+    #[allow(unused_mut)]
+    let mut formats: Vec<(image::ImageFormat, &str, image::DynamicImage)> = vec![];
+    let source = image::RgbImage::from_fn(4, 3, |x, y| image::Rgb([x as u8 * 60, y as u8 * 80, 0]));
+    #[cfg(feature = "tiff")]
+    formats.push((image::ImageFormat::Tiff, "image/tiff", source.clone().into()));
+    #[cfg(feature = "pnm")]
+    formats.push((image::ImageFormat::Pnm, "image/x-portable-pixmap", source.clone().into()));
+    #[cfg(feature = "exr")]
+    formats.push((
+        image::ImageFormat::OpenExr,
+        "image/x-exr",
+        image::DynamicImage::from(source.clone()).to_rgb32f().into()
+    ));
+
+    for (format, mime_type, image) in formats {
+        let mut buffer = std::io::Cursor::new(vec![]);
+        image.write_to(&mut buffer, format).unwrap();
+        let buffer = buffer.into_inner();
+        let location = RawBufferLocation::new(&buffer, mime_type.to_owned(), SystemTime::now());
+        let image_files = ImageFilesBuilder::default()
+            .add_file(location)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(image_files.common_format_in_sources(), Some(format));
+        assert_eq!(image_files.dimensions().unwrap(), vec![(4, 3)]);
+        let output = Stitch::builder()
+            .resize_filter(FilterType::Nearest)
+            .pixel_format(PixelFormat::Rgba8)
+            .stitch_files(image_files, false)
+            .unwrap();
+        assert_eq!(output.to_rgb8(), source);
+    }
+}
+
+#[test]
+pub fn test_formats_detected_from_contents() {
+    use crate::FilePath;
+
+    let directory = std::env::temp_dir()
+        .join(format!("stitchy-sniff-test-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    let source = image::RgbImage::from_pixel(3, 2, image::Rgb([10, 20, 30]));
+    for name in ["wrong.JPG", "upper.PNG", "noextension"] {
+        source.save_with_format(directory.join(name), image::ImageFormat::Png).unwrap();
+    }
+
+    // A PNG named as a JPEG is read as a PNG, with a warning
+    let image_files = ImageFilesBuilder::<FilePathWithMetadata, FilePath>::default()
+        .add_file(FilePath::new(directory.join("wrong.JPG")))
+        .unwrap()
+        .add_file(FilePath::new(directory.join("upper.PNG")))
+        .unwrap()
+        .build()
+        .unwrap();
+    assert_eq!(image_files.common_format_in_sources(), Some(image::ImageFormat::Png));
+    let warnings = image_files.format_warnings();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].ends_with("wrong.JPG is named as JPG but contains PNG data"));
+    let output = Stitch::builder()
+        .alignment(AlignmentMode::Horizontal)
+        .stitch_files(image_files, false)
+        .unwrap();
+    assert_eq!(output.dimensions(), (6, 2));
+
+    // Files without an extension are only used when accepted
+    let without = ImageFilesBuilder::<FilePathWithMetadata, FilePath>::default()
+        .add_directory(directory.clone())
+        .unwrap()
+        .build()
+        .unwrap();
+    assert_eq!(without.file_count(), 2);
+    assert!(ImageFilesBuilder::<FilePathWithMetadata, FilePath>::default()
+        .add_file(FilePath::new(directory.join("noextension")))
+        .is_err());
+    let with = ImageFilesBuilder::<FilePathWithMetadata, FilePath>::default()
+        .accept_extensionless(true)
+        .add_directory(directory.clone())
+        .unwrap()
+        .build()
+        .unwrap();
+    assert_eq!(with.file_count(), 3);
+    assert_eq!(with.format_warnings().len(), 1);
+
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[test]
+pub fn test_buffer_format_mismatch() {
+    let mut buffer = std::io::Cursor::new(vec![]);
+    image::DynamicImage::ImageRgb8(image::RgbImage::new(2, 2))
+        .write_to(&mut buffer, image::ImageFormat::Png)
+        .unwrap();
+    let buffer = buffer.into_inner();
+    let location = RawBufferLocation::new(&buffer, "image/jpeg".to_owned(), SystemTime::now());
+    let properties = location.into_properties().unwrap();
+    assert_eq!(properties.infer_format(), Some(image::ImageFormat::Png));
+    assert_eq!(
+        properties.format_mismatch(),
+        Some((image::ImageFormat::Jpeg, image::ImageFormat::Png))
+    );
+
+    // A file descriptor is checked in the same way, and can still be read afterwards
+    #[cfg(unix)]
+    {
+        use std::os::fd::IntoRawFd;
+        let path = std::env::temp_dir()
+            .join(format!("stitchy-fd-mismatch-test-{}", std::process::id()));
+        std::fs::write(&path, &buffer).unwrap();
+        let fd = std::fs::File::open(&path).unwrap().into_raw_fd();
+        let properties = OwnedRawFdLocation::new(fd, "image/jpeg".to_owned())
+            .into_properties()
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(properties.infer_format(), Some(image::ImageFormat::Png));
+        assert_eq!(
+            properties.format_mismatch(),
+            Some((image::ImageFormat::Jpeg, image::ImageFormat::Png))
+        );
+        assert_eq!(properties.dimensions().unwrap(), (2, 2));
+    }
+}
+
+fn animated_gif(frames: &[([u8; 4], u32)]) -> Vec<u8> {
+    let mut buffer = vec![];
+    {
+        let mut encoder = crate::image::GifEncoder::new(&mut buffer);
+        for &(colour, delay) in frames {
+            encoder.encode_frame(image::Frame::from_parts(
+                image::RgbaImage::from_pixel(2, 2, Rgba(colour)),
+                0,
+                0,
+                image::Delay::from_numer_denom_ms(delay, 1),
+            )).unwrap();
+        }
+    }
+    buffer
+}
+
+#[test]
+pub fn test_animations_lined_up() {
+    let red = [255, 0, 0, 255];
+    let green = [0, 255, 0, 255];
+    let blue = [0, 0, 255, 255];
+    let white = [255, 255, 255, 255];
+    let short = animated_gif(&[(red, 30), (green, 50)]);
+    let long = animated_gif(&[(blue, 60), (white, 60)]);
+    let mut still = std::io::Cursor::new(vec![]);
+    image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(2, 2, Rgba(white)))
+        .write_to(&mut still, image::ImageFormat::Png)
+        .unwrap();
+    let still = still.into_inner();
+
+    let stitch = |sync: AnimationSync| {
+        let image_files = ImageFilesBuilder::default()
+            .add_file(RawBufferLocation::new(&short, "image/gif".to_owned(), SystemTime::now()))
+            .unwrap()
+            .add_file(RawBufferLocation::new(&long, "image/gif".to_owned(), SystemTime::now()))
+            .unwrap()
+            .add_file(RawBufferLocation::new(&still, "image/png".to_owned(), SystemTime::now()))
+            .unwrap()
+            .build()
+            .unwrap();
+        Stitch::builder()
+            .alignment(AlignmentMode::Horizontal)
+            .resize_filter(FilterType::Nearest)
+            .animation_sync(sync)
+            .stitch_animation(image_files, false)
+            .unwrap()
+    };
+    let summary = |frames: Vec<image::Frame>| frames
+        .into_iter()
+        .map(|frame| {
+            let pixel = |x: u32| frame.buffer().get_pixel(x, 0).0;
+            (frame.delay().numer_denom_ms().0, pixel(0), pixel(2), pixel(4))
+        })
+        .collect::<Vec<_>>();
+
+    // A new frame starts whenever either animation changes, and the output lasts 120ms
+    assert_eq!(summary(stitch(AnimationSync::Loop)), vec![
+        (30, red, blue, white),
+        (30, green, blue, white),
+        (20, green, white, white),
+        (30, red, white, white),
+        (10, green, white, white),
+    ]);
+    assert_eq!(summary(stitch(AnimationSync::Hold)), vec![
+        (30, red, blue, white),
+        (30, green, blue, white),
+        (60, green, white, white),
+    ]);
+
+    // Still images alone give a single frame
+    let image_files = ImageFilesBuilder::default()
+        .add_file(RawBufferLocation::new(&still, "image/png".to_owned(), SystemTime::now()))
+        .unwrap()
+        .build()
+        .unwrap();
+    let frames = Stitch::builder().stitch_animation(image_files, false).unwrap();
+    assert_eq!(frames.len(), 1);
+    assert_eq!(frames[0].delay().numer_denom_ms().0, 0);
+}
+
+#[test]
+pub fn test_slideshow_frames() {
+    let red = [255, 0, 0, 255];
+    let blue = [0, 0, 255, 255];
+    let black = [0, 0, 0, 255];
+    let encode = |width: u32, height: u32, colour: [u8; 4]| {
+        let mut bytes = std::io::Cursor::new(vec![]);
+        image::DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(width, height, Rgba(colour)))
+            .write_to(&mut bytes, image::ImageFormat::Png)
+            .unwrap();
+        bytes.into_inner()
+    };
+    let wide = encode(4, 2, red);
+    let tall = encode(2, 4, blue);
+    let slideshow = |builder: crate::StitchBuilder| {
+        let image_files = ImageFilesBuilder::default()
+            .add_file(RawBufferLocation::new(&wide, "image/png".to_owned(), SystemTime::now()))
+            .unwrap()
+            .add_file(RawBufferLocation::new(&tall, "image/png".to_owned(), SystemTime::now()))
+            .unwrap()
+            .build()
+            .unwrap();
+        builder
+            .resize_filter(FilterType::Nearest)
+            .background(Rgba(black))
+            .stitch_slideshow(image_files, false)
+            .unwrap()
+    };
+
+    // Each slide fills a canvas fitting both images, letterboxed with the background
+    let frames = slideshow(Stitch::builder().slide_delay(1500));
+    assert_eq!(frames.len(), 2);
+    for frame in frames.iter() {
+        assert_eq!(frame.buffer().dimensions(), (4, 4));
+        assert_eq!(frame.delay().numer_denom_ms().0, 1500);
+    }
+    assert_eq!(frames[0].buffer().get_pixel(0, 0).0, black);
+    assert_eq!(frames[0].buffer().get_pixel(0, 1).0, red);
+    assert_eq!(frames[1].buffer().get_pixel(0, 1).0, black);
+    assert_eq!(frames[1].buffer().get_pixel(1, 0).0, blue);
+
+    // Crossfades blend between neighbouring slides, and limits shrink the canvas
+    let crossfade = Crossfade { frames: 1, frame_delay: 50 };
+    let frames = slideshow(Stitch::builder().crossfade(crossfade).width_limit(2));
+    let delays: Vec<u32> = frames.iter().map(|frame| frame.delay().numer_denom_ms().0).collect();
+    assert_eq!(delays, vec![1000, 50, 1000]);
+    assert_eq!(frames[1].buffer().dimensions(), (2, 2));
+    assert_eq!(frames[1].buffer().get_pixel(0, 0).0, [128, 0, 128, 255]);
+    assert_eq!(frames[1].buffer().get_pixel(1, 0).0, [128, 0, 0, 255]);
+    assert_eq!(frames[1].buffer().get_pixel(0, 1).0, [0, 0, 128, 255]);
+}
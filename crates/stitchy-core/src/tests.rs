@@ -16,3 +16,109 @@ fn check_files_length_strings() {
         assert_eq!(expected_string, got_string.as_str())
     }
 }
+
+#[test]
+fn check_parallel_results_consumed_in_order() {
+    let mut consumed = vec![];
+    let result = crate::parallel::map_in_order(
+        (0..100).collect(),
+        4,
+        |i: usize| Ok(i * 2),
+        |index, value| {
+            assert_eq!(index * 2, value);
+            consumed.push(value);
+            Ok(())
+        },
+    );
+    assert!(result.is_ok());
+    assert_eq!(consumed, (0..100).map(|i| i * 2).collect::<Vec<_>>());
+
+    // Work stops at an error, and nothing after it is consumed
+    let mut consumed = vec![];
+    let result = crate::parallel::map_in_order(
+        (0..100).collect(),
+        4,
+        |i: usize| if i == 50 { Err("failed".to_owned()) } else { Ok(i) },
+        |_, value| {
+            consumed.push(value);
+            Ok(())
+        },
+    );
+    assert_eq!(result, Err("failed".to_owned()));
+    assert!(consumed.len() <= 50);
+    assert!(consumed.iter().enumerate().all(|(index, &value)| index == value));
+}
+
+#[test]
+fn check_colour_profile_conversion() {
+    use crate::files::colour::{convert_to_working_space, source_profile};
+    use crate::WorkingSpace;
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    let convert = |image: DynamicImage, icc_profile: Option<&[u8]>, working_space| {
+        let source = source_profile(icc_profile, working_space, "test image");
+        convert_to_working_space(image, source.as_ref(), working_space).unwrap()
+    };
+    let source = DynamicImage::ImageRgb8(RgbImage::from_pixel(2, 2, Rgb([200, 100, 50])));
+
+    // Untagged images are already sRGB, so need no conversion into an sRGB working space, and
+    // neither do images tagged with the working space itself
+    assert_eq!(convert(source.clone(), None, WorkingSpace::Srgb), source);
+    let srgb_profile = WorkingSpace::Srgb.icc_profile().unwrap();
+    assert!(source_profile(Some(&srgb_profile), WorkingSpace::Srgb, "test image").is_none());
+    let p3_profile = WorkingSpace::DisplayP3.icc_profile().unwrap();
+    assert!(source_profile(Some(&p3_profile), WorkingSpace::DisplayP3, "test image").is_none());
+
+    // The same numbers in Display P3 are a more saturated colour, which sRGB needs larger
+    // numbers to express
+    let converted = convert(source.clone(), Some(&p3_profile), WorkingSpace::Srgb);
+    let pixel = converted.as_rgb8().unwrap().get_pixel(0, 0).0;
+    assert!(pixel[0] > 200 && pixel[2] < 50, "Got {:?}", pixel);
+
+    // Converting into another working space and back gives the original colour
+    let wide = convert(source.clone(), None, WorkingSpace::DisplayP3);
+    assert_ne!(wide, source);
+    let back = convert(wide.clone(), Some(&p3_profile), WorkingSpace::Srgb);
+    let pixel = back.as_rgb8().unwrap().get_pixel(1, 1).0;
+    for (channel, expected) in pixel.iter().zip([200u8, 100, 50]) {
+        assert!(channel.abs_diff(expected) <= 1, "Got {:?}", pixel);
+    }
+
+    // A damaged profile is taken to be sRGB rather than stopping the stitch
+    let damaged = &p3_profile[..p3_profile.len() / 2];
+    assert_eq!(convert(source.clone(), Some(damaged), WorkingSpace::Srgb), source);
+    assert_eq!(convert(source.clone(), Some(damaged), WorkingSpace::DisplayP3), wide);
+}
+
+#[test]
+fn check_extensions_follow_format_features() {
+    use crate::files::path::FilePathWithMetadata;
+    use crate::files::util::{extension_formats, mime_type_format};
+    use crate::ImageFiles;
+
+    let allowed = ImageFiles::<FilePathWithMetadata>::allowed_extensions();
+    for (extension, format) in extension_formats() {
+        assert!(allowed.contains(&extension.trim_start_matches('.')));
+        let main_extension = ImageFiles::<FilePathWithMetadata>::get_main_extension(format);
+        assert!(main_extension.is_some_and(|main| allowed.contains(&main)));
+    }
+
+    // Formats behind features are only accepted when their feature is enabled
+    for (extension, mime_type, enabled) in [
+        ("tiff", "image/tiff", cfg!(feature = "tiff")),
+        ("qoi", "image/qoi", cfg!(feature = "qoi")),
+        ("tga", "image/x-tga", cfg!(feature = "tga")),
+        ("ico", "image/x-icon", cfg!(feature = "ico")),
+        ("ppm", "image/x-portable-pixmap", cfg!(feature = "pnm")),
+        ("hdr", "image/vnd.radiance", cfg!(feature = "hdr")),
+        ("exr", "image/x-exr", cfg!(feature = "exr"))
+    ] {
+        assert_eq!(allowed.contains(&extension), enabled);
+        assert_eq!(mime_type_format(mime_type).is_some(), enabled);
+    }
+    assert!(mime_type_format("image/png").is_some());
+
+    // AVIF is output only, even with the encoder enabled
+    assert!(!allowed.contains(&"avif"));
+    assert!(mime_type_format("image/avif").is_none());
+}
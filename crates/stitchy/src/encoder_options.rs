@@ -0,0 +1,88 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use jpeg_encoder::SamplingFactor;
+
+/// Resolution at which colour is stored in JPEG output, relative to brightness.
+///
+/// Full resolution keeps coloured text and fine lines in screenshots sharp, while halving it in
+/// one or both directions gives smaller files with little visible difference for photos. Colour
+/// is stored at half width and height unless another choice is made.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum ChromaSubsampling {
+    #[value(name = "444")]
+    #[serde(rename = "444")]
+    Full,
+    #[value(name = "422")]
+    #[serde(rename = "422")]
+    HalfWidth,
+    #[value(name = "420")]
+    #[serde(rename = "420")]
+    #[default]
+    HalfWidthAndHeight,
+}
+
+impl ChromaSubsampling {
+    pub fn sampling_factor(self) -> SamplingFactor {
+        match self {
+            ChromaSubsampling::Full => SamplingFactor::R_4_4_4,
+            ChromaSubsampling::HalfWidth => SamplingFactor::R_4_2_2,
+            ChromaSubsampling::HalfWidthAndHeight => SamplingFactor::R_4_2_0,
+        }
+    }
+}
+
+/// How colours missing from a reduced palette are approximated.
+///
+/// Without dithering each pixel takes the nearest palette colour, which can leave visible bands
+/// in gradients. Floyd–Steinberg dithering passes each pixel's difference on to its neighbours,
+/// and ordered dithering adds a regular pattern, which compresses better but is more visible.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+pub enum Dither {
+    None,
+    #[default]
+    FloydSteinberg,
+    Ordered,
+}
+
+/// Settings for reducing the output to a palette of colours
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PaletteOptions {
+
+    /// The most colours the palette may have, from 2 to 256
+    pub colours: usize,
+
+    pub dither: Dither,
+}
+
+/// Settings for the JPEG encoder, other than the quality
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct JpegOptions {
+
+    /// Write the image in several passes of increasing detail, rather than top to bottom
+    pub progressive: bool,
+
+    /// Chroma subsampling to use, if not the default of 4:2:0 at every quality
+    pub subsampling: Option<ChromaSubsampling>,
+
+    /// Build Huffman tables for the image being encoded, for a slightly smaller file
+    pub optimise_huffman: bool,
+
+    /// Number of blocks between restart markers, which let a decoder recover from corrupted data
+    pub restart_interval: Option<u16>,
+}
+
+/// Settings for writing the output file, each applying to the formats that support them
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct EncoderOptions {
+
+    /// Quality from 1 to 100 for lossy formats, where 100 writes WebP losslessly
+    pub quality: usize,
+
+    /// Encode as small a file as possible, at the expense of speed
+    pub encode_smallest: bool,
+
+    pub jpeg: JpegOptions,
+
+    /// Palette to reduce PNG and GIF output to, if any
+    pub palette: Option<PaletteOptions>,
+}
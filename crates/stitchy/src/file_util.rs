@@ -1,9 +1,46 @@
 
-use crate::Opt;
-use stitchy_core::{ImageFiles, FilePathWithMetadata, image::{GifEncoder, Frame, ImageFormat, DynamicImage, JpegEncoder, PngCompressionType, PngEncoder, PngFilterType}};
+use crate::{
+    encoder_options::{EncoderOptions, JpegOptions},
+    palette::IndexedImage,
+    Opt,
+    OutputFormat,
+};
+use clap::ValueEnum;
+use stitchy_core::{
+    ImageFiles, FilePathWithMetadata,
+    image::{
+        FilterType, GifEncoder, Frame, ImageEncoder, ImageFormat, DynamicImage, PngCompressionType,
+        PngEncoder, PngFilterType, Repeat, RgbaImage, WebPEncoder
+    }
+};
+#[cfg(feature = "avif")]
+use stitchy_core::image::AvifEncoder;
+#[cfg(feature = "pnm")]
+use stitchy_core::image::{PnmEncoder, PnmSampleEncoding, PnmSubtype};
+use std::borrow::Cow;
 use std::fs::File;
-use std::io::BufWriter;
+use std::io::{BufWriter, Cursor, Write};
 use std::path::{Path, PathBuf};
+use webp::{AnimEncoder, AnimFrame, WebPConfig};
+
+/// Highest quality tried when fitting output within a file size, which is lossless for WebP
+const MAX_TARGET_QUALITY: usize = 100;
+
+/// Lowest quality tried when fitting output within a file size, below which the image is scaled
+/// down instead, as lower qualities show blocky artefacts that cost more detail than a smaller
+/// image does
+const MIN_TARGET_QUALITY: usize = 40;
+
+/// The quality and dimensions chosen to fit an output file within a file size, and its final size
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SizeFit {
+    pub quality: usize,
+    pub width: u32,
+    pub height: u32,
+
+    /// Size of the file as written
+    pub bytes: u64,
+}
 
 pub fn to_absolute_dir(path_string: &String) -> Result<PathBuf, String> {
     let path = PathBuf::from(path_string)
@@ -20,9 +57,7 @@ pub fn next_available_output(
     options: &Opt
 ) -> Result<PathBuf, String> {
 
-    let target_extension = ImageFiles::<FilePathWithMetadata>
-        ::get_main_extension(determine_output_format(sources, options)?)
-        .unwrap_or("jpg");
+    let target_extension = determine_output_format(sources, options)?.extension();
 
     let mut output_file_path: PathBuf = match &options.output_dir {
         Some(output_path) => {
@@ -38,7 +73,7 @@ pub fn next_available_output(
 
     // Get current path, check if the default file name exists, if not return it
     let mut un_numbered_file_exists = false;
-    for &extension in ImageFiles::<FilePathWithMetadata>::allowed_extensions().iter() {
+    for extension in OutputFormat::value_variants().iter().map(|format| format.extension()) {
         output_file_path.push(format!("stitch.{}", extension));
         if output_file_path.is_file() {
             un_numbered_file_exists = true;
@@ -57,7 +92,7 @@ pub fn next_available_output(
     let mut i = 1usize;
     while i < 1000 {
         let mut numbered_file_exists = false;
-        for &extension in ImageFiles::<FilePathWithMetadata>::allowed_extensions().iter() {
+        for extension in OutputFormat::value_variants().iter().map(|format| format.extension()) {
             let file_name: String = format!("stitch_{}.{}", i, extension);
             output_file_path.push(file_name);
             if output_file_path.is_file() {
@@ -78,40 +113,562 @@ pub fn next_available_output(
     Err(String::from("Did not find a usable file name - if you have 1000 stitches, please move or delete some."))
 }
 
+/// Encode an image and write it to a file, which is only created once encoding has succeeded
 pub fn write_image_to_file(
     image: DynamicImage,
     file_path: &Path,
-    format: Option<ImageFormat>,
-    quality: usize,
-    encode_smallest: bool
+    format: OutputFormat,
+    options: &EncoderOptions,
+    icc_profile: Option<Vec<u8>>
 ) -> Result<(), String> {
-    let mut file_writer = BufWriter::new(File::create(file_path).unwrap());
+    let data = encode_image(image, format, options, icc_profile)?;
+    write_output_file(file_path, &data)
+}
+
+/// Create the output file and write the encoded image to it
+fn write_output_file(file_path: &Path, data: &[u8]) -> Result<(), String> {
+    File::create(file_path)
+        .map_err(|e| format!("Cannot create output file - {}", e))?
+        .write_all(data)
+        .map_err(|e| format!("Failed to write output file - {}", e))
+}
+
+/// Check that an image is small enough to be stored in a format
+fn check_dimensions(image: &DynamicImage, format: OutputFormat) -> Result<(), String> {
+    match format.max_dimension() {
+        Some(max_dimension) if image.width() > max_dimension || image.height() > max_dimension => {
+            Err(format!(
+                "Cannot write {}x{} output - {} is limited to {} pixels in each direction",
+                image.width(), image.height(), format.name(), max_dimension))
+        },
+        _ => Ok(())
+    }
+}
+
+/// Encode an image in memory, giving the contents of the output file
+pub fn encode_image(
+    image: DynamicImage,
+    format: OutputFormat,
+    options: &EncoderOptions,
+    icc_profile: Option<Vec<u8>>
+) -> Result<Vec<u8>, String> {
+    check_dimensions(&image, format)?;
+    let mut file_writer = Cursor::new(Vec::new());
+    let icc_profile = icc_profile.filter(|_| format.supports_icc_profile());
+    let encode_smallest = options.encode_smallest;
+    if let Some(palette) = options.palette.filter(|_| format.supports_palette()) {
+        let image = match format {
+            OutputFormat::Gif => without_partial_transparency(image.to_rgba8()),
+            _ => image.to_rgba8()
+        };
+        let image = IndexedImage::quantise(&image, palette.colours, palette.dither);
+        write_indexed(image, &mut file_writer, format, encode_smallest, icc_profile)?;
+        return Ok(file_writer.into_inner());
+    }
     let result = match format {
-        None => JpegEncoder::new_with_quality(file_writer, 100).encode_image(&image),
-        Some(ImageFormat::Jpeg) => { JpegEncoder::new_with_quality(file_writer, quality as u8).encode_image(&image) },
-        Some(ImageFormat::Png) => {
+        OutputFormat::Jpeg | OutputFormat::WebP => {
+            return encode_with_quality(&image, format, options, icc_profile);
+        },
+        OutputFormat::Png => {
             let mode = match encode_smallest {
                 true => PngCompressionType::Best,
                 false => PngCompressionType::Fast
             };
-            let encoder = PngEncoder::new_with_quality(file_writer, mode, PngFilterType::default());
+            let mut encoder = PngEncoder::new_with_quality(
+                &mut file_writer, mode, PngFilterType::default());
+            set_icc_profile(&mut encoder, icc_profile)?;
+            to_integer(image).write_with_encoder(encoder)
+        },
+        OutputFormat::Gif => {
+            let speed = match encode_smallest {
+                true => 1,
+                false => 10
+            };
+            let mut encoder = GifEncoder::new_with_speed(&mut file_writer, speed);
+            encoder.encode_frame(Frame::new(image.to_rgba8()))
+        },
+        OutputFormat::Bmp => to_eight_bit(image).write_to(&mut file_writer, ImageFormat::Bmp),
+        OutputFormat::Tiff => {
+            let image = match to_integer(image) {
+                DynamicImage::ImageLumaA8(image) => DynamicImage::ImageRgba8(
+                    DynamicImage::ImageLumaA8(image).to_rgba8()),
+                DynamicImage::ImageLumaA16(image) => DynamicImage::ImageRgba16(
+                    DynamicImage::ImageLumaA16(image).to_rgba16()),
+                image => image
+            };
+            image.write_to(&mut file_writer, ImageFormat::Tiff)
+        },
+        OutputFormat::Qoi => {
+            let image = match image {
+                DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => image,
+                _ => DynamicImage::ImageRgba8(image.to_rgba8())
+            };
+            image.write_to(&mut file_writer, ImageFormat::Qoi)
+        },
+        #[cfg(feature = "avif")]
+        OutputFormat::Avif => {
+            let speed = match encode_smallest {
+                true => 1,
+                false => 6
+            };
+            let encoder = AvifEncoder::new_with_speed_quality(
+                &mut file_writer, speed, options.quality as u8);
+            to_eight_bit(image).write_with_encoder(encoder)
+        },
+        #[cfg(feature = "pnm")]
+        OutputFormat::Pnm => {
+            let image = without_alpha(to_integer(image));
+            let encoder = PnmEncoder::new(&mut file_writer);
+            let encoder = match image {
+                DynamicImage::ImageRgb8(_) => encoder
+                    .with_subtype(PnmSubtype::Pixmap(PnmSampleEncoding::Binary)),
+                DynamicImage::ImageLuma8(_) => encoder
+                    .with_subtype(PnmSubtype::Graymap(PnmSampleEncoding::Binary)),
+                _ => encoder
+            };
             image.write_with_encoder(encoder)
         },
-        Some(ImageFormat::Gif) => {
+        OutputFormat::Ico => to_eight_bit(image).write_to(&mut file_writer, ImageFormat::Ico),
+        OutputFormat::OpenExr => {
+            let image = match image.color().has_alpha() {
+                true => DynamicImage::ImageRgba32F(image.to_rgba32f()),
+                false => DynamicImage::ImageRgb32F(image.to_rgb32f())
+            };
+            image.write_to(&mut file_writer, ImageFormat::OpenExr)
+        },
+        #[allow(unreachable_patterns)]
+        unavailable_format => {
+            return Err(format!("Output format {} is not available", unavailable_format.name()))
+        },
+    };
+    result.map_err(|e| format!("Failed to generate output file - {}", e))?;
+    Ok(file_writer.into_inner())
+}
+
+/// Encode an image as JPEG or WebP, the formats with a quality setting, without taking
+/// ownership of it so that it can be encoded again at another quality
+fn encode_with_quality(
+    image: &DynamicImage,
+    format: OutputFormat,
+    options: &EncoderOptions,
+    icc_profile: Option<Vec<u8>>
+) -> Result<Vec<u8>, String> {
+    let mut data = Vec::new();
+    match format {
+        OutputFormat::Jpeg => {
+            write_jpeg(image, &mut data, options.quality, &options.jpeg, icc_profile)?;
+        },
+        OutputFormat::WebP if options.quality < 100 => {
+            return encode_lossy_webp(image, options.quality, icc_profile);
+        },
+        OutputFormat::WebP => {
+            let mut encoder = WebPEncoder::new_lossless(&mut data);
+            set_icc_profile(&mut encoder, icc_profile)?;
+            let result = match image {
+                DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_)
+                    | DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => {
+                    image.write_with_encoder(encoder)
+                },
+                _ => DynamicImage::ImageRgba8(image.to_rgba8()).write_with_encoder(encoder)
+            };
+            result.map_err(|e| format!("Failed to generate output file - {}", e))?;
+        },
+        _ => return Err(format!("Output format {} has no quality setting", format.name()))
+    }
+    Ok(data)
+}
+
+/// Encode a lossy WebP file using libwebp, embedding the colour profile afterwards
+fn encode_lossy_webp(
+    image: &DynamicImage,
+    quality: usize,
+    icc_profile: Option<Vec<u8>>
+) -> Result<Vec<u8>, String> {
+    let (width, height) = (image.width(), image.height());
+    let (rgba, rgb);
+    let encoder = match image {
+        DynamicImage::ImageRgb8(buffer) => webp::Encoder::from_rgb(buffer, width, height),
+        DynamicImage::ImageRgba8(buffer) => webp::Encoder::from_rgba(buffer, width, height),
+        _ if image.color().has_alpha() => {
+            rgba = image.to_rgba8();
+            webp::Encoder::from_rgba(&rgba, width, height)
+        },
+        _ => {
+            rgb = image.to_rgb8();
+            webp::Encoder::from_rgb(&rgb, width, height)
+        }
+    };
+    let data = encoder.encode_simple(false, quality as f32)
+        .map_err(|e| format!("Failed to generate output file - WebP encoder error {:?}", e))?;
+    match icc_profile {
+        Some(icc_profile) => with_webp_icc_profile(&data, width, height, &icc_profile),
+        None => Ok(data.to_vec())
+    }
+}
+
+/// Write the frames of an animation to a GIF, WebP, or APNG file, which plays the given number
+/// of times, or repeats forever if zero. WebP frames are stored losslessly unless the quality is
+/// below 100.
+pub fn write_animation_to_file(
+    frames: Vec<Frame>,
+    file_path: &Path,
+    format: OutputFormat,
+    options: &EncoderOptions,
+    loops: u16
+) -> Result<(), String> {
+    let EncoderOptions { quality, encode_smallest, .. } = *options;
+    let frame_delay = |frame: &Frame| {
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        numerator / denominator.max(1)
+    };
+    match format {
+        OutputFormat::Png => {
+            let Some((width, height)) = frames.first().map(|frame| frame.buffer().dimensions()) else {
+                return Err("No frames to write".to_owned());
+            };
+            let file_writer = BufWriter::new(File::create(file_path)
+                .map_err(|e| format!("Cannot create output file - {}", e))?);
+            let mut encoder = png::Encoder::new(file_writer, width, height);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_compression(match encode_smallest {
+                true => png::Compression::Best,
+                false => png::Compression::Fast
+            });
+            let write_frames = move || -> Result<(), png::EncodingError> {
+                encoder.set_animated(frames.len() as u32, loops as u32)?;
+                let mut writer = encoder.write_header()?;
+                for frame in frames.iter() {
+                    let delay = frame_delay(frame).min(u16::MAX as u32) as u16;
+                    writer.set_frame_delay(delay, 1000)?;
+                    writer.write_image_data(frame.buffer())?;
+                }
+                writer.finish()
+            };
+            write_frames().map_err(|e| format!("Failed to generate output file - {}", e))
+        },
+        OutputFormat::Gif => {
+            let file_writer = BufWriter::new(File::create(file_path)
+                .map_err(|e| format!("Cannot create output file - {}", e))?);
             let speed = match encode_smallest {
                 true => 1,
                 false => 10
             };
+            let repeat = match loops {
+                0 => Repeat::Infinite,
+                loops => Repeat::Finite(loops - 1)
+            };
             let mut encoder = GifEncoder::new_with_speed(file_writer, speed);
-            encoder.encode_frame(Frame::new(image.to_rgba8()))
+            encoder.set_repeat(repeat)
+                .and_then(|_| encoder.encode_frames(frames))
+                .map_err(|e| format!("Failed to generate output file - {}", e))
         },
-        Some(ImageFormat::Bmp) => image.write_to(&mut file_writer, ImageFormat::Bmp),
-        Some(ImageFormat::WebP) => image.write_to(&mut file_writer, ImageFormat::WebP),
-        Some(other_format) => { panic!("Internal error: found format {:?}", other_format) },
+        OutputFormat::WebP => {
+            let Some((width, height)) = frames.first().map(|frame| frame.buffer().dimensions()) else {
+                return Err("No frames to write".to_owned());
+            };
+            let mut config = WebPConfig::new()
+                .map_err(|_| "Failed to configure the WebP encoder".to_owned())?;
+            config.lossless = (quality >= 100) as i32;
+            config.quality = quality as f32;
+
+            // The encoder ends the animation at a timestamp of zero, so frames are given times
+            // counting up to zero, from which libwebp finds the length of every frame
+            let delays: Vec<i32> = frames.iter().map(|frame| frame_delay(frame) as i32).collect();
+            let mut timestamp = -delays.iter().sum::<i32>();
+            let mut encoder = AnimEncoder::new(width, height, &config);
+            encoder.set_loop_count(loops as i32);
+            for (frame, delay) in frames.iter().zip(delays) {
+                encoder.add_frame(AnimFrame::from_rgba(frame.buffer(), width, height, timestamp));
+                timestamp += delay;
+            }
+            let data = encoder.try_encode()
+                .map_err(|e| format!("Failed to generate output file - {:?}", e))?;
+            std::fs::write(file_path, &*data)
+                .map_err(|e| format!("Failed to write output file - {}", e))
+        },
+        _ => Err(format!("Output format {} cannot hold an animation", format.name()))
+    }
+}
+
+/// Write an image already reduced to a palette as an indexed PNG or GIF, storing PNG pixels in
+/// as few bits as the palette allows
+fn write_indexed<W: Write>(
+    image: IndexedImage,
+    file_writer: W,
+    format: OutputFormat,
+    encode_smallest: bool,
+    icc_profile: Option<Vec<u8>>
+) -> Result<(), String> {
+    let rgb_palette: Vec<u8> = image.palette
+        .iter()
+        .flat_map(|colour| [colour[0], colour[1], colour[2]])
+        .collect();
+    match format {
+        OutputFormat::Png => {
+            let (bit_depth, bits) = match image.palette.len() {
+                0..=2 => (png::BitDepth::One, 1),
+                3..=4 => (png::BitDepth::Two, 2),
+                5..=16 => (png::BitDepth::Four, 4),
+                _ => (png::BitDepth::Eight, 8)
+            };
+            let mut info = png::Info::with_size(image.width, image.height);
+            info.color_type = png::ColorType::Indexed;
+            info.bit_depth = bit_depth;
+            info.palette = Some(rgb_palette.into());
+            if let Some(last) = image.palette.iter().rposition(|colour| colour[3] < 255) {
+                let alphas: Vec<u8> = image.palette[..=last]
+                    .iter()
+                    .map(|colour| colour[3])
+                    .collect();
+                info.trns = Some(alphas.into());
+            }
+            info.icc_profile = icc_profile.map(Into::into);
+            info.compression = match encode_smallest {
+                true => png::Compression::Best,
+                false => png::Compression::Fast
+            };
+
+            // Pack the indices of each row into bytes, with the first pixel in the highest bits
+            let pixels_per_byte = 8 / bits;
+            let data: Vec<u8> = image.indices
+                .chunks(image.width.max(1) as usize)
+                .flat_map(|row| row.chunks(pixels_per_byte).map(|pixels| {
+                    pixels.iter().enumerate().fold(0u8, |byte, (position, &index)| {
+                        byte | index << (8 - bits * (position + 1))
+                    })
+                }))
+                .collect();
+            let write = move || -> Result<(), png::EncodingError> {
+                let mut writer = png::Encoder::with_info(file_writer, info)?.write_header()?;
+                writer.write_image_data(&data)?;
+                writer.finish()
+            };
+            write().map_err(|e| format!("Failed to generate output file - {}", e))
+        },
+        OutputFormat::Gif => {
+            let (width, height) = match (u16::try_from(image.width), u16::try_from(image.height)) {
+                (Ok(width), Ok(height)) => (width, height),
+                _ => return Err(
+                    format!("GIF output is limited to {} pixels in each direction", u16::MAX))
+            };
+            let transparent = image.palette.iter().position(|colour| colour[3] == 0);
+            let frame = gif::Frame {
+                width,
+                height,
+                transparent: transparent.map(|index| index as u8),
+                buffer: image.indices.into(),
+                ..gif::Frame::default()
+            };
+            gif::Encoder::new(file_writer, width, height, &rgb_palette)
+                .and_then(|mut encoder| encoder.write_frame(&frame))
+                .map_err(|e| format!("Failed to generate output file - {}", e))
+        },
+        _ => Err(format!("Output format {} cannot be written with a palette", format.name()))
+    }
+}
+
+/// Make each pixel either fully transparent or opaque, for formats with no partial transparency
+fn without_partial_transparency(mut image: RgbaImage) -> RgbaImage {
+    for pixel in image.pixels_mut() {
+        pixel[3] = if pixel[3] < 128 { 0 } else { 255 };
+    }
+    image
+}
+
+/// Encode a JPEG file with the given settings, dropping any transparency
+fn write_jpeg<W: Write>(
+    image: &DynamicImage,
+    file_writer: W,
+    quality: usize,
+    options: &JpegOptions,
+    icc_profile: Option<Vec<u8>>
+) -> Result<(), String> {
+    let (width, height) = match (u16::try_from(image.width()), u16::try_from(image.height())) {
+        (Ok(width), Ok(height)) => (width, height),
+        _ => return Err(format!("JPEG output is limited to {} pixels in each direction", u16::MAX))
+    };
+    let mut encoder = jpeg_encoder::Encoder::new(file_writer, quality as u8);
+    encoder.set_progressive(options.progressive);
+    encoder.set_sampling_factor(options.subsampling.unwrap_or_default().sampling_factor());
+    encoder.set_optimized_huffman_tables(options.optimise_huffman);
+    if let Some(restart_interval) = options.restart_interval {
+        encoder.set_restart_interval(restart_interval);
+    }
+    if let Some(icc_profile) = icc_profile {
+        encoder.add_icc_profile(&icc_profile)
+            .map_err(|e| format!("Cannot embed colour profile - {}", e))?;
+    }
+    let result = match image {
+        DynamicImage::ImageLuma8(image) => {
+            encoder.encode(image, width, height, jpeg_encoder::ColorType::Luma)
+        },
+        DynamicImage::ImageLumaA8(_) | DynamicImage::ImageLuma16(_)
+            | DynamicImage::ImageLumaA16(_) => {
+            encoder.encode(&image.to_luma8(), width, height, jpeg_encoder::ColorType::Luma)
+        },
+        image => encoder.encode(&image.to_rgb8(), width, height, jpeg_encoder::ColorType::Rgb)
     };
     result.map_err(|e| format!("Failed to generate output file - {}", e))
 }
 
+/// Embed a colour profile in a lossy WebP file, which libwebp cannot do when encoding. The
+/// profile goes in an ICCP chunk following the extended format header, which is added if the
+/// encoder wrote a simple file without one.
+fn with_webp_icc_profile(
+    data: &[u8],
+    width: u32,
+    height: u32,
+    icc_profile: &[u8]
+) -> Result<Vec<u8>, String> {
+    const ICC_PROFILE_FLAG: u8 = 0x20;
+    if data.len() < 16 || &data[0..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return Err("Failed to generate output file - the WebP encoder output is invalid".to_owned());
+    }
+    let chunks = &data[12..];
+    let (header, chunks) = match &chunks[0..4] == b"VP8X" && chunks.len() >= 18 {
+        true => {
+            let mut header = chunks[..18].to_vec();
+            header[8] |= ICC_PROFILE_FLAG;
+            (header, &chunks[18..])
+        },
+        false => {
+            let mut header = b"VP8X".to_vec();
+            header.extend(10u32.to_le_bytes());
+            header.extend([ICC_PROFILE_FLAG, 0, 0, 0]);
+            header.extend(&(width - 1).to_le_bytes()[..3]);
+            header.extend(&(height - 1).to_le_bytes()[..3]);
+            (header, chunks)
+        }
+    };
+
+    // Chunks are padded to an even length
+    let mut icc_chunk = b"ICCP".to_vec();
+    icc_chunk.extend((icc_profile.len() as u32).to_le_bytes());
+    icc_chunk.extend(icc_profile);
+    if icc_profile.len() % 2 == 1 {
+        icc_chunk.push(0);
+    }
+
+    let riff_size = 4 + header.len() + icc_chunk.len() + chunks.len();
+    let mut output = Vec::with_capacity(8 + riff_size);
+    output.extend(b"RIFF");
+    output.extend((riff_size as u32).to_le_bytes());
+    output.extend(b"WEBP");
+    output.extend(header);
+    output.extend(icc_chunk);
+    output.extend(chunks);
+    Ok(output)
+}
+
+/// Embed a colour profile in the output, if one is given
+fn set_icc_profile(encoder: &mut impl ImageEncoder, icc_profile: Option<Vec<u8>>) -> Result<(), String> {
+    if let Some(icc_profile) = icc_profile {
+        encoder.set_icc_profile(icc_profile)
+            .map_err(|e| format!("Cannot embed colour profile - {}", e))?;
+    }
+    Ok(())
+}
+
+/// Reduce floating point images to 16 bits per channel for encoders that only store integers
+fn to_integer(image: DynamicImage) -> DynamicImage {
+    match image {
+        DynamicImage::ImageRgb32F(_) | DynamicImage::ImageRgba32F(_) => {
+            DynamicImage::ImageRgba16(image.to_rgba16())
+        },
+        _ => image
+    }
+}
+
+/// Remove the alpha channel from an integer image, for formats whose readers expect none
+#[cfg(feature = "pnm")]
+fn without_alpha(image: DynamicImage) -> DynamicImage {
+    match image {
+        DynamicImage::ImageLumaA8(_) => DynamicImage::ImageLuma8(image.to_luma8()),
+        DynamicImage::ImageLumaA16(_) => DynamicImage::ImageLuma16(image.to_luma16()),
+        DynamicImage::ImageRgba16(_) => DynamicImage::ImageRgb16(image.to_rgb16()),
+        DynamicImage::ImageRgba8(_) => DynamicImage::ImageRgb8(image.to_rgb8()),
+        _ => image
+    }
+}
+
+/// Reduce an image to 8 bits per channel for encoders that cannot store any more
+fn to_eight_bit(image: DynamicImage) -> DynamicImage {
+    match image {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_)
+            | DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => image,
+        _ => DynamicImage::ImageRgba8(image.to_rgba8())
+    }
+}
+
+/// Write an image at the highest quality that fits within a number of bytes. If the file is still
+/// too large at the lowest quality tried, the image is scaled down and the search repeated. Each
+/// attempt is encoded in memory, and only the one chosen is written, so nothing is written if no
+/// attempt fits.
+pub fn write_image_within_size(
+    image: DynamicImage,
+    file_path: &Path,
+    format: OutputFormat,
+    options: &EncoderOptions,
+    icc_profile: Option<Vec<u8>>,
+    max_bytes: u64,
+    resize_filter: FilterType
+) -> Result<SizeFit, String> {
+    check_dimensions(&image, format)?;
+    let (full_width, full_height) = (image.width(), image.height());
+    let mut scale = 1.0f64;
+    loop {
+        let width = ((full_width as f64 * scale).round() as u32).max(1);
+        let height = ((full_height as f64 * scale).round() as u32).max(1);
+        let scaled_image = match scale < 1.0 {
+            true => Cow::Owned(image.resize_exact(width, height, resize_filter)),
+            false => Cow::Borrowed(&image)
+        };
+        let encode_at_quality = |quality: usize| -> Result<Vec<u8>, String> {
+            let options = EncoderOptions { quality, ..*options };
+            encode_with_quality(&scaled_image, format, &options, icc_profile.clone())
+        };
+        let fits = |data: &Vec<u8>| data.len() as u64 <= max_bytes;
+        let write_fit = |quality: usize, data: Vec<u8>| -> Result<SizeFit, String> {
+            write_output_file(file_path, &data)?;
+            Ok(SizeFit { quality, width, height, bytes: data.len() as u64 })
+        };
+
+        // Try the best quality first, then the lowest, then search between them
+        let best_data = encode_at_quality(MAX_TARGET_QUALITY)?;
+        if fits(&best_data) {
+            return write_fit(MAX_TARGET_QUALITY, best_data);
+        }
+        let lowest_data = encode_at_quality(MIN_TARGET_QUALITY)?;
+        if fits(&lowest_data) {
+            let mut best = (MIN_TARGET_QUALITY, lowest_data);
+            let (mut low, mut high) = (MIN_TARGET_QUALITY + 1, MAX_TARGET_QUALITY - 1);
+            while low <= high {
+                let quality = (low + high) / 2;
+                let data = encode_at_quality(quality)?;
+                match fits(&data) {
+                    true => {
+                        best = (quality, data);
+                        low = quality + 1;
+                    },
+                    false => high = quality - 1
+                }
+            }
+            let (quality, data) = best;
+            return write_fit(quality, data);
+        }
+
+        // File size falls roughly in proportion to the number of pixels, so shrink the area by
+        // the amount the file is over, plus a little more to avoid many near misses
+        if width == 1 && height == 1 {
+            return Err(format!(
+                "The output cannot be made small enough to fit within {} bytes.", max_bytes));
+        }
+        let area_ratio = max_bytes as f64 / lowest_data.len() as f64;
+        scale *= (area_ratio.sqrt() * 0.95).min(0.9);
+    }
+}
+
 pub fn size_of_file(file_path: &Path) -> Result<u64, String> {
     let length_bytes = file_path.metadata()
         .map_err(|_| "File metadata could not be read.".to_owned())?
@@ -127,24 +684,31 @@ pub fn make_ratio_string(input_size: u64, output_size: u64) -> String {
     format!("{:.0}%", ratio * 100.0)
 }
 
+/// Choose the output format, being the one requested or otherwise the format shared by all of
+/// the sources. If they do not share a format that output can be written in, or that can be fitted
+/// within a maximum file size if one is set, JPEG is used, or GIF for a slideshow.
 pub fn determine_output_format(
     sources: &ImageFiles<FilePathWithMetadata>,
     options: &Opt
-) -> Result<ImageFormat, String> {
+) -> Result<OutputFormat, String> {
 
-    let requested_format: Option<ImageFormat> = options.get_requested_image_format();
-
-    // Check if no format was specified, but all source images are the same
-    let image_format = match requested_format {
+    // Check if no format was specified, but all source images are the same; slideshows need a
+    // format that can hold an animation, so use GIF for those if the sources are in another
+    let output_format = match options.format {
         None => {
-            let common_format = sources.common_format_in_sources();
+            let common_format = sources.common_format_in_sources()
+                .and_then(OutputFormat::from_image_format)
+                .filter(|format| format.is_available())
+                .filter(|format| format.supports_animation() || !options.slideshow)
+                .filter(|format| format.supports_size_target() || options.max_bytes.is_none());
             match common_format {
-                None => ImageFormat::Jpeg,
+                None if options.slideshow => OutputFormat::Gif,
+                None => OutputFormat::DEFAULT,
                 Some(format) => {
-                    if format != ImageFormat::Jpeg && options.quality != 100 {
+                    if !format.supports_quality() && options.quality != 100 {
                         return Err(format!(
-                            "Output format {:?} cannot use a quality setting.",
-                            format));
+                            "Output format {} cannot use a quality setting.",
+                            format.name()));
                     }
                     format
                 }
@@ -152,6 +716,9 @@ pub fn determine_output_format(
         },
         Some(format) => format
     };
+    if let Some(e) = options.check_pixel_format_supported(output_format) {
+        return Err(e);
+    }
 
-    Ok(image_format)
+    Ok(output_format)
 }
@@ -1,21 +1,30 @@
+mod encoder_options;
 mod file_util;
 mod options;
+mod output_format;
+mod palette;
 mod print;
 mod profiles;
 
 #[cfg(test)]
 mod tests;
 
-use clap::Parser;
 use options::Opt;
+use output_format::OutputFormat;
 use stitchy_core::{
-    image::FilterType, util::make_size_string, FilePathWithMetadata, ImageFiles, OrderBy, Stitch,
-    TakeFrom,
+    image::{DynamicImage, FilterType}, util::make_size_string, Crossfade, FilePathWithMetadata,
+    ImageFiles, OrderBy, ResampleMode, Stitch, TakeFrom, UnsharpMask,
 };
 
+/// How long each slide of a slideshow is shown for, in milliseconds, unless set with --delay
+const DEFAULT_SLIDE_DELAY: u32 = 1000;
+
+/// How long each frame of a crossfade between slides is shown for, in milliseconds
+const CROSSFADE_FRAME_DELAY: u32 = 50;
+
 fn main() {
     // Get command line args, check for flags that merely print to the console and exit
-    let opt = Opt::parse();
+    let opt = Opt::parse_with_format_flags(std::env::args()).unwrap_or_else(|e| e.exit());
     if opt.help {
         print::help();
         return;
@@ -75,10 +84,12 @@ fn run_with_options(opt: Opt) -> Result<String, String> {
         Some(source_path) => {
             let dir = file_util::to_absolute_dir(source_path)?;
             ImageFiles::<FilePathWithMetadata>::builder()
+                .accept_extensionless(opt.extensionless)
                 .add_directory(dir)?
                 .build()?
         }
         None => ImageFiles::<FilePathWithMetadata>::builder()
+            .accept_extensionless(opt.extensionless)
             .add_current_directory(vec![])?
             .build()?,
     };
@@ -87,29 +98,114 @@ fn run_with_options(opt: Opt) -> Result<String, String> {
         opt.order.unwrap_or(OrderBy::Latest),
         opt.take_from.unwrap_or(TakeFrom::Start),
         opt.reverse,
-    )?;
+    )?.with_orientation(opt.orientation.unwrap_or_default());
+    for warning in image_sources.format_warnings() {
+        println!("Warning: {}", warning);
+    }
 
     // Determine the output path, considering the input files if need be
     let total_source_size = image_sources.total_size();
     let output_format = file_util::determine_output_format(&image_sources, &opt)?;
     let output_file_path = file_util::next_available_output(&image_sources, &opt)?;
 
-    // Open the image files and process them to make the output image
-    let images = image_sources.into_image_contents(true)?;
-    let resize_mode = match opt.fast {
-        true => FilterType::Nearest,
-        false => FilterType::Lanczos3,
+    // Plan the layout from the file headers, then load and place the image files one at a time
+    let resize_mode = match (opt.filter, opt.fast) {
+        (Some(filter), _) => FilterType::from(filter),
+        (None, true) => FilterType::Nearest,
+        (None, false) => FilterType::Lanczos3,
+    };
+    let working_space = opt.working_space.unwrap_or_default();
+    let size_limit = |limit: usize| match output_format.max_dimension() {
+        Some(max_dimension) if limit == 0 || limit as u32 > max_dimension => max_dimension,
+        _ => limit as u32,
     };
-    let output = Stitch::builder()
-        .images(images)
+    let mut builder = Stitch::builder()
         .alignment(opt.get_alignment())
-        .width_limit(opt.maxw as u32)
-        .height_limit(opt.maxh as u32)
+        .grid(opt.get_grid_spec())
+        .width_limit(size_limit(opt.maxw))
+        .height_limit(size_limit(opt.maxh))
+        .line_alignment(opt.line_align.unwrap_or_default())
+        .spacing(opt.spacing as u32)
+        .margin(opt.margin as u32)
+        .background(opt.get_background()?)
         .resize_filter(resize_mode)
-        .stitch()?;
+        .resample_mode(if opt.linear { ResampleMode::Linear } else { ResampleMode::Encoded })
+        .pixel_format(opt.pixel_format.unwrap_or_default())
+        .working_space(working_space)
+        .threads(opt.jobs);
+    if let Some(aspect) = opt.aspect {
+        builder = builder.target_aspect_ratio(aspect);
+    }
+    if let Some(sigma) = opt.sharpen {
+        builder = builder.unsharp_mask(UnsharpMask { sigma, threshold: 0 });
+    }
 
-    // Write the output file, returning a success message or an error message
-    file_util::write_image_to_file(output, &output_file_path, Some(output_format), opt.quality, opt.small)?;
+    // Show the images one at a time for a slideshow, or otherwise stitch every frame of animated
+    // sources if the output format can hold an animation, which gives a single frame if none of
+    // them turn out to be animated
+    let animate = output_format.supports_animation() && image_sources.may_be_animated();
+    let output = if opt.slideshow || animate {
+        let mut frames = if opt.slideshow {
+            if let Some(crossfade_frames) = opt.crossfade {
+                builder = builder.crossfade(Crossfade {
+                    frames: crossfade_frames,
+                    frame_delay: CROSSFADE_FRAME_DELAY,
+                });
+            }
+            builder
+                .slide_delay(opt.delay.unwrap_or(DEFAULT_SLIDE_DELAY))
+                .stitch_slideshow(image_sources, true)?
+        } else {
+            builder
+                .animation_sync(opt.animation_sync.unwrap_or_default())
+                .stitch_animation(image_sources, true)?
+        };
+        if frames.len() > 1 {
+            if opt.max_bytes.is_some() {
+                return Err("Animated output cannot be fitted within a maximum file size.".to_owned());
+            }
+            file_util::write_animation_to_file(
+                frames,
+                &output_file_path,
+                output_format,
+                &opt.encoder_options(),
+                opt.loops.unwrap_or(0))?;
+            None
+        } else {
+            Some(DynamicImage::ImageRgba8(frames.remove(0).into_buffer()))
+        }
+    } else {
+        Some(builder.stitch_files(image_sources, true)?)
+    };
+
+    // Write the output file, searching for a quality and size that fit if a limit was set, and
+    // returning a success message or an error message
+    if let Some(output) = output {
+        let icc_profile = working_space.icc_profile()?;
+        match opt.max_bytes {
+            Some(max_bytes) => {
+                let (full_width, full_height) = (output.width(), output.height());
+                let fit = file_util::write_image_within_size(
+                    output,
+                    &output_file_path,
+                    output_format,
+                    &opt.encoder_options(),
+                    Some(icc_profile),
+                    max_bytes,
+                    resize_mode)?;
+                let scale = match (fit.width, fit.height) == (full_width, full_height) {
+                    true => String::new(),
+                    false => format!(", scaled down from {}x{}", full_width, full_height),
+                };
+                println!(
+                    "Fitted {} within {} at quality {}, {}x{}{}",
+                    make_size_string(fit.bytes), make_size_string(max_bytes), fit.quality,
+                    fit.width, fit.height, scale);
+            },
+            None => file_util::write_image_to_file(
+                output, &output_file_path, output_format, &opt.encoder_options(), Some(icc_profile))?
+        }
+    }
     let output_string = match file_util::size_of_file(&output_file_path) {
         Ok(size_bytes) => format!(
             "Created file: {:?}, {}, ({})",
@@ -1,7 +1,59 @@
-use crate::{Opt, OrderBy, TakeFrom};
+use crate::{encoder_options::{ChromaSubsampling, Dither}, Opt, OrderBy, OutputFormat, TakeFrom};
+use stitchy_core::LineAlignment;
+use stitchy_core::MajorAxis;
+use stitchy_core::{AnimationSync, OrientationMode, PixelFormat, ResizeFilter, WorkingSpace};
 use stitchy_core::ImageFiles;
 
 const TEST_JSON: &str = "{ \
+        \"horizontal\":true, \
+        \"vertical\":false, \
+        \"justified\":false, \
+        \"masonry\":false, \
+        \"slideshow\":false, \
+        \"rows\":null, \
+        \"cols\":null, \
+        \"major_axis\":null, \
+        \"aspect\":null, \
+        \"line_align\":null, \
+        \"spacing\":0, \
+        \"margin\":0, \
+        \"background\":null, \
+        \"maxd\":0, \
+        \"maxw\":120, \
+        \"maxh\":0, \
+        \"reverse\":false, \
+        \"take_from\":null, \
+        \"extensionless\":false, \
+        \"format\":\"jpeg\", \
+        \"fast\":false, \
+        \"linear\":false, \
+        \"filter\":null, \
+        \"sharpen\":null, \
+        \"pixel_format\":null, \
+        \"working_space\":null, \
+        \"orientation\":null, \
+        \"animation_sync\":null, \
+        \"delay\":null, \
+        \"crossfade\":null, \
+        \"loops\":null, \
+        \"small\":false, \
+        \"quality\":80, \
+        \"lossless\":false, \
+        \"max_bytes\":null, \
+        \"progressive\":false, \
+        \"subsampling\":null, \
+        \"optimise_huffman\":false, \
+        \"restart_interval\":null, \
+        \"colours\":null, \
+        \"dither\":null, \
+        \"order\":null, \
+        \"jobs\":1, \
+        \"input_dir\":null, \
+        \"output_dir\":null, \
+        \"number_of_files\":null \
+        }";
+
+const TEST_JSON_V4: &str = "{ \
         \"horizontal\":true, \
         \"vertical\":false, \
         \"maxd\":0, \
@@ -52,7 +104,7 @@ fn deserializes_okay() {
     let expected = Opt {
         horizontal: true,
         maxw: 120,
-        jpeg: true,
+        format: Some(OutputFormat::Jpeg),
         fast: false,
         small: false,
         quality: 80,
@@ -70,7 +122,7 @@ fn serializes_okay() {
     let from_opt = Opt {
         horizontal: true,
         maxw: 120,
-        jpeg: true,
+        format: Some(OutputFormat::Jpeg),
         fast: false,
         small: false,
         quality: 80,
@@ -108,6 +160,117 @@ fn choosing_both_directions_gives_error() {
     assert!(error.is_some());
 }
 
+#[test]
+fn choosing_justified_with_a_direction_gives_error() {
+    let error_1 = Opt {
+        horizontal: true,
+        justified: true,
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let error_2 = Opt {
+        vertical: true,
+        justified: true,
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let no_error = Opt {
+        justified: true,
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    assert!(error_1.is_some());
+    assert!(error_2.is_some());
+    assert!(no_error.is_none());
+}
+
+#[test]
+fn choosing_bad_aspect_ratio_gives_error() {
+    let error_1 = Opt {
+        aspect: Some(0.0),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let error_2 = Opt {
+        aspect: Some(1.5),
+        horizontal: true,
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let no_error = Opt {
+        aspect: Some(1.5),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    assert!(error_1.is_some());
+    assert!(error_2.is_some());
+    assert!(no_error.is_none());
+}
+
+#[test]
+fn choosing_masonry_with_columns_gives_no_error() {
+    let no_error = Opt {
+        masonry: true,
+        cols: Some(3),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let error_1 = Opt {
+        masonry: true,
+        rows: Some(3),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let error_2 = Opt {
+        masonry: true,
+        justified: true,
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    assert!(no_error.is_none());
+    assert!(error_1.is_some());
+    assert!(error_2.is_some());
+}
+
+#[test]
+fn choosing_slideshow_with_other_options_gives_error() {
+    let no_error = Opt {
+        slideshow: true,
+        delay: Some(500),
+        crossfade: Some(4),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let error_1 = Opt {
+        slideshow: true,
+        horizontal: true,
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let error_2 = Opt {
+        delay: Some(500),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let error_3 = Opt {
+        slideshow: true,
+        format: Some(OutputFormat::Jpeg),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let error_4 = Opt {
+        slideshow: true,
+        crossfade: Some(0),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    assert!(no_error.is_none());
+    assert!(error_1.is_some());
+    assert!(error_2.is_some());
+    assert!(error_3.is_some());
+    assert!(error_4.is_some());
+}
+
 #[test]
 fn choosing_neither_direction_gives_no_error() {
     let opt = make_test_default();
@@ -117,6 +280,66 @@ fn choosing_neither_direction_gives_no_error() {
     assert!(error.is_none());
 }
 
+#[test]
+fn choosing_grid_with_single_direction_gives_error() {
+    let error_1 = Opt {
+        horizontal: true,
+        cols: Some(4),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let error_2 = Opt {
+        vertical: true,
+        major_axis: Some(MajorAxis::Horizontal),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    assert!(error_1.is_some());
+    assert!(error_2.is_some());
+}
+
+#[test]
+fn choosing_line_alignment_with_justified_gives_error() {
+    let error = Opt {
+        justified: true,
+        line_align: Some(LineAlignment::Centre),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let no_error = Opt {
+        cols: Some(2),
+        line_align: Some(LineAlignment::Fill),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    assert!(error.is_some());
+    assert!(no_error.is_none());
+}
+
+#[test]
+fn choosing_zero_rows_or_columns_gives_error() {
+    let error_1 = Opt {
+        rows: Some(0),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let error_2 = Opt {
+        cols: Some(0),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let no_error = Opt {
+        rows: Some(2),
+        cols: Some(4),
+        major_axis: Some(MajorAxis::Vertical),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    assert!(error_1.is_some());
+    assert!(error_2.is_some());
+    assert!(no_error.is_none());
+}
+
 #[test]
 fn setting_general_and_specific_dimension_constraints_gives_error() {
     let error_1 = Opt {
@@ -167,45 +390,151 @@ fn setting_zero_dimension_constraints_gives_no_error() {
 }
 
 #[test]
-fn choosing_multiple_formats_gives_error() {
-    let error_1 = Opt {
-        jpeg: true,
-        png: true,
+fn choosing_margin_larger_than_limit_gives_error() {
+    let error = Opt {
+        maxw: 100,
+        margin: 50,
         ..make_test_default()
     }
     .check_for_basic_errors(&None);
-    let error_2 = Opt {
-        png: true,
-        gif: true,
+    let no_error = Opt {
+        maxw: 100,
+        margin: 49,
+        spacing: 10,
         ..make_test_default()
     }
     .check_for_basic_errors(&None);
-    let error_3 = Opt {
-        gif: true,
-        bmp: true,
+    assert!(error.is_some());
+    assert!(no_error.is_none());
+}
+
+#[test]
+fn parses_background_colours() {
+    let background = |text: &str| Opt {
+        background: Some(text.to_owned()),
+        ..make_test_default()
+    }.get_background();
+    assert_eq!(background("#ff8000").unwrap().0, [255, 128, 0, 255]);
+    assert_eq!(background("FF800080").unwrap().0, [255, 128, 0, 128]);
+    assert!(background("ff80").is_err());
+    assert!(background("gg8000").is_err());
+    assert_eq!(make_test_default().get_background().unwrap().0, [0, 0, 0, 0]);
+}
+
+#[test]
+fn choosing_linear_with_fast_gives_error() {
+    let error = Opt {
+        linear: true,
+        fast: true,
         ..make_test_default()
     }
     .check_for_basic_errors(&None);
-    let error_4 = Opt {
-        png: true,
-        webp: true,
+    let no_error = Opt {
+        linear: true,
         ..make_test_default()
     }
     .check_for_basic_errors(&None);
-    let error_5 = Opt {
-        jpeg: true,
-        png: true,
-        gif: true,
-        bmp: true,
-        webp: true,
+    assert!(error.is_some());
+    assert!(no_error.is_none());
+}
+
+#[test]
+fn choosing_filter_with_fast_gives_error() {
+    let error = Opt {
+        filter: Some(ResizeFilter::CatmullRom),
+        fast: true,
         ..make_test_default()
     }
     .check_for_basic_errors(&None);
-    assert!(error_1.is_some());
-    assert!(error_2.is_some());
-    assert!(error_3.is_some());
-    assert!(error_4.is_some());
-    assert!(error_5.is_some());
+    let nearest_linear_error = Opt {
+        filter: Some(ResizeFilter::Nearest),
+        linear: true,
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let sharpen_error = Opt {
+        sharpen: Some(0.0),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let no_error = Opt {
+        filter: Some(ResizeFilter::Gaussian),
+        sharpen: Some(0.5),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    assert!(error.is_some());
+    assert!(nearest_linear_error.is_some());
+    assert!(sharpen_error.is_some());
+    assert!(no_error.is_none());
+}
+
+#[test]
+fn choosing_high_bit_depth_needs_png() {
+    let error = Opt {
+        pixel_format: Some(PixelFormat::Rgba16),
+        format: Some(OutputFormat::Jpeg),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let no_error = Opt {
+        pixel_format: Some(PixelFormat::Luma16),
+        format: Some(OutputFormat::Png),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let eight_bit = Opt {
+        pixel_format: Some(PixelFormat::Rgba8),
+        format: Some(OutputFormat::WebP),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    assert!(error.is_some());
+    assert!(no_error.is_none());
+    assert!(eight_bit.is_none());
+}
+
+#[test]
+fn mixin_keeps_filter_choice_together() {
+    let fast = Opt { fast: true, ..Opt::default() };
+    let filtered = Opt {
+        filter: Some(ResizeFilter::Triangle),
+        sharpen: Some(1.0),
+        ..Opt::default()
+    };
+    let merged = fast.clone().mix_in(&filtered);
+    assert!(merged.fast);
+    assert_eq!(merged.filter, None);
+    assert_eq!(merged.sharpen, Some(1.0));
+    let merged = filtered.clone().mix_in(&fast);
+    assert!(!merged.fast);
+    assert_eq!(merged.filter, Some(ResizeFilter::Triangle));
+    let merged = Opt::default().mix_in(&filtered);
+    assert_eq!(merged.filter, Some(ResizeFilter::Triangle));
+}
+
+#[test]
+fn choosing_multiple_formats_gives_error() {
+    let parse = |args: &[&str]| {
+        let args = ["stitchy"].iter().chain(args).map(|arg| arg.to_string());
+        Opt::parse_with_format_flags(args)
+    };
+    assert!(parse(&["--jpeg", "--png", "3"]).is_err());
+    assert!(parse(&["--format=png", "--gif", "3"]).is_err());
+    assert!(parse(&["--format=tiff", "--format=qoi", "3"]).is_err());
+    assert!(parse(&["--format=heic", "3"]).is_err());
+}
+
+#[test]
+fn format_flags_are_aliases_for_format() {
+    let parse = |args: &[&str]| {
+        let args = ["stitchy"].iter().chain(args).map(|arg| arg.to_string());
+        Opt::parse_with_format_flags(args).unwrap().format
+    };
+    assert_eq!(parse(&["--webp", "3"]), Some(OutputFormat::WebP));
+    assert_eq!(parse(&["3", "--bmp"]), Some(OutputFormat::Bmp));
+    assert_eq!(parse(&["--format", "exr", "3"]), Some(OutputFormat::OpenExr));
+    assert_eq!(parse(&["3"]), None);
 }
 
 #[test]
@@ -213,62 +542,199 @@ fn choosing_no_format_gives_no_error() {
     let opt = Opt {
         ..make_test_default()
     };
-    assert_eq!(opt.jpeg, false);
-    assert_eq!(opt.png, false);
-    assert_eq!(opt.gif, false);
-    assert_eq!(opt.bmp, false);
-    assert_eq!(opt.webp, false);
+    assert!(opt.format.is_none());
     let error = opt.check_for_basic_errors(&None);
     assert!(error.is_none());
 }
 
 #[test]
-fn choosing_quality_for_non_jpeg_gives_error() {
-    let error_1 = Opt {
-        png: true,
-        quality: 50,
+fn choosing_quality_for_lossless_format_gives_error() {
+    for format in [OutputFormat::Png, OutputFormat::Gif, OutputFormat::Bmp, OutputFormat::Tiff,
+        OutputFormat::Qoi, OutputFormat::Pnm] {
+        let error = Opt {
+            format: Some(format),
+            quality: 50,
+            ..make_test_default()
+        }
+        .check_for_basic_errors(&None);
+        assert!(error.is_some(), "{:?}", format);
+    }
+}
+
+#[test]
+fn choosing_small_for_format_without_it_gives_error() {
+    let error = Opt {
+        format: Some(OutputFormat::Jpeg),
+        small: true,
         ..make_test_default()
     }
     .check_for_basic_errors(&None);
-    let error_2 = Opt {
-        gif: true,
+    let no_error = Opt {
+        format: Some(OutputFormat::Png),
+        small: true,
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    assert_eq!(
+        error,
+        Some("The small setting can only be used for PNG, GIF or AVIF output.".to_owned()));
+    assert!(no_error.is_none());
+}
+
+#[test]
+fn choosing_quality_for_jpeg_gives_no_error() {
+    let error = Opt {
+        format: Some(OutputFormat::Jpeg),
         quality: 50,
         ..make_test_default()
     }
     .check_for_basic_errors(&None);
-    let error_3 = Opt {
-        bmp: true,
+    assert!(error.is_none());
+}
+
+#[test]
+fn choosing_lossless_only_for_webp_without_quality() {
+    let no_error_1 = Opt {
+        format: Some(OutputFormat::WebP),
         quality: 50,
         ..make_test_default()
     }
     .check_for_basic_errors(&None);
-    let error_4 = Opt {
-        webp: true,
+    let no_error_2 = Opt {
+        format: Some(OutputFormat::WebP),
+        lossless: true,
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let error_1 = Opt {
+        format: Some(OutputFormat::WebP),
         quality: 50,
+        lossless: true,
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let error_2 = Opt {
+        format: Some(OutputFormat::Jpeg),
+        lossless: true,
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    assert!(no_error_1.is_none());
+    assert!(no_error_2.is_none());
+    assert!(error_1.is_some());
+    assert_eq!(
+        error_2,
+        Some("Lossless output can only be chosen for WebP output.".to_owned()));
+}
+
+#[test]
+fn choosing_jpeg_options_for_other_format_gives_error() {
+    let no_error = Opt {
+        format: Some(OutputFormat::Jpeg),
+        progressive: true,
+        subsampling: Some(ChromaSubsampling::Full),
+        optimise_huffman: true,
+        restart_interval: Some(4),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let error_1 = Opt {
+        format: Some(OutputFormat::Png),
+        subsampling: Some(ChromaSubsampling::Full),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let error_2 = Opt {
+        format: Some(OutputFormat::Jpeg),
+        restart_interval: Some(0),
         ..make_test_default()
     }
     .check_for_basic_errors(&None);
+    assert!(no_error.is_none());
     assert!(error_1.is_some());
     assert!(error_2.is_some());
-    assert!(error_3.is_some());
-    assert!(error_4.is_some());
 }
 
 #[test]
-fn choosing_quality_for_jpeg_gives_no_error() {
-    let error = Opt {
-        jpeg: true,
-        quality: 50,
+fn choosing_palette_only_for_png_and_gif() {
+    let no_error = Opt {
+        format: Some(OutputFormat::Gif),
+        colours: Some(16),
+        dither: Some(Dither::Ordered),
         ..make_test_default()
     }
     .check_for_basic_errors(&None);
-    assert!(error.is_none());
+    let errors = [
+        Opt {
+            format: Some(OutputFormat::Jpeg),
+            colours: Some(16),
+            ..make_test_default()
+        },
+        Opt {
+            colours: Some(300),
+            ..make_test_default()
+        },
+        Opt {
+            dither: Some(Dither::None),
+            ..make_test_default()
+        },
+        Opt {
+            slideshow: true,
+            colours: Some(16),
+            ..make_test_default()
+        },
+    ];
+    assert!(no_error.is_none());
+    for opt in errors {
+        assert!(opt.check_for_basic_errors(&None).is_some(), "{:?}", opt);
+    }
+}
+
+#[test]
+fn choosing_max_bytes_only_for_jpeg_and_webp() {
+    let no_error = Opt {
+        format: Some(OutputFormat::WebP),
+        max_bytes: Some(100_000),
+        ..make_test_default()
+    }
+    .check_for_basic_errors(&None);
+    let errors = [
+        Opt {
+            format: Some(OutputFormat::Png),
+            max_bytes: Some(100_000),
+            ..make_test_default()
+        },
+        Opt {
+            max_bytes: Some(0),
+            ..make_test_default()
+        },
+        Opt {
+            format: Some(OutputFormat::Jpeg),
+            quality: 50,
+            max_bytes: Some(100_000),
+            ..make_test_default()
+        },
+        Opt {
+            lossless: true,
+            max_bytes: Some(100_000),
+            ..make_test_default()
+        },
+        Opt {
+            slideshow: true,
+            max_bytes: Some(100_000),
+            ..make_test_default()
+        },
+    ];
+    assert!(no_error.is_none());
+    for opt in errors {
+        assert!(opt.check_for_basic_errors(&None).is_some(), "{:?}", opt);
+    }
 }
 
 #[test]
 fn choosing_silly_quality_gives_error() {
     let error = Opt {
-        jpeg: true,
+        format: Some(OutputFormat::Jpeg),
         quality: 250,
         ..make_test_default()
     }
@@ -282,7 +748,7 @@ fn base_options_favoured_in_classes() {
     let base = Opt {
         horizontal: true,
         maxw: 540,
-        png: true,
+        format: Some(OutputFormat::Png),
         order: Some(OrderBy::Latest),
         take_from: Some(TakeFrom::End),
         ..Opt::default()
@@ -290,7 +756,7 @@ fn base_options_favoured_in_classes() {
     let mixer = Opt {
         vertical: true,
         maxd: 540,
-        gif: true,
+        format: Some(OutputFormat::Gif),
         order: Some(OrderBy::Alphabetic),
         take_from: Some(TakeFrom::Start),
         ..Opt::default()
@@ -301,11 +767,7 @@ fn base_options_favoured_in_classes() {
     assert_eq!(merged.maxd, 0);
     assert_eq!(merged.maxw, 540);
     assert_eq!(merged.maxh, 0);
-    assert_eq!(merged.jpeg, false);
-    assert_eq!(merged.png, true);
-    assert_eq!(merged.gif, false);
-    assert_eq!(merged.bmp, false);
-    assert_eq!(merged.webp, false);
+    assert_eq!(merged.format, Some(OutputFormat::Png));
     assert_eq!(merged.order, Some(OrderBy::Latest));
     assert_eq!(merged.take_from, Some(TakeFrom::End));
 }
@@ -315,23 +777,75 @@ fn mixin_preserves_mixer_booleans() {
     let mixer = Opt {
         horizontal: true,
         vertical: true,
+        justified: true,
+        masonry: true,
         reverse: true,
-        jpeg: true,
-        png: true,
-        gif: true,
-        bmp: true,
-        webp: true,
         ..Opt::default()
     };
     let merged = Opt::default().mix_in(&mixer);
     assert!(merged.horizontal);
     assert!(merged.vertical);
+    assert!(merged.justified);
+    assert!(merged.masonry);
     assert!(merged.reverse);
-    assert!(merged.jpeg);
-    assert!(merged.png);
-    assert!(merged.gif);
-    assert!(merged.bmp);
-    assert!(merged.webp);
+}
+
+#[test]
+fn mixin_keeps_layout_options_together() {
+    let defaults = Opt {
+        cols: Some(4),
+        ..Opt::default()
+    };
+    let merged = Opt {
+        horizontal: true,
+        ..Opt::default()
+    }
+    .mix_in(&defaults);
+    assert!(merged.horizontal);
+    assert_eq!(merged.cols, None);
+
+    let defaults = Opt {
+        vertical: true,
+        ..Opt::default()
+    };
+    let merged = Opt {
+        rows: Some(2),
+        ..Opt::default()
+    }
+    .mix_in(&defaults);
+    assert!(!merged.vertical);
+    assert_eq!(merged.rows, Some(2));
+
+    let merged = Opt::default().mix_in(&Opt {
+        cols: Some(3),
+        major_axis: Some(MajorAxis::Horizontal),
+        ..Opt::default()
+    });
+    assert_eq!(merged.cols, Some(3));
+    assert_eq!(merged.major_axis, Some(MajorAxis::Horizontal));
+}
+
+#[test]
+fn mixin_keeps_slideshow_options_together() {
+    let defaults = Opt {
+        slideshow: true,
+        delay: Some(2000),
+        loops: Some(3),
+        ..Opt::default()
+    };
+    let merged = Opt::default().mix_in(&defaults);
+    assert!(merged.slideshow);
+    assert_eq!(merged.delay, Some(2000));
+    assert_eq!(merged.loops, Some(3));
+
+    let merged = Opt {
+        vertical: true,
+        ..Opt::default()
+    }
+    .mix_in(&defaults);
+    assert!(!merged.slideshow);
+    assert_eq!(merged.delay, None);
+    assert_eq!(merged.loops, Some(3));
 }
 
 #[test]
@@ -339,23 +853,17 @@ fn mixin_preserves_original_booleans() {
     let base = Opt {
         horizontal: true,
         vertical: true,
+        justified: true,
+        masonry: true,
         reverse: true,
-        jpeg: true,
-        png: true,
-        gif: true,
-        bmp: true,
-        webp: true,
         ..Opt::default()
     };
     let merged = base.mix_in(&Opt::default());
     assert!(merged.horizontal);
     assert!(merged.vertical);
+    assert!(merged.justified);
+    assert!(merged.masonry);
     assert!(merged.reverse);
-    assert!(merged.jpeg);
-    assert!(merged.png);
-    assert!(merged.gif);
-    assert!(merged.bmp);
-    assert!(merged.webp);
 }
 
 #[test]
@@ -365,6 +873,7 @@ fn mixin_preserves_mixer_integers() {
         maxw: 200,
         maxh: 50,
         quality: 80,
+        jobs: 2,
         ..Opt::default()
     };
     let merged = Opt::default().mix_in(&mixer);
@@ -372,6 +881,7 @@ fn mixin_preserves_mixer_integers() {
     assert_eq!(merged.maxw, 200);
     assert_eq!(merged.maxh, 50);
     assert_eq!(merged.quality, 80);
+    assert_eq!(merged.jobs, 2);
 }
 
 #[test]
@@ -427,17 +937,96 @@ fn mixin_preserves_non_default_quality() {
     assert_eq!(merged.quality, 40);
 }
 
+#[test]
+fn mixin_keeps_max_bytes_and_quality_apart() {
+    let merged = Opt {
+        max_bytes: Some(100_000),
+        ..Opt::default()
+    }
+    .mix_in(&Opt {
+        quality: 40,
+        ..Opt::default()
+    });
+    assert_eq!(merged.max_bytes, Some(100_000));
+    assert_eq!(merged.quality, super::DEFAULT_QUALITY);
+
+    let size_defaults = Opt {
+        max_bytes: Some(100_000),
+        ..Opt::default()
+    };
+    let merged = Opt {
+        quality: 40,
+        ..Opt::default()
+    }
+    .mix_in(&size_defaults);
+    assert_eq!(merged.max_bytes, None);
+    assert_eq!(merged.quality, 40);
+
+    let merged = Opt {
+        format: Some(OutputFormat::Png),
+        ..Opt::default()
+    }
+    .mix_in(&size_defaults);
+    assert_eq!(merged.max_bytes, None);
+}
+
+#[test]
+fn mixin_keeps_lossless_and_quality_apart() {
+    let merged = Opt {
+        lossless: true,
+        ..Opt::default()
+    }
+    .mix_in(&Opt {
+        quality: 40,
+        ..Opt::default()
+    });
+    assert!(merged.lossless);
+    assert_eq!(merged.quality, super::DEFAULT_QUALITY);
+
+    let lossless_defaults = Opt {
+        format: Some(OutputFormat::WebP),
+        lossless: true,
+        ..Opt::default()
+    };
+    let merged = Opt {
+        quality: 40,
+        ..Opt::default()
+    }
+    .mix_in(&lossless_defaults);
+    assert!(!merged.lossless);
+    assert_eq!(merged.quality, 40);
+
+    let merged = Opt {
+        format: Some(OutputFormat::Png),
+        ..Opt::default()
+    }
+    .mix_in(&lossless_defaults);
+    assert!(!merged.lossless);
+}
+
 #[test]
 fn mixin_preserves_mixer_enums() {
     let base = Opt::default();
     let mixer = Opt {
         take_from: Some(TakeFrom::End),
         order: Some(OrderBy::Alphabetic),
+        line_align: Some(LineAlignment::Centre),
+        working_space: Some(WorkingSpace::DisplayP3),
+        orientation: Some(OrientationMode::Ignore),
+        animation_sync: Some(AnimationSync::Hold),
+        subsampling: Some(ChromaSubsampling::HalfWidth),
+        format: Some(OutputFormat::Tiff),
         ..Opt::default()
     };
     let merged = base.mix_in(&mixer);
     assert_eq!(merged.take_from, Some(TakeFrom::End));
     assert_eq!(merged.order, Some(OrderBy::Alphabetic));
+    assert_eq!(merged.line_align, Some(LineAlignment::Centre));
+    assert_eq!(merged.working_space, Some(WorkingSpace::DisplayP3));
+    assert_eq!(merged.orientation, Some(OrientationMode::Ignore));
+    assert_eq!(merged.animation_sync, Some(AnimationSync::Hold));
+    assert_eq!(merged.subsampling, Some(ChromaSubsampling::HalfWidth));
+    assert_eq!(merged.format, Some(OutputFormat::Tiff));
 }
 
 #[test]
@@ -517,6 +1106,29 @@ fn v1_options_does_deserialise() {
     assert!(options.is_ok());
 }
 
+#[test]
+fn v4_options_migrate_format_flags() {
+    assert!(Opt::deserialise(TEST_JSON_V4).is_err());
+    let options = Opt::deserialise_as_current(TEST_JSON_V4).unwrap();
+    assert_eq!(options.format, Some(OutputFormat::Jpeg));
+    assert_eq!(options.quality, 80);
+    assert_eq!(options.maxw, 120);
+    assert!(options.horizontal);
+    assert!(!options.justified && !options.linear);
+    assert_eq!((options.spacing, options.jobs), (0, 1));
+}
+
+#[test]
+fn options_without_grid_does_deserialise() {
+    let test_str = "{\"horizontal\":false,\"vertical\":false,\"maxd\":0,\"maxw\":0,\"maxh\":0\
+        ,\"reverse\":false,\"take_from\":null,\"jpeg\":false,\"png\":true,\"gif\":false\
+        ,\"bmp\":false,\"webp\":false,\"fast\":false,\"small\":false,\"quality\":100\
+        ,\"order\":null,\"input_dir\":null,\"output_dir\":null,\"number_of_files\":null}";
+    let options = Opt::deserialise_as_current(test_str).unwrap();
+    assert_eq!(options.format, Some(OutputFormat::Png));
+    assert_eq!(options.get_grid_spec(), stitchy_core::GridSpec::default());
+}
+
 #[test]
 fn mixin_quality_ignored_for_png_override() {
     // Clear existing file
@@ -542,13 +1154,13 @@ fn mixin_quality_ignored_for_png_override() {
     let retrieved_files = retrieve_files_result.unwrap();
     let loaded_defaults = Opt {
         number_of_files: Some(1),
-        jpeg: true,
+        format: Some(OutputFormat::Jpeg),
         quality: 50,
         ..Opt::default()
     };
     let options = Opt {
         number_of_files: Some(retrieved_files.file_count()),
-        png: true,
+        format: Some(OutputFormat::Png),
         ..Opt::default()
     }
     .mix_in(&loaded_defaults);
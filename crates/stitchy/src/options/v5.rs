@@ -0,0 +1,875 @@
+
+use crate::{
+    encoder_options::{ChromaSubsampling, Dither, EncoderOptions, JpegOptions, PaletteOptions},
+    options::{OptV1, OptV2, OptV3, OptV4},
+    file_util::to_absolute_dir,
+    OutputFormat,
+};
+use stitchy_core::{
+    image::Rgba, AlignmentMode, AnimationSync, GridSpec, LineAlignment, MajorAxis, PixelFormat,
+    OrientationMode, ResizeFilter, TakeFrom, OrderBy, WorkingSpace,
+};
+use clap::Parser;
+use serde::{Serialize, Deserialize};
+
+pub const DEFAULT_QUALITY: usize = 100;
+
+/// Threads used to load and resize images unless more are asked for, as each holds a source
+/// image in memory
+pub const DEFAULT_JOBS: usize = 1;
+
+/// Flags for choosing the output format from before it was chosen with `--format`, which are
+/// still accepted on the command line
+const FORMAT_FLAGS: [(&str, &str); 5] = [
+    ("--jpeg", "--format=jpeg"),
+    ("--png", "--format=png"),
+    ("--gif", "--format=gif"),
+    ("--bmp", "--format=bmp"),
+    ("--webp", "--format=webp"),
+];
+
+#[derive(Debug, Clone, Parser, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Opt {
+
+    #[arg(long)]
+    #[serde(skip_serializing, default)]
+    pub help: bool,
+
+    #[arg(long)]
+    #[serde(skip_serializing, default)]
+    pub version: bool,
+
+    #[arg(long)]
+    #[serde(skip_serializing, default)]
+    pub printdefaults: bool,
+
+    #[arg(short, long)]
+    pub horizontal: bool,
+
+    #[arg(short, long)]
+    pub vertical: bool,
+
+    #[arg(short, long)]
+    #[serde(default)]
+    pub justified: bool,
+
+    #[arg(short, long)]
+    #[serde(default)]
+    pub masonry: bool,
+
+    #[arg(long)]
+    #[serde(default)]
+    pub slideshow: bool,
+
+    #[arg(long)]
+    pub rows: Option<usize>,
+
+    #[arg(long)]
+    pub cols: Option<usize>,
+
+    #[arg(long = "major-axis")]
+    pub major_axis: Option<MajorAxis>,
+
+    #[arg(long)]
+    pub aspect: Option<f64>,
+
+    #[arg(long = "line-align")]
+    pub line_align: Option<LineAlignment>,
+
+    #[arg(long, default_value="0")]
+    #[serde(default)]
+    pub spacing: usize,
+
+    #[arg(long, default_value="0")]
+    #[serde(default)]
+    pub margin: usize,
+
+    #[arg(long)]
+    pub background: Option<String>,
+
+    #[arg(long, default_value="0")]
+    pub maxd: usize,
+
+    #[arg(long, default_value="0")]
+    pub maxw: usize,
+
+    #[arg(long, default_value="0")]
+    pub maxh: usize,
+
+    #[arg(short, long)]
+    pub reverse: bool,
+
+    #[arg(long = "take-from")]
+    pub take_from: Option<TakeFrom>,
+
+    #[arg(long)]
+    #[serde(default)]
+    pub extensionless: bool,
+
+    #[arg(long)]
+    pub format: Option<OutputFormat>,
+
+    #[arg(long, short)]
+    pub fast: bool,
+
+    #[arg(long)]
+    #[serde(default)]
+    pub linear: bool,
+
+    #[arg(long)]
+    pub filter: Option<ResizeFilter>,
+
+    #[arg(long)]
+    pub sharpen: Option<f32>,
+
+    #[arg(long = "pixel-format")]
+    pub pixel_format: Option<PixelFormat>,
+
+    #[arg(long = "working-space")]
+    pub working_space: Option<WorkingSpace>,
+
+    #[arg(long)]
+    pub orientation: Option<OrientationMode>,
+
+    #[arg(long = "animation-sync")]
+    pub animation_sync: Option<AnimationSync>,
+
+    #[arg(long)]
+    pub delay: Option<u32>,
+
+    #[arg(long)]
+    pub crossfade: Option<u32>,
+
+    #[arg(long)]
+    pub loops: Option<u16>,
+
+    #[arg(long, short)]
+    pub small: bool,
+
+    #[arg(long, short, default_value="100")]
+    pub quality: usize,
+
+    #[arg(long)]
+    #[serde(default)]
+    pub lossless: bool,
+
+    #[arg(long = "max-bytes")]
+    pub max_bytes: Option<u64>,
+
+    #[arg(long)]
+    #[serde(default)]
+    pub progressive: bool,
+
+    #[arg(long)]
+    pub subsampling: Option<ChromaSubsampling>,
+
+    #[arg(long = "optimise-huffman")]
+    #[serde(default)]
+    pub optimise_huffman: bool,
+
+    #[arg(long = "restart-interval")]
+    pub restart_interval: Option<u16>,
+
+    #[arg(long)]
+    pub colours: Option<u16>,
+
+    #[arg(long)]
+    pub dither: Option<Dither>,
+
+    #[arg(long)]
+    pub order: Option<OrderBy>,
+
+    #[arg(long, default_value="1")]
+    #[serde(default = "default_jobs")]
+    pub jobs: usize,
+
+    #[arg(short, long = "input-dir")]
+    pub input_dir: Option<String>,
+
+    #[arg(short, long = "output-dir")]
+    pub output_dir: Option<String>,
+
+    #[arg(required_unless_present_any =
+    &["help", "version", "setdefaults", "updatedefaults", "cleardefaults", "printdefaults"])]
+    pub number_of_files: Option<usize>,
+
+    #[arg(long)]
+    #[serde(skip_serializing, default)]
+    pub setdefaults: bool,
+
+    #[arg(long)]
+    #[serde(skip_serializing, default)]
+    pub updatedefaults: bool,
+
+    #[arg(long)]
+    #[serde(skip_serializing, default)]
+    pub cleardefaults: bool
+}
+
+fn default_jobs() -> usize {
+    DEFAULT_JOBS
+}
+
+impl Default for Opt {
+    fn default() -> Self {
+        Opt {
+            help: false,
+            version: false,
+            printdefaults: false,
+            horizontal: false,
+            vertical: false,
+            justified: false,
+            masonry: false,
+            slideshow: false,
+            rows: None,
+            cols: None,
+            major_axis: None,
+            aspect: None,
+            line_align: None,
+            spacing: 0,
+            margin: 0,
+            background: None,
+            maxd: 0,
+            maxw: 0,
+            maxh: 0,
+            reverse: false,
+            take_from: None,
+            extensionless: false,
+            format: None,
+            fast: false,
+            linear: false,
+            filter: None,
+            sharpen: None,
+            pixel_format: None,
+            working_space: None,
+            orientation: None,
+            animation_sync: None,
+            delay: None,
+            crossfade: None,
+            loops: None,
+            small: false,
+            quality: DEFAULT_QUALITY,
+            lossless: false,
+            max_bytes: None,
+            progressive: false,
+            subsampling: None,
+            optimise_huffman: false,
+            restart_interval: None,
+            colours: None,
+            dither: None,
+            order: None,
+            jobs: DEFAULT_JOBS,
+            input_dir: None,
+            output_dir: None,
+            number_of_files: None,
+            setdefaults: false,
+            updatedefaults: false,
+            cleardefaults: false
+        }
+    }
+}
+
+impl Opt {
+
+    /// Parse the command line arguments, accepting the older per-format flags such as `--png`
+    /// in place of `--format`
+    pub fn parse_with_format_flags<I: IntoIterator<Item = String>>(
+        args: I
+    ) -> Result<Opt, clap::Error> {
+        let args = args.into_iter().map(|arg| {
+            match FORMAT_FLAGS.iter().find(|(flag, _)| *flag == arg) {
+                Some((_, replacement)) => replacement.to_string(),
+                None => arg
+            }
+        });
+        Opt::try_parse_from(args)
+    }
+
+    #[inline]
+    fn supports_quality(&self) -> bool {
+        self.format.is_some_and(OutputFormat::supports_quality)
+    }
+
+    #[inline]
+    fn supports_small_mode(&self) -> bool {
+        self.format.is_some_and(OutputFormat::supports_small_mode)
+    }
+
+    #[inline]
+    fn supports_size_target(&self) -> bool {
+        self.format.is_some_and(OutputFormat::supports_size_target)
+    }
+
+    #[inline]
+    fn supports_palette(&self) -> bool {
+        self.format.is_some_and(OutputFormat::supports_palette)
+    }
+
+    #[inline]
+    fn supports_jpeg_options(&self) -> bool {
+        self.format == Some(OutputFormat::Jpeg)
+    }
+
+    #[inline]
+    fn has_jpeg_options(&self) -> bool {
+        self.progressive || self.subsampling.is_some() || self.optimise_huffman
+            || self.restart_interval.is_some()
+    }
+
+    /// Collect the settings for writing the output file
+    pub fn encoder_options(&self) -> EncoderOptions {
+        EncoderOptions {
+            quality: self.quality,
+            encode_smallest: self.small,
+            jpeg: JpegOptions {
+                progressive: self.progressive,
+                subsampling: self.subsampling,
+                optimise_huffman: self.optimise_huffman,
+                restart_interval: self.restart_interval,
+            },
+            palette: self.colours.map(|colours| PaletteOptions {
+                colours: colours as usize,
+                dither: self.dither.unwrap_or_default(),
+            }),
+        }
+    }
+
+    pub fn deserialise(json: &str) -> Result<Opt, String> {
+        serde_json::from_str(json)
+            .map_err(|e| format!("Error deserialising settings: {:?}", e))
+    }
+
+    pub fn deserialise_as_current(json: &str) -> Result<Opt, String> {
+
+        // Try latest version
+        if let Ok(opt) = Opt::deserialise(json) {
+            return Ok(opt);
+        };
+
+        // Try v4
+        if let Ok(opt) = OptV4::deserialise(json) {
+            return Ok(opt.into());
+        }
+
+        // Try v3
+        if let Ok(opt) = OptV3::deserialise(json) {
+            return Ok(opt.into());
+        }
+
+        // Try v2
+        if let Ok(opt) = OptV2::deserialise(json) {
+            return Ok(opt.into());
+        }
+
+        // Try v1
+        OptV1::deserialise(json).map(|opt| opt.into())
+    }
+
+    pub fn check_for_basic_errors(&self, previous_options: &Option<Opt>) -> Option<String> {
+
+        // Verify directories are actually directories
+        if let Some(dir) = &self.input_dir {
+            if let Err(e) = to_absolute_dir(dir) {
+                return Some(e);
+            }
+        }
+        if let Some(dir) = &self.output_dir {
+            if let Err(e) = to_absolute_dir(dir) {
+                return Some(e);
+            }
+        }
+
+        // Verify not requesting more than one of the fixed layouts
+        let layout_flag_set: [bool; 5] =
+            [self.horizontal, self.vertical, self.justified, self.masonry, self.slideshow];
+        let layout_flag_count: usize = layout_flag_set.iter()
+            .map(|&f| { if f { 1 } else { 0 } })
+            .sum();
+        if layout_flag_count > 1 {
+            return Some("Choose one of horizontal, vertical, justified, masonry, or slideshow (or none), not several.".to_owned());
+        }
+
+        // Verify grid options are sensible, and not mixed with a single-line layout
+        if self.rows == Some(0) || self.cols == Some(0) {
+            return Some("The number of rows or columns must be at least 1.".to_owned());
+        }
+        if let Some(aspect) = self.aspect {
+            if !aspect.is_finite() || aspect <= 0.0 {
+                return Some("The aspect ratio must be a positive number.".to_owned());
+            }
+        }
+        let masonry_grid_options = self.masonry && self.rows.is_none()
+            && self.major_axis.is_none() && self.aspect.is_none();
+        if self.has_grid_options() && layout_flag_count > 0 && !masonry_grid_options {
+            return Some("Grid options (rows, cols, major-axis, aspect) are only for the grid layout, though cols can also be used with masonry.".to_owned());
+        }
+
+        // Verify line alignment is only used where images are laid out in lines of a grid
+        if self.line_align.is_some() && (self.justified || self.masonry || self.slideshow) {
+            return Some("Line alignment cannot be used with the justified, masonry, or slideshow layouts.".to_owned());
+        }
+
+        // Verify slideshow timing is only given for slideshows, and that it is usable
+        if !self.slideshow && (self.delay.is_some() || self.crossfade.is_some()) {
+            return Some("The delay and crossfade options can only be used with a slideshow.".to_owned());
+        }
+        if self.delay == Some(0) {
+            return Some("The slide delay must be at least 1 millisecond.".to_owned());
+        }
+        if self.crossfade == Some(0) {
+            return Some("The number of crossfade frames must be at least 1.".to_owned());
+        }
+
+        // Verify linear resampling is not combined with copying pixels without a filter
+        if self.linear && self.fast {
+            return Some("Linear resampling cannot be used with the fast option.".to_owned());
+        }
+        if self.linear && self.filter == Some(ResizeFilter::Nearest) {
+            return Some("Linear resampling cannot be used with the nearest filter.".to_owned());
+        }
+
+        // Verify the resize filter is chosen only once, and that sharpening is usable
+        if self.fast && self.filter.is_some() {
+            return Some("The fast option cannot be used with a resize filter.".to_owned());
+        }
+        if let Some(sharpen) = self.sharpen {
+            if !sharpen.is_finite() || sharpen <= 0.0 {
+                return Some("The sharpening amount must be a positive number.".to_owned());
+            }
+        }
+
+        // Verify the background is a valid colour, and that margins leave room for the images
+        if let Err(e) = self.get_background() {
+            return Some(e);
+        }
+        let margins = self.margin.saturating_mul(2);
+        let limits = [self.maxd, self.maxw, self.maxh];
+        if limits.iter().any(|&limit| limit > 0 && limit <= margins) {
+            return Some("The margin must leave room for images inside the maximum dimensions.".to_owned());
+        }
+
+        // Verify not requesting overlapping constraints
+        if self.maxd > 0 && self.maxw > 0 {
+            return Some("If using maxd, do not specify maxw as well.".to_owned());
+        }
+        if self.maxd > 0 && self.maxh > 0 {
+            return Some("If using maxd, do not specify maxh as well.".to_owned());
+        }
+
+        // Verify the format was included in this build
+        if let Some(format) = self.format {
+            if !format.is_available() {
+                return Some(format!(
+                    "Output format {} is not available; build with the \"{}\" feature to use it.",
+                    format.name(), format.extension()));
+            }
+        }
+
+        // Verify slideshows are written in a format that can hold an animation
+        if let Some(format) = self.format {
+            if self.slideshow && !format.supports_animation() {
+                return Some(format!(
+                    "A slideshow can only be written as {} output.",
+                    OutputFormat::names_where(OutputFormat::supports_animation)));
+            }
+        }
+
+        // Verify quality setting is within the appropriate range, and is only used for lossy
+        // formats. Be careful that a quality setting loaded from settings is ignored when
+        // changing format.
+        if self.quality == 0 || self.quality > 100 {
+            return Some("The quality setting must be in the range of 1 to 100 inclusive.".to_owned());
+        }
+        let targeting_quality = self.quality != 100 && self.format.is_some();
+        let defaults_support_quality = match previous_options {
+            Some(options) => options.supports_quality(),
+            None => false
+        };
+        if targeting_quality && !self.supports_quality() && !defaults_support_quality {
+            return Some(format!(
+                "The quality setting can only be used for {} output.",
+                OutputFormat::names_where(OutputFormat::supports_quality)));
+        }
+
+        // Verify lossless output is only chosen for formats that can also be lossy, and not
+        // together with a quality setting
+        if self.lossless && self.quality != DEFAULT_QUALITY {
+            return Some("Choose either a quality setting or lossless output, not both.".to_owned());
+        }
+        let targeting_lossless = self.lossless && self.format.is_some();
+        if targeting_lossless && !self.format.is_some_and(OutputFormat::supports_lossless_choice) {
+            return Some(format!(
+                "Lossless output can only be chosen for {} output.",
+                OutputFormat::names_where(OutputFormat::supports_lossless_choice)));
+        }
+
+        // Verify a maximum file size is only used where the quality can be searched for
+        if let Some(max_bytes) = self.max_bytes {
+            if max_bytes == 0 {
+                return Some("The maximum file size must be at least 1 byte.".to_owned());
+            }
+            if self.quality != DEFAULT_QUALITY || self.lossless {
+                return Some("Choose either a quality setting or a maximum file size, not both.".to_owned());
+            }
+            if self.slideshow {
+                return Some("A maximum file size cannot be used with a slideshow.".to_owned());
+            }
+        }
+        let targeting_size = self.max_bytes.is_some() && self.format.is_some();
+        let defaults_support_size_target = match previous_options {
+            Some(options) => options.supports_size_target(),
+            None => false
+        };
+        if targeting_size && !self.supports_size_target() && !defaults_support_size_target {
+            return Some(format!(
+                "A maximum file size can only be used for {} output.",
+                OutputFormat::names_where(OutputFormat::supports_size_target)));
+        }
+
+        // Verify JPEG encoder settings are only used for JPEG output
+        if self.restart_interval == Some(0) {
+            return Some("The restart interval must be at least 1.".to_owned());
+        }
+        let targeting_jpeg_options = self.has_jpeg_options() && self.format.is_some();
+        let defaults_support_jpeg_options = match previous_options {
+            Some(options) => options.supports_jpeg_options(),
+            None => false
+        };
+        if targeting_jpeg_options && !self.supports_jpeg_options() && !defaults_support_jpeg_options {
+            return Some("The progressive, subsampling, optimise-huffman, and restart-interval options \
+                can only be used for JPEG output.".to_owned());
+        }
+
+        // Verify a palette has a usable number of colours, and is only used for still images in
+        // formats that can store one
+        if let Some(colours) = self.colours {
+            if !(2..=256).contains(&colours) {
+                return Some("The number of colours must be from 2 to 256.".to_owned());
+            }
+        }
+        let defaults_have_palette = match previous_options {
+            Some(options) => options.colours.is_some(),
+            None => false
+        };
+        if self.dither.is_some() && self.colours.is_none() && !defaults_have_palette {
+            return Some("Dithering can only be used together with the colours option.".to_owned());
+        }
+        if self.colours.is_some() && self.slideshow {
+            return Some("The colours option cannot be used with a slideshow.".to_owned());
+        }
+        let targeting_palette = self.colours.is_some() && self.format.is_some();
+        let defaults_support_palette = match previous_options {
+            Some(options) => options.supports_palette(),
+            None => false
+        };
+        if targeting_palette && !self.supports_palette() && !defaults_support_palette {
+            return Some(format!(
+                "The colours option can only be used for {} output.",
+                OutputFormat::names_where(OutputFormat::supports_palette)));
+        }
+
+        // Verify small mode is only used for formats supporting it
+        let targeting_small_mode = self.small && self.format.is_some();
+        let defaults_support_small_mode = match previous_options {
+            Some(options) => options.supports_small_mode(),
+            None => false
+        };
+        if targeting_small_mode && !self.supports_small_mode() && !defaults_support_small_mode {
+            return Some(format!(
+                "The small setting can only be used for {} output.",
+                OutputFormat::names_where(OutputFormat::supports_small_mode)));
+        }
+
+        // Verify more than 8 bits per channel are only requested for formats that can store them
+        if let Some(format) = self.format {
+            if let Some(e) = self.check_pixel_format_supported(format) {
+                return Some(e);
+            }
+        }
+
+        None
+    }
+
+    /// Check that an output format can store the requested pixel format, if one was requested
+    /// with more than 8 bits per channel
+    pub fn check_pixel_format_supported(&self, format: OutputFormat) -> Option<String> {
+        let high_bit_depth = !matches!(
+            self.pixel_format, None | Some(PixelFormat::Auto) | Some(PixelFormat::Rgba8));
+        if high_bit_depth && !format.supports_high_bit_depth() {
+            return Some(format!(
+                "Output format {} cannot store more than 8 bits per channel; use {} instead.",
+                format.name(), OutputFormat::names_where(OutputFormat::supports_high_bit_depth)));
+        }
+        None
+    }
+
+    pub fn check_number_of_files_provided(&self) -> Option<&'static str> {
+
+        // Verify a sensible number was given
+        let number_of_files = match self.number_of_files {
+            Some(num) => num,
+            None => return Some("You did not provide number_of_files and StructOpt did not catch this error")
+        };
+        if number_of_files == 0 {
+            return Some("The number of images to stitch must be at least 1.");
+        }
+
+        None
+    }
+
+    pub fn prepare_for_use(&mut self) {
+        if self.maxd > 0 {
+            self.maxw = self.maxd;
+            self.maxh = self.maxd;
+        }
+    }
+
+    #[inline]
+    fn has_grid_options(&self) -> bool {
+        self.rows.is_some() || self.cols.is_some() || self.major_axis.is_some()
+            || self.aspect.is_some()
+    }
+
+    pub fn get_grid_spec(&self) -> GridSpec {
+        GridSpec {
+            rows: self.rows,
+            columns: self.cols,
+            major_axis: self.major_axis,
+        }
+    }
+
+    /// Parse the background colour, given as hex digits RRGGBB or RRGGBBAA with an optional
+    /// leading '#'. Transparent if not given.
+    pub fn get_background(&self) -> Result<Rgba<u8>, String> {
+        let text = match &self.background {
+            Some(text) => text.trim_start_matches('#'),
+            None => return Ok(Rgba([0, 0, 0, 0])),
+        };
+        let error = || format!(
+            "The background colour must be given as RRGGBB or RRGGBBAA in hex, got \"{}\".",
+            text);
+        if (text.len() != 6 && text.len() != 8) || !text.is_ascii() {
+            return Err(error());
+        }
+        let mut channels = [0, 0, 0, 255];
+        for (i, channel) in channels.iter_mut().enumerate().take(text.len() / 2) {
+            *channel = u8::from_str_radix(&text[(i * 2)..(i * 2 + 2)], 16)
+                .map_err(|_| error())?;
+        }
+        Ok(Rgba(channels))
+    }
+
+    pub fn get_alignment(&self) -> AlignmentMode {
+        match (self.horizontal, self.vertical, self.justified, self.masonry) {
+            (true, false, false, false) => AlignmentMode::Horizontal,
+            (false, true, false, false) => AlignmentMode::Vertical,
+            (false, false, true, false) => AlignmentMode::Justified,
+            (false, false, false, true) => AlignmentMode::Masonry,
+            _ => AlignmentMode::Grid
+        }
+    }
+
+    pub fn serialise(&self) -> Option<String> {
+        let result = serde_json::to_string(self);
+        match result {
+            Ok(s) => Some(s),
+            Err(e) => {
+                println!("Error serialising settings: {:?}", e);
+                None
+            }
+        }
+    }
+
+    /// Sets the options included in the other instance
+    pub fn mix_in(self, other: &Opt) -> Opt {
+        let number_of_files = match (self.number_of_files, other.number_of_files) {
+            (Some(i), None) => Some(i),
+            (None, Some(i)) => Some(i),
+            (Some(i), Some(_)) => Some(i),
+            _ => None
+        };
+        let take_from = match (self.take_from, other.take_from) {
+            (None, that) => that,
+            (this, _) => this
+        };
+        let base_has_layout = self.horizontal || self.vertical || self.justified || self.masonry
+            || self.slideshow || self.has_grid_options();
+        let slideshow = self.slideshow || (other.slideshow && !base_has_layout);
+        let base_constrains_dimensions = self.maxd != 0 || self.maxw != 0 || self.maxh != 0;
+        let order = match (self.order, other.order) {
+            (None, that) => that,
+            (this, _) => this
+        };
+        let line_align = match (self.line_align, other.line_align) {
+            (None, that) => that,
+            (this, _) => this
+        };
+        let input_dir = match (&self.input_dir, &other.input_dir) {
+            (None, Some(that)) => Some(that.clone()),
+            (Some(this), _) => Some(this.clone()),
+            _ => None
+        };
+        let output_dir = match (&self.output_dir, &other.output_dir) {
+            (None, Some(that)) => Some(that.clone()),
+            (Some(this), _) => Some(this.clone()),
+            _ => None
+        };
+        Opt {
+            help: self.help,
+            version: self.version,
+            printdefaults: self.printdefaults,
+            horizontal: self.horizontal || (other.horizontal && !base_has_layout),
+            vertical: self.vertical || (other.vertical && !base_has_layout),
+            justified: self.justified || (other.justified && !base_has_layout),
+            masonry: self.masonry || (other.masonry && !base_has_layout),
+            slideshow,
+            rows: if base_has_layout { self.rows } else { other.rows },
+            cols: if base_has_layout { self.cols } else { other.cols },
+            major_axis: if base_has_layout { self.major_axis } else { other.major_axis },
+            aspect: if base_has_layout { self.aspect } else { other.aspect },
+            line_align,
+            spacing: if self.spacing != 0 { self.spacing } else { other.spacing },
+            margin: if self.margin != 0 { self.margin } else { other.margin },
+            background: self.background.clone().or_else(|| other.background.clone()),
+            maxd: if base_constrains_dimensions { self.maxd } else { other.maxd },
+            maxw: if base_constrains_dimensions { self.maxw } else { other.maxw },
+            maxh: if base_constrains_dimensions { self.maxh } else { other.maxh },
+            reverse: self.reverse || other.reverse,
+            take_from,
+            extensionless: self.extensionless || other.extensionless,
+            format: self.format.or(other.format),
+            fast: self.fast || (other.fast && self.filter.is_none()),
+            linear: self.linear || other.linear,
+            filter: if self.fast { None } else { self.filter.or(other.filter) },
+            sharpen: self.sharpen.or(other.sharpen),
+            pixel_format: self.pixel_format.or(other.pixel_format),
+            working_space: self.working_space.or(other.working_space),
+            orientation: self.orientation.or(other.orientation),
+            animation_sync: self.animation_sync.or(other.animation_sync),
+            delay: if slideshow { self.delay.or(other.delay) } else { self.delay },
+            crossfade: if slideshow { self.crossfade.or(other.crossfade) } else { self.crossfade },
+            loops: self.loops.or(other.loops),
+            small: self.small || other.small,
+            quality: match self.quality != DEFAULT_QUALITY || self.lossless || self.max_bytes.is_some() {
+                true => self.quality,
+                false => other.quality
+            },
+            lossless: self.lossless || (other.lossless && self.quality == DEFAULT_QUALITY
+                && self.max_bytes.is_none()
+                && self.format.is_none_or(OutputFormat::supports_lossless_choice)),
+            max_bytes: match self.quality != DEFAULT_QUALITY || self.lossless
+                || self.format.is_some_and(|format| !format.supports_size_target()) {
+                true => self.max_bytes,
+                false => self.max_bytes.or(other.max_bytes)
+            },
+            progressive: self.progressive || other.progressive,
+            subsampling: self.subsampling.or(other.subsampling),
+            optimise_huffman: self.optimise_huffman || other.optimise_huffman,
+            restart_interval: self.restart_interval.or(other.restart_interval),
+            colours: self.colours.or(other.colours),
+            dither: self.dither.or(other.dither),
+            order,
+            jobs: if self.jobs != DEFAULT_JOBS { self.jobs } else { other.jobs },
+            input_dir,
+            output_dir,
+            number_of_files,
+            setdefaults: self.setdefaults,
+            updatedefaults: self.updatedefaults,
+            cleardefaults: self.cleardefaults
+        }
+    }
+}
+
+impl From<OptV4> for Opt {
+    fn from(value: OptV4) -> Self {
+
+        // Replace the flag for each format with the format itself
+        let format = [
+            (value.jpeg, OutputFormat::Jpeg),
+            (value.png, OutputFormat::Png),
+            (value.gif, OutputFormat::Gif),
+            (value.bmp, OutputFormat::Bmp),
+            (value.webp, OutputFormat::WebP),
+        ]
+            .into_iter()
+            .find_map(|(flag, format)| flag.then_some(format));
+
+        // Return new type
+        Opt {
+            help: value.help,
+            version: value.version,
+            printdefaults: value.printdefaults,
+            horizontal: value.horizontal,
+            vertical: value.vertical,
+            justified: false,
+            masonry: false,
+            slideshow: false,
+            rows: None,
+            cols: None,
+            major_axis: None,
+            aspect: None,
+            line_align: None,
+            spacing: 0,
+            margin: 0,
+            background: None,
+            maxd: value.maxd,
+            maxw: value.maxw,
+            maxh: value.maxh,
+            reverse: value.reverse,
+            take_from: value.take_from,
+            extensionless: false,
+            format,
+            fast: value.fast,
+            linear: false,
+            filter: None,
+            sharpen: None,
+            pixel_format: None,
+            working_space: None,
+            orientation: None,
+            animation_sync: None,
+            delay: None,
+            crossfade: None,
+            loops: None,
+            small: value.small,
+            quality: value.quality,
+            lossless: false,
+            max_bytes: None,
+            progressive: false,
+            subsampling: None,
+            optimise_huffman: false,
+            restart_interval: None,
+            colours: None,
+            dither: None,
+            order: value.order,
+            jobs: DEFAULT_JOBS,
+            input_dir: value.input_dir,
+            output_dir: value.output_dir,
+            number_of_files: value.number_of_files,
+            setdefaults: value.setdefaults,
+            updatedefaults: value.updatedefaults,
+            cleardefaults: value.cleardefaults,
+        }
+    }
+}
+
+impl From<OptV3> for Opt {
+    fn from(value: OptV3) -> Self {
+        let value: OptV4 = value.into();
+        value.into()
+    }
+}
+
+impl From<OptV2> for Opt {
+    fn from(value: OptV2) -> Self {
+        let value: OptV3 = value.into();
+        value.into()
+    }
+}
+
+impl From<OptV1> for Opt {
+    fn from(value: OptV1) -> Self {
+        let value: OptV2 = value.into();
+        value.into()
+    }
+}
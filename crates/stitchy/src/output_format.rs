@@ -0,0 +1,171 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use stitchy_core::image::ImageFormat;
+
+/// Formats the output image can be written in.
+///
+/// Formats other than JPEG, PNG, GIF, BMP, and WebP need the cargo feature of the same name,
+/// which are all enabled by default. Each format declares which of the encoding options apply
+/// to it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    Jpeg,
+    Png,
+    Gif,
+    Bmp,
+    #[value(name = "webp")]
+    WebP,
+    Tiff,
+    Qoi,
+    Avif,
+    Pnm,
+    Ico,
+    #[value(name = "exr")]
+    #[serde(rename = "exr")]
+    OpenExr,
+}
+
+impl OutputFormat {
+
+    /// The format used when none is requested and the sources do not share one
+    pub const DEFAULT: OutputFormat = OutputFormat::Jpeg;
+
+    pub fn image_format(self) -> ImageFormat {
+        match self {
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Gif => ImageFormat::Gif,
+            OutputFormat::Bmp => ImageFormat::Bmp,
+            OutputFormat::WebP => ImageFormat::WebP,
+            OutputFormat::Tiff => ImageFormat::Tiff,
+            OutputFormat::Qoi => ImageFormat::Qoi,
+            OutputFormat::Avif => ImageFormat::Avif,
+            OutputFormat::Pnm => ImageFormat::Pnm,
+            OutputFormat::Ico => ImageFormat::Ico,
+            OutputFormat::OpenExr => ImageFormat::OpenExr,
+        }
+    }
+
+    /// Find the output format for an image format, if output can be written in it
+    pub fn from_image_format(format: ImageFormat) -> Option<OutputFormat> {
+        OutputFormat::value_variants()
+            .iter()
+            .copied()
+            .find(|output_format| output_format.image_format() == format)
+    }
+
+    /// Name of the format as written in messages
+    pub fn name(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "JPEG",
+            OutputFormat::Png => "PNG",
+            OutputFormat::Gif => "GIF",
+            OutputFormat::Bmp => "BMP",
+            OutputFormat::WebP => "WebP",
+            OutputFormat::Tiff => "TIFF",
+            OutputFormat::Qoi => "QOI",
+            OutputFormat::Avif => "AVIF",
+            OutputFormat::Pnm => "PNM",
+            OutputFormat::Ico => "ICO",
+            OutputFormat::OpenExr => "OpenEXR",
+        }
+    }
+
+    /// Extension given to output files in this format
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::Gif => "gif",
+            OutputFormat::Bmp => "bmp",
+            OutputFormat::WebP => "webp",
+            OutputFormat::Tiff => "tiff",
+            OutputFormat::Qoi => "qoi",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Pnm => "pnm",
+            OutputFormat::Ico => "ico",
+            OutputFormat::OpenExr => "exr",
+        }
+    }
+
+    /// Check whether the encoder for this format was included in this build
+    pub fn is_available(self) -> bool {
+        match self {
+            OutputFormat::Jpeg | OutputFormat::Png | OutputFormat::Gif | OutputFormat::Bmp
+                | OutputFormat::WebP => true,
+            OutputFormat::Tiff => cfg!(feature = "tiff"),
+            OutputFormat::Qoi => cfg!(feature = "qoi"),
+            OutputFormat::Avif => cfg!(feature = "avif"),
+            OutputFormat::Pnm => cfg!(feature = "pnm"),
+            OutputFormat::Ico => cfg!(feature = "ico"),
+            OutputFormat::OpenExr => cfg!(feature = "exr"),
+        }
+    }
+
+    /// Check whether the format is lossy, taking a quality setting
+    pub fn supports_quality(self) -> bool {
+        matches!(self, OutputFormat::Jpeg | OutputFormat::WebP | OutputFormat::Avif)
+    }
+
+    /// Check whether the format can be written either lossily or losslessly, being lossless at
+    /// the default quality of 100
+    pub fn supports_lossless_choice(self) -> bool {
+        matches!(self, OutputFormat::WebP)
+    }
+
+    /// Check whether the format can trade encoding speed for a smaller file
+    pub fn supports_small_mode(self) -> bool {
+        matches!(self, OutputFormat::Png | OutputFormat::Gif | OutputFormat::Avif)
+    }
+
+    /// Check whether the output can be fitted within a file size by searching for a quality
+    pub fn supports_size_target(self) -> bool {
+        matches!(self, OutputFormat::Jpeg | OutputFormat::WebP)
+    }
+
+    /// Check whether the format can be written with a palette of at most 256 colours
+    pub fn supports_palette(self) -> bool {
+        matches!(self, OutputFormat::Png | OutputFormat::Gif)
+    }
+
+    /// Check whether the format can store an animation, which for PNG is written as APNG
+    pub fn supports_animation(self) -> bool {
+        matches!(self, OutputFormat::Png | OutputFormat::Gif | OutputFormat::WebP)
+    }
+
+    /// Check whether the format can store more than 8 bits per channel
+    pub fn supports_high_bit_depth(self) -> bool {
+        matches!(self, OutputFormat::Png | OutputFormat::Tiff | OutputFormat::Pnm
+            | OutputFormat::OpenExr)
+    }
+
+    /// Check whether the format can store an embedded colour profile
+    pub fn supports_icc_profile(self) -> bool {
+        matches!(self, OutputFormat::Jpeg | OutputFormat::Png | OutputFormat::WebP)
+    }
+
+    /// The largest width or height an image can have in this format, if limited
+    pub fn max_dimension(self) -> Option<u32> {
+        match self {
+            OutputFormat::Ico => Some(256),
+            OutputFormat::WebP => Some(16383),
+            _ => None,
+        }
+    }
+
+    /// Names of every format with some property, for messages
+    pub fn names_where(property: fn(OutputFormat) -> bool) -> String {
+        let names: Vec<&str> = OutputFormat::value_variants()
+            .iter()
+            .copied()
+            .filter(|&format| property(format))
+            .map(OutputFormat::name)
+            .collect();
+        match names.split_last() {
+            Some((last, [])) => last.to_string(),
+            Some((last, rest)) => format!("{} or {}", rest.join(", "), last),
+            None => String::new(),
+        }
+    }
+}
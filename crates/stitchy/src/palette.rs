@@ -0,0 +1,303 @@
+use crate::encoder_options::Dither;
+use stitchy_core::image::RgbaImage;
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+
+/// Colour used for every fully transparent pixel, which gets a palette entry of its own
+const TRANSPARENT: [u8; 4] = [0, 0, 0, 0];
+
+/// Threshold map for ordered dithering, giving the order in which pixels of an 8x8 block are
+/// pushed towards the next palette colour
+const BAYER_MATRIX: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// Map from colours, packed into a single number, to some value
+type ColourMap<V> = HashMap<u32, V, BuildHasherDefault<ColourHasher>>;
+
+/// Hashes packed colours by multiplying them by a large odd number, which is much faster than
+/// the default hasher and spreads neighbouring colours well enough for a map of colours
+#[derive(Default)]
+struct ColourHasher(u64);
+
+impl Hasher for ColourHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0.rotate_left(8) ^ byte as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        }
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.0 = (value as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    }
+}
+
+/// An image reduced to a palette of RGBA colours, with one palette index per pixel
+pub struct IndexedImage {
+    pub width: u32,
+    pub height: u32,
+    pub palette: Vec<[u8; 4]>,
+    pub indices: Vec<u8>,
+}
+
+/// A group of colours from the image, each with the number of pixels using it, which median cut
+/// splits in two until there is one group per palette entry
+struct ColourBox {
+    colours: Vec<([u8; 4], u32)>,
+    pixel_count: u64,
+
+    /// The channel whose values are most spread out, and how far they spread
+    widest_channel: (usize, u8),
+}
+
+impl ColourBox {
+    fn new(colours: Vec<([u8; 4], u32)>) -> Self {
+        let pixel_count = colours.iter().map(|&(_, count)| count as u64).sum();
+        let mut min = [u8::MAX; 4];
+        let mut max = [u8::MIN; 4];
+        for (colour, _) in colours.iter() {
+            for channel in 0..4 {
+                min[channel] = min[channel].min(colour[channel]);
+                max[channel] = max[channel].max(colour[channel]);
+            }
+        }
+        let widest_channel = (0..4)
+            .map(|channel| (channel, max[channel].saturating_sub(min[channel])))
+            .max_by_key(|&(_, range)| range)
+            .unwrap_or((0, 0));
+        Self { colours, pixel_count, widest_channel }
+    }
+
+    /// Split along the widest channel, so that each half is used by about as many pixels
+    fn split(mut self) -> (ColourBox, ColourBox) {
+        let (channel, _) = self.widest_channel;
+        self.colours.sort_unstable_by_key(|(colour, _)| colour[channel]);
+        let mut remaining = self.pixel_count / 2;
+        let mut split_index = 0;
+        for (index, &(_, count)) in self.colours.iter().enumerate() {
+            split_index = index;
+            if remaining <= count as u64 {
+                break;
+            }
+            remaining -= count as u64;
+        }
+        let split_index = split_index.clamp(1, self.colours.len() - 1);
+        let upper = self.colours.split_off(split_index);
+        (ColourBox::new(self.colours), ColourBox::new(upper))
+    }
+
+    /// The average colour of the pixels in this group
+    fn average(&self) -> [u8; 4] {
+        let mut sums = [0u64; 4];
+        for &(colour, count) in self.colours.iter() {
+            for (sum, value) in sums.iter_mut().zip(colour) {
+                *sum += value as u64 * count as u64;
+            }
+        }
+        let pixel_count = self.pixel_count.max(1);
+        sums.map(|sum| ((sum + pixel_count / 2) / pixel_count) as u8)
+    }
+}
+
+impl IndexedImage {
+
+    /// Reduce an image to a palette of at most the given number of colours, chosen by median
+    /// cut. Images already using few enough colours keep them exactly, without dithering.
+    pub fn quantise(image: &RgbaImage, max_colours: usize, dither: Dither) -> IndexedImage {
+        let (width, height) = image.dimensions();
+        let pixels: Vec<[u8; 4]> = image
+            .pixels()
+            .map(|pixel| if pixel[3] == 0 { TRANSPARENT } else { pixel.0 })
+            .collect();
+
+        // Count the pixels using each colour, leaving out fully transparent ones
+        let mut colour_counts: ColourMap<u32> = ColourMap::default();
+        let mut has_transparency = false;
+        for &pixel in pixels.iter() {
+            match pixel == TRANSPARENT {
+                true => has_transparency = true,
+                false => *colour_counts.entry(u32::from_ne_bytes(pixel)).or_insert(0) += 1,
+            }
+        }
+        let mut palette = match has_transparency {
+            true => vec![TRANSPARENT],
+            false => vec![],
+        };
+        let colour_limit = max_colours.saturating_sub(palette.len()).max(1);
+
+        // Keep the colours as they are if there are few enough, otherwise find new ones
+        if colour_counts.len() <= colour_limit {
+            let mut colours: Vec<[u8; 4]> = colour_counts
+                .into_keys()
+                .map(u32::to_ne_bytes)
+                .collect();
+            colours.sort_unstable();
+            palette.extend(colours);
+            let palette_indices: ColourMap<u8> = palette
+                .iter()
+                .enumerate()
+                .map(|(index, &colour)| (u32::from_ne_bytes(colour), index as u8))
+                .collect();
+            let indices = pixels
+                .iter()
+                .map(|&pixel| palette_indices[&u32::from_ne_bytes(pixel)])
+                .collect();
+            return IndexedImage { width, height, palette, indices };
+        }
+        let colour_counts = colour_counts
+            .into_iter()
+            .map(|(colour, count)| (colour.to_ne_bytes(), count))
+            .collect();
+        let mut boxes = vec![ColourBox::new(colour_counts)];
+        while boxes.len() < colour_limit {
+            let splittable = boxes
+                .iter()
+                .enumerate()
+                .filter(|(_, colour_box)| colour_box.colours.len() > 1)
+                .max_by_key(|(_, colour_box)| {
+                    colour_box.widest_channel.1 as u64 * colour_box.pixel_count
+                })
+                .map(|(index, _)| index);
+            let Some(index) = splittable else {
+                break;
+            };
+            let (lower, upper) = boxes.swap_remove(index).split();
+            boxes.push(lower);
+            boxes.push(upper);
+        }
+        palette.extend(boxes.iter().map(ColourBox::average));
+
+        let mut matcher = PaletteMatcher::new(&palette, has_transparency);
+        let indices = match dither {
+            Dither::None => pixels.iter().map(|&pixel| matcher.index_of(pixel)).collect(),
+            Dither::FloydSteinberg => floyd_steinberg(&pixels, width as usize, &mut matcher),
+            Dither::Ordered => {
+                let spread = 255.0 / (palette.len() as f32).cbrt();
+                pixels
+                    .iter()
+                    .enumerate()
+                    .map(|(index, &pixel)| {
+                        let (x, y) = (index % width as usize, index / width as usize);
+                        let threshold = (BAYER_MATRIX[y % 8][x % 8] as f32 + 0.5) / 64.0 - 0.5;
+                        let adjusted = adjust_colour(pixel, [threshold * spread; 3]);
+                        matcher.index_of(adjusted)
+                    })
+                    .collect()
+            },
+        };
+        IndexedImage { width, height, palette, indices }
+    }
+}
+
+/// Diffuse the difference between each pixel and its palette colour into the pixels to its
+/// right and below it
+fn floyd_steinberg(pixels: &[[u8; 4]], width: usize, matcher: &mut PaletteMatcher) -> Vec<u8> {
+    let mut current_errors = vec![[0f32; 3]; width + 2];
+    let mut next_errors = vec![[0f32; 3]; width + 2];
+    let mut indices = Vec::with_capacity(pixels.len());
+    for row in pixels.chunks(width.max(1)) {
+        for (x, &pixel) in row.iter().enumerate() {
+            let adjusted = adjust_colour(pixel, current_errors[x + 1]);
+            let index = matcher.index_of(adjusted);
+            indices.push(index);
+            if pixel == TRANSPARENT {
+                continue;
+            }
+            let chosen = matcher.palette[index as usize];
+            for channel in 0..3 {
+                let error = adjusted[channel] as f32 - chosen[channel] as f32;
+                current_errors[x + 2][channel] += error * 7.0 / 16.0;
+                next_errors[x][channel] += error * 3.0 / 16.0;
+                next_errors[x + 1][channel] += error * 5.0 / 16.0;
+                next_errors[x + 2][channel] += error / 16.0;
+            }
+        }
+        std::mem::swap(&mut current_errors, &mut next_errors);
+        next_errors.fill([0.0; 3]);
+    }
+    indices
+}
+
+/// Shift the colour channels of a pixel, leaving transparent pixels and alpha unchanged
+fn adjust_colour(pixel: [u8; 4], offsets: [f32; 3]) -> [u8; 4] {
+    if pixel == TRANSPARENT {
+        return pixel;
+    }
+    let mut adjusted = pixel;
+    for (value, offset) in adjusted.iter_mut().zip(offsets) {
+        *value = (*value as f32 + offset).round().clamp(0.0, 255.0) as u8;
+    }
+    adjusted
+}
+
+/// Finds the nearest palette colour to each pixel, remembering the answer for colours already
+/// seen. Palette colours are searched in order of green value outwards from the pixel's, which
+/// stops once the difference in green alone is further than the nearest colour found so far.
+struct PaletteMatcher<'a> {
+    palette: &'a [[u8; 4]],
+    by_green: Vec<usize>,
+    has_transparency: bool,
+    found: ColourMap<u8>,
+}
+
+impl<'a> PaletteMatcher<'a> {
+    fn new(palette: &'a [[u8; 4]], has_transparency: bool) -> Self {
+        let first_colour = if has_transparency { 1 } else { 0 };
+        let mut by_green: Vec<usize> = (first_colour..palette.len()).collect();
+        by_green.sort_unstable_by_key(|&index| palette[index][1]);
+        Self { palette, by_green, has_transparency, found: ColourMap::default() }
+    }
+
+    fn index_of(&mut self, pixel: [u8; 4]) -> u8 {
+        if pixel == TRANSPARENT && self.has_transparency {
+            return 0;
+        }
+        let palette = self.palette;
+        let by_green = &self.by_green;
+        *self.found.entry(u32::from_ne_bytes(pixel)).or_insert_with(|| {
+            let distance = |colour: &[u8; 4]| -> u32 {
+                colour.iter()
+                    .zip(pixel)
+                    .map(|(&a, b)| (a as i32 - b as i32).pow(2) as u32)
+                    .sum()
+            };
+            let green_distance = |index: usize| {
+                (palette[index][1] as i32 - pixel[1] as i32).pow(2) as u32
+            };
+            let mut nearest = (0, u32::MAX);
+            let mut upper = by_green.partition_point(|&index| palette[index][1] < pixel[1]);
+            let mut lower = upper;
+            loop {
+                let below = lower.checked_sub(1)
+                    .map(|position| by_green[position])
+                    .filter(|&index| green_distance(index) < nearest.1);
+                let above = by_green.get(upper)
+                    .copied()
+                    .filter(|&index| green_distance(index) < nearest.1);
+                if below.is_none() && above.is_none() {
+                    break;
+                }
+                for index in [below, above].into_iter().flatten() {
+                    let distance = distance(&palette[index]);
+                    if distance < nearest.1 {
+                        nearest = (index, distance);
+                    }
+                }
+                lower -= below.is_some() as usize;
+                upper += above.is_some() as usize;
+            }
+            nearest.0 as u8
+        })
+    }
+}
@@ -98,18 +98,55 @@ pub fn help() {
         "  --output-dir=_, -o=_ Specify a directory to write files to".to_owned(),
         "  --horizontal, -h     Force stitching across a single row only".to_owned(),
         "  --vertical, -v       Force stitching down a single column only".to_owned(),
+        "  --justified, -j      Stitch rows that are each scaled to the full output width, with varying row heights".to_owned(),
+        "  --masonry, -m        Stitch columns of equal width, adding each image to the shortest column".to_owned(),
+        "  --slideshow          Show the images one after another as an animation (GIF, WebP, or PNG), each fitted to a common size".to_owned(),
+        "  --delay=n            Show each slide of a slideshow for n milliseconds (default: 1000)".to_owned(),
+        "  --crossfade=n        Blend each slide of a slideshow into the next over n frames of 50 milliseconds".to_owned(),
+        "  --loops=n            Play animated output n times (default: 0, repeating forever)".to_owned(),
+        "  --rows=n             Arrange the grid into n rows".to_owned(),
+        "  --cols=n             Arrange the grid (or masonry layout) into n columns".to_owned(),
+        "  --major-axis=_       Fill the grid along this axis first".to_owned(),
+        "                       Allowed values: 'horizontal', 'vertical'".to_owned(),
+        "  --aspect=n           Prefer grid arrangements where output width divided by height is close to n (default: 1)".to_owned(),
+        "  --line-align=_       Position images in lines shorter than the longest (grid, horizontal, or vertical)".to_owned(),
+        "                       Allowed values: 'start', 'centre', 'end', 'spread', 'fill'".to_owned(),
+        "  --spacing=n          Leave a gap of n pixels between neighbouring images".to_owned(),
+        "  --margin=n           Leave a border of n pixels around the output".to_owned(),
+        "  --background=_       Fill gaps and empty space with this colour, in hex RRGGBB or RRGGBBAA (default: transparent)".to_owned(),
         "  --maxw=n             Limit output width to n pixels at most".to_owned(),
         "  --maxh=n             Limit output height to n pixels at most".to_owned(),
         "  --maxd=n             Limit output width and height to n pixels at most".to_owned(),
+        "  --jobs=n             Load and resize images using n threads (default: 1; 0 uses one per CPU core)".to_owned(),
+        "  --orientation=_      Turn images the right way up using their EXIF metadata ('auto', the default), ignore it, or apply a fixed rotation or flip".to_owned(),
+        "                       Allowed values: 'auto', 'ignore', 'rotate90', 'rotate180', 'rotate270', 'flip-horizontal', 'flip-vertical'".to_owned(),
+        "  --extensionless      Also use files without an extension if their contents are a known image format".to_owned(),
+        "  --animation-sync=_   Loop animations shorter than the longest source ('loop', the default), or play them once and hold the last frame ('hold')".to_owned(),
         "  --reverse, -r        Reverse order of files, after sorting and taking from start or end".to_owned(),
         "  --fast, -f           Copy source pixels into the output as quickly as possible without a filtering algorithm (results may have sharp artifacts)".to_owned(),
-        "  --small, -s          Encode the image as small as possible (applies to PNG and GIF only)".to_owned(),
-        "  --quality=n, -q=n    Set the output quality from 1 to 100, defaulting to 100; applies to JPEG only".to_owned(),
-        "  --jpeg               Output as JPEG".to_owned(),
-        "  --png                Output as PNG".to_owned(),
-        "  --gif                Output as GIF".to_owned(),
-        "  --bmp                Output as BMP".to_owned(),
-        "  --webp               Output as WebP (lossless only)".to_owned(),
+        "  --filter=_           Resize images using this filter (default: 'lanczos3')".to_owned(),
+        "                       Allowed values: 'nearest', 'triangle', 'catmull-rom', 'gaussian', 'lanczos3'".to_owned(),
+        "  --sharpen=n          Sharpen each image after resizing, using an unsharp mask with a blur radius of n pixels".to_owned(),
+        "  --pixel-format=_     Set the pixel type of the output, following the sources by default (16-bit and float need PNG, TIFF, PNM, or OpenEXR output)".to_owned(),
+        "                       Allowed values: 'auto', 'rgba8', 'rgba16', 'rgb16', 'luma16', 'rgba32f'".to_owned(),
+        "  --working-space=_    Convert images into this colour space using their embedded profiles, and embed its profile in the output (default: 'srgb')".to_owned(),
+        "                       Allowed values: 'srgb', 'display-p3', 'adobe-rgb', 'bt2020', 'pro-photo-rgb'".to_owned(),
+        "  --linear             Resize images in linear light with premultiplied alpha, keeping fine detail and transparent edges clean (slower)".to_owned(),
+        "  --small, -s          Encode the image as small as possible (applies to PNG, GIF, and AVIF only)".to_owned(),
+        "  --quality=n, -q=n    Set the output quality from 1 to 100, defaulting to 100; applies to JPEG, WebP, and AVIF only".to_owned(),
+        "  --colours=n          Reduce PNG and GIF output to a palette of at most n colours (2 to 256), written as an indexed image".to_owned(),
+        "  --dither=_           Set how colours missing from the palette are approximated (default: 'floyd-steinberg')".to_owned(),
+        "                       Allowed values: 'none', 'floyd-steinberg', 'ordered'".to_owned(),
+        "  --progressive        Write JPEG output progressively, in passes of increasing detail".to_owned(),
+        "  --subsampling=_      Set the JPEG chroma subsampling to 444, 422, or 420 (the default)".to_owned(),
+        "                       Allowed values: '444' (sharpest colour, best for screenshots), '422', '420' (smallest, suits photos)".to_owned(),
+        "  --optimise-huffman   Build Huffman tables for each JPEG output, for a slightly smaller file (slower)".to_owned(),
+        "  --restart-interval=n Add a restart marker to JPEG output every n blocks, so damaged files can be partly recovered".to_owned(),
+        "  --lossless           Write WebP output losslessly, ignoring any saved quality setting; this is the default at quality 100".to_owned(),
+        "  --max-bytes=n        Write JPEG or WebP output at the highest quality that fits in n bytes, scaling it down if it still does not at quality 40".to_owned(),
+        "  --format=_           Set the output format; PNM output has no transparency, ICO output is limited to 256 pixels in each direction, and WebP to 16383".to_owned(),
+        "                       Allowed values: 'jpeg', 'png', 'gif', 'bmp', 'webp', 'tiff', 'qoi', 'avif', 'pnm', 'ico', 'exr'".to_owned(),
+        "                       The flags --jpeg, --png, --gif, --bmp, and --webp are also accepted in place of this option".to_owned(),
         "                       Note: default format matches sources, or JPEG if source formats vary".to_owned(),
     ]);
     printer.print_with_wrap_indent(
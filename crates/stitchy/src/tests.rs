@@ -1,8 +1,8 @@
-use crate::Opt;
+use crate::{encoder_options::EncoderOptions, Opt, OutputFormat};
+use clap::ValueEnum;
 use stitchy_core::{
-    extension_formats,
-    image::{FilterType, ImageFormat},
-    FilePathWithMetadata, ImageFiles, OrderBy, Stitch, TakeFrom,
+    image::{DynamicImage, FilterType}, FileLocation, FilePath, FileProperties, ImageFiles, OrderBy, Stitch,
+    TakeFrom, WorkingSpace,
 };
 
 fn clear_output() -> Result<(), String> {
@@ -20,8 +20,8 @@ fn clear_output() -> Result<(), String> {
 
 #[test]
 pub fn test_output_formats() {
-    // Per allowed extension, infer the type enum and generate an output
-    for &extension in ImageFiles::<FilePathWithMetadata>::allowed_extensions().iter() {
+    // Per available output format, generate an output and read it back
+    for &format in OutputFormat::value_variants().iter().filter(|format| format.is_available()) {
         // Clear existing file
         let clear_result = clear_output();
         assert!(
@@ -44,45 +44,10 @@ pub fn test_output_formats() {
         // Build options set matching the image format under test
         let retrieved_files = retrieve_files_result.unwrap();
         let all_files_count = retrieved_files.file_count();
-        let mut format: Option<ImageFormat> = None;
-        for &(ext, fmt) in extension_formats().iter() {
-            if ext == extension {
-                format = Some(fmt);
-            }
-        }
-        let options = match format {
-            Some(ImageFormat::Jpeg) => Opt {
-                number_of_files: Some(all_files_count),
-                jpeg: true,
-                ..Opt::default()
-            },
-            Some(ImageFormat::Png) => Opt {
-                number_of_files: Some(all_files_count),
-                png: true,
-                ..Opt::default()
-            },
-            Some(ImageFormat::Bmp) => Opt {
-                number_of_files: Some(all_files_count),
-                bmp: true,
-                ..Opt::default()
-            },
-            Some(ImageFormat::WebP) => Opt {
-                number_of_files: Some(all_files_count),
-                webp: true,
-                ..Opt::default()
-            },
-            Some(ImageFormat::Gif) => Opt {
-                number_of_files: Some(all_files_count),
-                gif: true,
-                ..Opt::default()
-            },
-            Some(inner_format) => {
-                panic!("Unknown format: {:?}", inner_format)
-            }
-            None => Opt {
-                number_of_files: Some(all_files_count),
-                ..Opt::default()
-            },
+        let options = Opt {
+            number_of_files: Some(all_files_count),
+            format: Some(format),
+            ..Opt::default()
         };
 
         // Process input files
@@ -95,20 +60,383 @@ pub fn test_output_formats() {
             )
             .unwrap();
         let output_path = crate::file_util::next_available_output(&sources, &options).unwrap();
+        assert_eq!(
+            output_path.extension().and_then(|extension| extension.to_str()),
+            Some(format.extension())
+        );
         let image_files = sources.into_image_contents(false).unwrap();
 
-        // Perform stitch on inputs
+        // Perform stitch on inputs, keeping within the size limit of the format
+        let size_limit = format.max_dimension().unwrap_or(0);
         let stitch = Stitch::builder()
             .resize_filter(FilterType::Nearest)
+            .width_limit(size_limit)
+            .height_limit(size_limit)
             .images(image_files)
             .stitch()
             .unwrap();
-        let process_result =
-            crate::file_util::write_image_to_file(stitch, &output_path, format, 100, false);
+        let (width, height) = (stitch.width(), stitch.height());
+        let icc_profile = WorkingSpace::Srgb.icc_profile().unwrap();
+        let process_result = crate::file_util::write_image_to_file(
+            stitch, &output_path, format, &options.encoder_options(), Some(icc_profile.clone()));
         assert!(
             process_result.is_ok(),
             "{}",
             process_result.err().unwrap_or(String::new())
         );
+
+        // Formats that cannot be read back are only checked for their file signature
+        if format == OutputFormat::Avif {
+            let contents = std::fs::read(&output_path).unwrap();
+            std::fs::remove_file(&output_path).unwrap();
+            assert_eq!(&contents[4..12], b"ftypavif");
+            continue;
+        }
+
+        // Formats that can hold a colour profile have it embedded
+        let properties = FilePath::new(output_path.clone()).into_properties().unwrap();
+        let format_read = properties.infer_format();
+        let dimensions = properties.dimensions();
+        let embedded_profile = properties.icc_profile();
+        std::fs::remove_file(&output_path).unwrap();
+        assert_eq!(format_read, Some(format.image_format()));
+        assert_eq!(dimensions.unwrap(), (width, height));
+        match format.supports_icc_profile() {
+            true => assert_eq!(embedded_profile.unwrap(), Some(icc_profile)),
+            false => assert_eq!(embedded_profile.unwrap(), None),
+        }
+    }
+
+    // Failing to create the output file is an error rather than a panic
+    let output_path = std::env::temp_dir().join("stitchy-missing-directory").join("stitch.png");
+    let process_result = crate::file_util::write_image_to_file(
+        DynamicImage::new_rgb8(2, 2), &output_path, OutputFormat::Png,
+        &Opt::default().encoder_options(), None);
+    assert!(process_result.is_err());
+}
+
+#[test]
+pub fn test_animated_output_formats() {
+    use stitchy_core::image::{Delay, Frame, Rgba, RgbaImage};
+
+    let colours = [[200, 0, 0, 255], [0, 200, 0, 255], [0, 0, 200, 255]];
+    let delays = [100, 50, 250];
+    let frames = || colours.iter()
+        .zip(delays)
+        .map(|(&colour, delay)| Frame::from_parts(
+            RgbaImage::from_pixel(4, 3, Rgba(colour)), 0, 0, Delay::from_numer_denom_ms(delay, 1)))
+        .collect::<Vec<Frame>>();
+
+    // Every frame is read back with its own delay, including the last
+    for format in [OutputFormat::Gif, OutputFormat::WebP] {
+        let output_path = std::env::temp_dir().join(format!(
+            "stitchy-animation-test-{}.{}", std::process::id(), format.extension()));
+        crate::file_util::write_animation_to_file(
+            frames(), &output_path, format, &Opt::default().encoder_options(), 0).unwrap();
+        let properties = FilePath::new(output_path.clone()).into_properties().unwrap();
+        let read_frames = properties.animation_frames().unwrap().unwrap();
+        std::fs::remove_file(&output_path).unwrap();
+
+        assert_eq!(read_frames.len(), 3, "{}", format.name());
+        for ((frame, colour), delay) in read_frames.iter().zip(colours).zip(delays) {
+            assert_eq!(frame.delay().numer_denom_ms(), (delay, 1), "{}", format.name());
+            // The WebP decoder blends each frame over the last, rounding down by up to one
+            let pixel = frame.buffer().get_pixel(2, 1).0;
+            for (channel, expected) in pixel.iter().zip(colour) {
+                assert!(channel.abs_diff(expected) <= 1, "{}: {:?}", format.name(), pixel);
+            }
+        }
+    }
+
+    // PNG output is written as APNG, with its frame delays and number of plays
+    let output_path = std::env::temp_dir().join(format!(
+        "stitchy-animation-test-{}.png", std::process::id()));
+    crate::file_util::write_animation_to_file(
+        frames(), &output_path, OutputFormat::Png, &Opt::default().encoder_options(), 2).unwrap();
+    let file = std::fs::File::open(&output_path).unwrap();
+    let mut reader = png::Decoder::new(file).read_info().unwrap();
+    let animation_control = reader.info().animation_control.unwrap();
+    assert_eq!((animation_control.num_frames, animation_control.num_plays), (3, 2));
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    for (colour, delay) in colours.iter().zip(delays) {
+        reader.next_frame(&mut buffer).unwrap();
+        let frame_control = reader.info().frame_control.unwrap();
+        assert_eq!((frame_control.delay_num, frame_control.delay_den), (delay as u16, 1000));
+        assert_eq!(&buffer[..4], colour);
+    }
+    std::fs::remove_file(&output_path).unwrap();
+}
+
+#[test]
+pub fn test_lossy_webp_output() {
+    use stitchy_core::image::{DynamicImage, Rgba, RgbaImage};
+
+    // A noisy image, which lossless compression cannot shrink much
+    let noise = |x: u32, y: u32| (x.wrapping_mul(7919) ^ y.wrapping_mul(104729)).wrapping_mul(2654435761);
+    let opaque = RgbaImage::from_fn(64, 48, |x, y| {
+        let [r, g, b, _] = noise(x, y).to_le_bytes();
+        Rgba([r, g, b, 255])
+    });
+    let mut transparent = opaque.clone();
+    transparent.put_pixel(0, 0, Rgba([0, 0, 0, 0]));
+    let icc_profile = WorkingSpace::DisplayP3.icc_profile().unwrap();
+
+    // Lossy files keep their colour profile, with or without transparency, and lossless output
+    // is still written at quality 100
+    for (name, image) in [("opaque", opaque), ("transparent", transparent)] {
+        let write = |quality: usize| {
+            let output_path = std::env::temp_dir().join(format!(
+                "stitchy-webp-test-{}-{}-{}.webp", std::process::id(), name, quality));
+            crate::file_util::write_image_to_file(
+                DynamicImage::ImageRgba8(image.clone()),
+                &output_path,
+                OutputFormat::WebP,
+                &EncoderOptions { quality, ..Opt::default().encoder_options() },
+                Some(icc_profile.clone())
+            ).unwrap();
+            let size = crate::file_util::size_of_file(&output_path).unwrap();
+            let properties = FilePath::new(output_path.clone()).into_properties().unwrap();
+            assert_eq!(properties.dimensions().unwrap(), (64, 48), "{}", name);
+            assert_eq!(properties.icc_profile().unwrap(), Some(icc_profile.clone()), "{}", name);
+            let image = properties.into_image_contents(false).unwrap();
+            std::fs::remove_file(&output_path).unwrap();
+            (size, image.to_rgba8().get_pixel(0, 0)[3])
+        };
+        let expected_alpha = image.get_pixel(0, 0)[3];
+        let (lossy_size, lossy_alpha) = write(60);
+        let (lossless_size, lossless_alpha) = write(100);
+        assert!(lossy_size < lossless_size, "{}: {} >= {}", name, lossy_size, lossless_size);
+        assert_eq!(lossy_alpha, expected_alpha, "{}", name);
+        assert_eq!(lossless_alpha, expected_alpha, "{}", name);
+    }
+
+    // A stitch too wide for WebP is scaled down to fit, and an image that was not is refused
+    // before any file is created
+    let size_limit = OutputFormat::WebP.max_dimension().unwrap();
+    let wide = DynamicImage::ImageRgba8(RgbaImage::new(9000, 20));
+    let stitch = Stitch::builder()
+        .alignment(stitchy_core::AlignmentMode::Horizontal)
+        .resize_filter(FilterType::Nearest)
+        .width_limit(size_limit)
+        .height_limit(size_limit)
+        .images(vec![wide.clone(), wide])
+        .stitch()
+        .unwrap();
+    assert_eq!(stitch.width(), 16383);
+    let options = EncoderOptions { quality: 80, ..Opt::default().encoder_options() };
+    let too_wide = DynamicImage::ImageRgba8(RgbaImage::new(16384, 2));
+    for (image, fits) in [(stitch, true), (too_wide, false)] {
+        let output_path = std::env::temp_dir().join(format!(
+            "stitchy-webp-test-{}-wide-{}.webp", std::process::id(), image.width()));
+        let result = crate::file_util::write_image_to_file(
+            image, &output_path, OutputFormat::WebP, &options, None);
+        assert_eq!(result.is_ok(), fits, "{:?}", result);
+        assert_eq!(output_path.is_file(), fits);
+        if fits {
+            std::fs::remove_file(&output_path).unwrap();
+        }
+    }
+}
+
+#[test]
+pub fn test_output_within_size() {
+    use stitchy_core::image::{Rgba, RgbaImage};
+
+    let noise = |x: u32, y: u32| (x.wrapping_mul(7919) ^ y.wrapping_mul(104729)).wrapping_mul(2654435761);
+    let image = DynamicImage::ImageRgba8(RgbaImage::from_fn(128, 96, |x, y| {
+        let [r, g, b, _] = noise(x, y).to_le_bytes();
+        Rgba([r, g, b, 255])
+    }));
+    let options = Opt::default().encoder_options();
+
+    for format in [OutputFormat::Jpeg, OutputFormat::WebP] {
+        let output_path = std::env::temp_dir().join(format!(
+            "stitchy-size-test-{}.{}", std::process::id(), format.extension()));
+        let size_at = |quality: usize| {
+            crate::file_util::write_image_to_file(
+                image.clone(), &output_path, format, &EncoderOptions { quality, ..options }, None
+            ).unwrap();
+            crate::file_util::size_of_file(&output_path).unwrap()
+        };
+        let (best_size, lowest_size) = (size_at(100), size_at(40));
+        let fit_within = |max_bytes: u64| {
+            let fit = crate::file_util::write_image_within_size(
+                image.clone(), &output_path, format, &options, None, max_bytes, FilterType::Triangle
+            ).unwrap();
+            let size = crate::file_util::size_of_file(&output_path).unwrap();
+            assert!(size <= max_bytes, "{:?}: {} > {}", format, size, max_bytes);
+            assert_eq!(fit.bytes, size, "{:?}", format);
+            let properties = FilePath::new(output_path.clone()).into_properties().unwrap();
+            assert_eq!(properties.dimensions().unwrap(), (fit.width, fit.height), "{:?}", format);
+            fit
+        };
+
+        // The best quality is kept if it fits, a lower quality is found if not, and the image
+        // is scaled down if even the lowest quality is too large
+        let fit = fit_within(best_size);
+        assert_eq!((fit.quality, fit.width, fit.height), (100, 128, 96), "{:?}", format);
+        let fit = fit_within((best_size + lowest_size) / 2);
+        assert!((40..100).contains(&fit.quality), "{:?}: {:?}", format, fit);
+        assert_eq!((fit.width, fit.height), (128, 96), "{:?}", format);
+        let fit = fit_within(lowest_size / 4);
+        assert!(fit.width < 128 && fit.height < 96, "{:?}: {:?}", format, fit);
+
+        // The search finds a quality at least as high as any known to fit
+        let fit = fit_within(size_at(75));
+        assert!(fit.quality >= 75, "{:?}: {:?}", format, fit);
+        std::fs::remove_file(&output_path).unwrap();
+
+        // Nothing is written if even the smallest image is too large
+        let result = crate::file_util::write_image_within_size(
+            image.clone(), &output_path, format, &options, None, 10, FilterType::Triangle);
+        assert!(result.is_err(), "{:?}: {:?}", format, result);
+        assert!(!output_path.exists(), "{:?}", format);
+    }
+}
+
+/// Get the marker and contents of each segment in a JPEG file up to the start of the image data
+fn jpeg_header_segments(bytes: &[u8]) -> Vec<(u8, &[u8])> {
+    let mut segments = vec![];
+    let mut index = 2;
+    while index + 4 <= bytes.len() && bytes[index] == 0xFF {
+        let marker = bytes[index + 1];
+        let length = u16::from_be_bytes([bytes[index + 2], bytes[index + 3]]) as usize;
+        segments.push((marker, &bytes[index + 4..index + 2 + length]));
+        if marker == 0xDA {
+            break;
+        }
+        index += 2 + length;
+    }
+    segments
+}
+
+#[test]
+pub fn test_jpeg_encoder_options() {
+    use crate::encoder_options::ChromaSubsampling;
+    use stitchy_core::image::{DynamicImage, Rgba, RgbaImage};
+
+    let image = RgbaImage::from_fn(40, 24, |x, y| match (x + y) % 3 {
+        0 => Rgba([255, 0, 0, 255]),
+        _ => Rgba([255, 255, 255, 255]),
+    });
+    let icc_profile = WorkingSpace::Srgb.icc_profile().unwrap();
+    let write = |options: Opt| {
+        let output_path = std::env::temp_dir().join(format!(
+            "stitchy-jpeg-test-{}-{}.jpg", std::process::id(), options.progressive));
+        crate::file_util::write_image_to_file(
+            DynamicImage::ImageRgba8(image.clone()),
+            &output_path,
+            OutputFormat::Jpeg,
+            &options.encoder_options(),
+            Some(icc_profile.clone())
+        ).unwrap();
+        let bytes = std::fs::read(&output_path).unwrap();
+        let properties = FilePath::new(output_path.clone()).into_properties().unwrap();
+        assert_eq!(properties.icc_profile().unwrap(), Some(icc_profile.clone()));
+        assert_eq!(properties.into_image_contents(false).unwrap().width(), 40);
+        std::fs::remove_file(&output_path).unwrap();
+        bytes
+    };
+
+    // Each segment's first component has its sampling factors in the eighth byte of the frame
+    // header, which is the start of frame for baseline or progressive encoding. Colour is at
+    // half resolution by default, even at the default quality of 100.
+    let baseline = write(Opt::default());
+    let segments = jpeg_header_segments(&baseline);
+    let (_, frame_header) = segments.iter().find(|(marker, _)| *marker == 0xC0).unwrap();
+    assert_eq!(frame_header[7], 0x22);
+    assert!(!segments.iter().any(|(marker, _)| *marker == 0xDD));
+
+    let progressive = write(Opt {
+        quality: 80,
+        progressive: true,
+        subsampling: Some(ChromaSubsampling::Full),
+        optimise_huffman: true,
+        restart_interval: Some(2),
+        ..Opt::default()
+    });
+    let segments = jpeg_header_segments(&progressive);
+    let (_, frame_header) = segments.iter().find(|(marker, _)| *marker == 0xC2).unwrap();
+    assert_eq!(frame_header[7], 0x11);
+    let (_, restart_interval) = segments.iter().find(|(marker, _)| *marker == 0xDD).unwrap();
+    assert_eq!(restart_interval, &[0, 2]);
+}
+
+#[test]
+pub fn test_palette_output() {
+    use crate::encoder_options::Dither;
+    use stitchy_core::image::{DynamicImage, Rgba, RgbaImage};
+
+    // A few flat colours with a transparent corner, and a gradient needing more colours
+    let flat = RgbaImage::from_fn(30, 20, |x, y| match (x, y) {
+        (0..=4, 0..=4) => Rgba([0, 0, 0, 0]),
+        (_, 0..=9) => Rgba([40, 80, 200, 255]),
+        _ => Rgba([250, 250, 250, 255]),
+    });
+    let gradient = RgbaImage::from_fn(64, 16, |x, y| Rgba([x as u8 * 4, y as u8 * 16, 128, 255]));
+    let write = |image: &RgbaImage, format: OutputFormat, colours: u16, dither: Dither| {
+        let output_path = std::env::temp_dir().join(format!(
+            "stitchy-palette-test-{}-{}-{:?}.{}",
+            std::process::id(), image.width(), dither, format.extension()));
+        let options = Opt {
+            colours: Some(colours),
+            dither: Some(dither),
+            ..Opt::default()
+        };
+        crate::file_util::write_image_to_file(
+            DynamicImage::ImageRgba8(image.clone()),
+            &output_path,
+            format,
+            &options.encoder_options(),
+            None
+        ).unwrap();
+        let bytes = std::fs::read(&output_path).unwrap();
+        let properties = FilePath::new(output_path.clone()).into_properties().unwrap();
+        let read_image = properties.into_image_contents(false).unwrap().to_rgba8();
+        std::fs::remove_file(&output_path).unwrap();
+        (bytes, read_image)
+    };
+
+    // Images with few enough colours keep them exactly, in as few bits per pixel as possible
+    for format in [OutputFormat::Png, OutputFormat::Gif] {
+        let (bytes, read_image) = write(&flat, format, 16, Dither::FloydSteinberg);
+        assert_eq!(read_image.get_pixel(0, 0)[3], 0, "{}", format.name());
+        for (read_pixel, pixel) in read_image.pixels().zip(flat.pixels()).filter(|(_, p)| p[3] > 0) {
+            assert_eq!(read_pixel, pixel, "{}", format.name());
+        }
+        if format == OutputFormat::Png {
+            let reader = png::Decoder::new(std::io::Cursor::new(bytes)).read_info().unwrap();
+            let info = reader.info();
+            assert_eq!(info.color_type, png::ColorType::Indexed);
+            assert_eq!(info.bit_depth, png::BitDepth::Two);
+            assert_eq!(info.trns.as_deref(), Some(&[0][..]));
+        }
+    }
+
+    // Other images are reduced to the palette size, with each kind of dithering
+    for dither in [Dither::None, Dither::FloydSteinberg, Dither::Ordered] {
+        let (bytes, read_image) = write(&gradient, OutputFormat::Png, 16, dither);
+        let reader = png::Decoder::new(std::io::Cursor::new(bytes)).read_info().unwrap();
+        assert_eq!(reader.info().bit_depth, png::BitDepth::Four);
+        assert!(reader.info().palette.as_ref().unwrap().len() <= 16 * 3);
+        let mut colours: Vec<[u8; 4]> = read_image.pixels().map(|pixel| pixel.0).collect();
+        colours.sort_unstable();
+        colours.dedup();
+        assert!(colours.len() <= 16, "{:?}: {}", dither, colours.len());
+
+        // Each 8x8 block still averages out close to the original colours
+        let block_average = |image: &RgbaImage, block_x: u32, block_y: u32, channel: usize| {
+            let sum: u32 = (0..64)
+                .map(|i| image.get_pixel(block_x * 8 + i % 8, block_y * 8 + i / 8)[channel] as u32)
+                .sum();
+            sum as f64 / 64.0
+        };
+        let mut total_error = 0.0;
+        for (block_x, block_y, channel) in (0..48u32).map(|i| (i % 8, i / 8 % 2, i as usize / 16)) {
+            total_error += (block_average(&read_image, block_x, block_y, channel)
+                - block_average(&gradient, block_x, block_y, channel)).abs();
+        }
+        let mean_error = total_error / 48.0;
+        assert!(mean_error < 8.0, "{:?}: {}", dither, mean_error);
     }
 }